@@ -0,0 +1,291 @@
+//! Ambient light sensor input, read from Linux IIO devices
+
+use crate::config::{AmbientLightSettings, LuxMapping, SsbConfig};
+use crate::controller::{BrightnessController, Message};
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const IIO_DEVICES_PATH: &str = "/sys/bus/iio/devices";
+
+/// How often to check whether the sensor has been enabled in the config
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An illuminance sensor exposed by the IIO subsystem
+#[derive(Debug)]
+pub struct IioSensor {
+    dir: PathBuf,
+    scale: f64,
+    offset: f64,
+}
+
+impl IioSensor {
+    /// Find the first device in the IIO directory that has an illuminance channel
+    pub fn find(iio_dir: &Path) -> Option<Self> {
+        let mut dirs = fs::read_dir(iio_dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.join("in_illuminance_raw").exists())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        let dir = dirs.into_iter().next()?;
+        let scale = read_value(&dir.join("in_illuminance_scale")).unwrap_or(1.0);
+        let offset = read_value(&dir.join("in_illuminance_offset")).unwrap_or(0.0);
+        Some(Self { dir, scale, offset })
+    }
+
+    pub fn read_lux(&self) -> anyhow::Result<f64> {
+        let raw = read_value(&self.dir.join("in_illuminance_raw"))?;
+        Ok((raw + self.offset) * self.scale)
+    }
+}
+
+fn read_value(path: &Path) -> anyhow::Result<f64> {
+    let contents =
+        fs::read_to_string(path).context(format!("Unable to read file '{}'", path.display()))?;
+    contents
+        .trim()
+        .parse()
+        .context(format!("Invalid value in '{}'", path.display()))
+}
+
+/// Map the illuminance to a brightness, interpolating linearly between the mapping points
+pub fn lux_to_brightness(mapping: &[LuxMapping], lux: f64) -> u32 {
+    let mut points = mapping.to_vec();
+    points.sort_by(|a, b| a.lux.total_cmp(&b.lux));
+    let first = points.first().expect("Mapping must not be empty");
+    let last = points.last().unwrap();
+    if lux <= first.lux {
+        return first.brightness;
+    }
+    if lux >= last.lux {
+        return last.brightness;
+    }
+    let (low, high) = points
+        .windows(2)
+        .map(|w| (w[0], w[1]))
+        .find(|(_, high)| lux <= high.lux)
+        .unwrap();
+    let progress = (lux - low.lux) / (high.lux - low.lux);
+    let brightness =
+        low.brightness as f64 + (high.brightness as f64 - low.brightness as f64) * progress;
+    brightness.round() as u32
+}
+
+/// Smooths the sensor readings, and ignores small changes in the resulting brightness
+#[derive(Default)]
+struct AmbientFilter {
+    smoothed_lux: Option<f64>,
+    reported: Option<u32>,
+}
+
+impl AmbientFilter {
+    /// Returns the new sensor brightness if it should be reported to the controller
+    fn update(&mut self, lux: f64, settings: &AmbientLightSettings) -> Option<u32> {
+        let smoothed = match self.smoothed_lux {
+            None => lux,
+            Some(previous) => previous + (lux - previous) * settings.smoothing,
+        };
+        self.smoothed_lux = Some(smoothed);
+        let brightness = lux_to_brightness(&settings.mapping, smoothed);
+        match self.reported {
+            Some(reported) if reported.abs_diff(brightness) < settings.hysteresis.max(1) => None,
+            _ => {
+                self.reported = Some(brightness);
+                Some(brightness)
+            }
+        }
+    }
+
+    /// Returns true if a brightness had previously been reported
+    fn reset(&mut self) -> bool {
+        std::mem::take(self).reported.is_some()
+    }
+}
+
+/// Samples the ambient light sensor on its own thread, and sends the resulting brightness to
+/// the BrightnessController whenever it changes
+pub struct AmbientLightWatcher {
+    stop: Option<mpsc::Sender<()>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl AmbientLightWatcher {
+    pub fn start(config: Arc<RwLock<SsbConfig>>, controller: &BrightnessController) -> Self {
+        Self::start_with_dir(
+            config,
+            controller.sender.clone(),
+            PathBuf::from(IIO_DEVICES_PATH),
+        )
+    }
+
+    fn start_with_dir(
+        config: Arc<RwLock<SsbConfig>>,
+        controller: mpsc::Sender<Message>,
+        iio_dir: PathBuf,
+    ) -> Self {
+        let (stop, stop_rx) = mpsc::channel();
+        let join_handle = std::thread::spawn(move || run(config, controller, iio_dir, stop_rx));
+        Self {
+            stop: Some(stop),
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for AmbientLightWatcher {
+    fn drop(&mut self) {
+        log::info!("Stopping ambient light watcher");
+        drop(self.stop.take());
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn run(
+    config: Arc<RwLock<SsbConfig>>,
+    controller: mpsc::Sender<Message>,
+    iio_dir: PathBuf,
+    stop: mpsc::Receiver<()>,
+) {
+    let mut filter = AmbientFilter::default();
+    let mut sensor = None;
+    let mut missing_logged = false;
+    loop {
+        let settings = config.read().unwrap().ambient_light.clone();
+        let interval = match settings {
+            None => {
+                if filter.reset() {
+                    controller.send(Message::AmbientLight(None)).unwrap();
+                }
+                sensor = None;
+                DISABLED_POLL_INTERVAL
+            }
+            Some(settings) => {
+                if sensor.is_none() {
+                    sensor = IioSensor::find(&iio_dir);
+                    match &sensor {
+                        Some(s) => log::info!("Using ambient light sensor {}", s.dir.display()),
+                        None if !missing_logged => {
+                            log::warn!("No ambient light sensor found in {}", iio_dir.display())
+                        }
+                        None => {}
+                    }
+                    missing_logged = sensor.is_none();
+                }
+                if let Some(s) = &sensor {
+                    match s.read_lux() {
+                        Ok(lux) => {
+                            log::trace!("Ambient light sensor read {lux} lux");
+                            if let Some(brightness) = filter.update(lux, &settings) {
+                                log::info!("Ambient light brightness changed to {brightness}% ({lux:.0} lux)");
+                                controller
+                                    .send(Message::AmbientLight(Some(brightness)))
+                                    .unwrap();
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed to read ambient light sensor: {:#}", e);
+                            sensor = None;
+                        }
+                    }
+                }
+                Duration::from_secs(settings.sample_interval_secs as u64)
+            }
+        };
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => break,
+        }
+    }
+    log::debug!("Ambient light watcher thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> AmbientLightSettings {
+        AmbientLightSettings {
+            mapping: vec![
+                LuxMapping {
+                    lux: 100.0,
+                    brightness: 40,
+                },
+                LuxMapping {
+                    lux: 0.0,
+                    brightness: 20,
+                },
+                LuxMapping {
+                    lux: 300.0,
+                    brightness: 100,
+                },
+            ],
+            smoothing: 0.5,
+            hysteresis: 5,
+            ..AmbientLightSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_lux_to_brightness() {
+        let mapping = settings().mapping;
+        assert_eq!(lux_to_brightness(&mapping, -1.0), 20);
+        assert_eq!(lux_to_brightness(&mapping, 0.0), 20);
+        assert_eq!(lux_to_brightness(&mapping, 50.0), 30);
+        assert_eq!(lux_to_brightness(&mapping, 100.0), 40);
+        assert_eq!(lux_to_brightness(&mapping, 200.0), 70);
+        assert_eq!(lux_to_brightness(&mapping, 5000.0), 100);
+    }
+
+    #[test]
+    fn test_ambient_filter() {
+        let settings = settings();
+        let mut filter = AmbientFilter::default();
+        // The first sample is always reported
+        assert_eq!(filter.update(100.0, &settings), Some(40));
+        // Smoothed to 104 lux, within the hysteresis
+        assert_eq!(filter.update(108.0, &settings), None);
+        // Smoothed to 152 lux, far enough to be reported
+        assert_eq!(filter.update(200.0, &settings), Some(56));
+        assert_eq!(filter.update(152.0, &settings), None);
+        // Smoothed to 176 lux
+        assert_eq!(filter.update(200.0, &settings), Some(63));
+        assert!(filter.reset());
+        assert!(!filter.reset());
+    }
+
+    #[test]
+    fn test_watcher_with_fake_iio_dir() {
+        let iio_dir = tempfile::tempdir().unwrap();
+        let other = iio_dir.path().join("iio:device0");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("in_accel_x_raw"), "12\n").unwrap();
+        let sensor = iio_dir.path().join("iio:device1");
+        fs::create_dir(&sensor).unwrap();
+        fs::write(sensor.join("in_illuminance_raw"), "200\n").unwrap();
+        fs::write(sensor.join("in_illuminance_scale"), "0.5\n").unwrap();
+
+        let found = IioSensor::find(iio_dir.path()).unwrap();
+        assert_eq!(found.dir, sensor);
+        assert_eq!(found.read_lux().unwrap(), 100.0);
+
+        let config = SsbConfig {
+            ambient_light: Some(settings()),
+            ..SsbConfig::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let watcher = AmbientLightWatcher::start_with_dir(
+            Arc::new(RwLock::new(config)),
+            tx,
+            iio_dir.path().to_path_buf(),
+        );
+        let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(message, Message::AmbientLight(Some(40)));
+        drop(watcher);
+    }
+}
@@ -1,18 +1,88 @@
-use crate::calculator::calculate_brightness;
-use crate::config::{BrightnessValues, Location, MonitorOverride, MonitorProperty};
+#[cfg(test)]
+use crate::calculator::Phase;
+use crate::calculator::{
+    blend, calculate_brightness, calculate_phase, cloud_adjusted_day_brightness, PhaseResult,
+};
+#[cfg(target_os = "linux")]
+use crate::calculator::{calculate_temperature, TemperatureResult};
+use crate::config::{
+    BrightnessValues, ColorTemperatureValues, Location, MonitorOverride, MonitorProperty,
+    SsbConfig, TransitionCurve,
+};
+use crate::fade::{FadeResult, Fader};
+#[cfg(target_os = "linux")]
+use crate::gamma::{
+    hardware_outputs, normalise_output_name, Adjustment, GammaController, BACKLIGHT_PATH, DRM_PATH,
+};
+use crate::location::{AutomaticLocation, LocationSource};
 use brightness::blocking::{Brightness, BrightnessDevice};
 use itertools::Itertools;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use sunrise_sunset_calculator::SunriseSunsetParameters;
 use wildmatch::WildMatch;
 
 #[derive(Debug, Serialize)]
 pub struct ApplyResults {
     pub unknown_devices: Vec<String>,
+    pub location: Location,
+    pub location_source: LocationSource,
+    /// The brightness measured by the ambient light sensor, if it was blended into the results
+    pub ambient_brightness: Option<u32>,
+    /// The cloud cover percentage, if it was used to reduce the day brightness
+    pub cloud_cover: Option<f64>,
+    /// The brightness that was set manually, replacing the calculated brightness
+    pub manual_brightness: Option<u32>,
     pub monitors: Vec<MonitorResult>,
+    /// LED devices such as keyboard backlights, if they are enabled in the config
+    pub leds: Vec<LedResult>,
+    /// Outputs whose colour temperature is adjusted, if it is enabled in the config
+    pub color_temperature: Vec<ColorTemperatureResult>,
     pub sun: SunriseSunsetResult,
+    pub phase: PhaseResult,
+    /// The fades that have finished or failed since the previous update
+    #[serde(skip)]
+    pub finished_fades: Vec<FadeResult>,
+}
+
+#[cfg(test)]
+impl ApplyResults {
+    /// Results for monitors set to these brightness values during the day in London, for the
+    /// tests of modules that report the results. Other fields can be changed afterwards.
+    pub fn for_test(brightness: &[(&str, u32)]) -> Self {
+        ApplyResults {
+            unknown_devices: vec![],
+            location: Location {
+                latitude: 51.5,
+                longitude: -0.12,
+            },
+            location_source: LocationSource::Configured,
+            ambient_brightness: None,
+            cloud_cover: None,
+            manual_brightness: None,
+            monitors: brightness
+                .iter()
+                .map(|(name, brightness)| MonitorResult::for_test(name, *brightness))
+                .collect(),
+            leds: vec![],
+            color_temperature: vec![],
+            sun: SunriseSunsetResult {
+                set: 1690000000,
+                rise: 1689950000,
+                visible: true,
+            },
+            phase: PhaseResult {
+                phase: Phase::Day,
+                expiry_time: 0,
+            },
+            finished_fades: vec![],
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -32,11 +102,79 @@ impl From<sunrise_sunset_calculator::SunriseSunsetResult> for SunriseSunsetResul
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct LedResult {
+    pub name: String,
+    /// None if the device is disabled by an override
+    pub brightness: Option<BrightnessDetails>,
+    /// The number of brightness levels, the percentage is rounded to the nearest level
+    pub max_brightness: u32,
+    pub error: Option<String>,
+    /// The device was already set to this brightness, so it wasn't written to
+    pub unchanged: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ColorTemperatureResult {
+    pub output: String,
+    pub expiry_time: Option<i64>,
+    /// Colour temperature in Kelvin
+    pub temperature: u32,
+    pub temperature_day: u32,
+    pub temperature_night: u32,
+    pub error: Option<String>,
+    /// The output was already set to this colour temperature, so it wasn't written to
+    pub unchanged: bool,
+}
+
 #[derive(Debug, Serialize)]
 pub struct MonitorResult {
     pub properties: MonitorProperties,
     pub brightness: Option<BrightnessDetails>,
     pub error: Option<String>,
+    /// Present if the brightness was written to the device directly (rather than faded)
+    pub write: Option<WriteDetails>,
+    /// True if the device was not written to because it was already set to this brightness
+    pub unchanged: bool,
+    /// The output has no backlight control, so it is dimmed using its gamma table instead
+    pub software_dimming: bool,
+}
+
+#[cfg(test)]
+impl MonitorResult {
+    /// A monitor that has been set to the brightness, for the tests of modules that report the
+    /// results. Other fields can be changed afterwards.
+    pub fn for_test(device_name: &str, brightness: u32) -> Self {
+        MonitorResult {
+            properties: MonitorProperties {
+                device_name: device_name.to_owned(),
+                #[cfg(windows)]
+                device_description: String::new(),
+                #[cfg(windows)]
+                device_key: String::new(),
+                #[cfg(windows)]
+                device_path: String::new(),
+            },
+            brightness: Some(BrightnessDetails {
+                expiry_time: None,
+                brightness,
+                brightness_day: 100,
+                brightness_night: 60,
+            }),
+            error: None,
+            write: None,
+            unchanged: false,
+            software_dimming: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WriteDetails {
+    /// How long it took to set the brightness, including any retries
+    pub duration_ms: u64,
+    /// How many attempts were made to set the brightness
+    pub attempts: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -62,6 +200,9 @@ pub struct MonitorOverrideCompiled {
     pub pattern: WildMatch,
     pub key: MonitorProperty,
     pub brightness: Option<BrightnessValues>,
+    pub transition_mins: Option<u32>,
+    pub transition_curve: Option<TransitionCurve>,
+    pub color_temperature: Option<ColorTemperatureValues>,
 }
 
 impl From<&MonitorOverride> for MonitorOverrideCompiled {
@@ -70,6 +211,9 @@ impl From<&MonitorOverride> for MonitorOverrideCompiled {
             pattern: WildMatch::new(&value.pattern),
             key: value.key,
             brightness: value.brightness,
+            transition_mins: value.transition_mins,
+            transition_curve: value.transition_curve,
+            color_temperature: value.color_temperature,
         }
     }
 }
@@ -90,14 +234,181 @@ fn match_monitor<'o>(
     None
 }
 
+/// The day and night brightness (None if the monitor is disabled), transition time and curve to
+/// use for a monitor, taking into account its override
+fn monitor_settings(
+    config: &SsbConfig,
+    monitor_override: Option<&MonitorOverrideCompiled>,
+) -> (Option<BrightnessValues>, u32, TransitionCurve) {
+    let monitor_values = match monitor_override {
+        None => Some(BrightnessValues {
+            brightness_day: config.brightness_day,
+            brightness_night: config.brightness_night,
+        }),
+        Some(o) => o.brightness,
+    };
+    let transition_mins = monitor_override
+        .and_then(|o| o.transition_mins)
+        .unwrap_or(config.transition_mins);
+    let transition_curve = monitor_override
+        .and_then(|o| o.transition_curve)
+        .unwrap_or(config.transition_curve);
+    (monitor_values, transition_mins, transition_curve)
+}
+
+/// The day and night colour temperature (None if it is disabled), transition time and curve to
+/// use for a monitor, taking into account its override
+#[cfg(target_os = "linux")]
+fn temperature_settings(
+    config: &SsbConfig,
+    monitor_override: Option<&MonitorOverrideCompiled>,
+) -> (Option<ColorTemperatureValues>, u32, TransitionCurve) {
+    let (_, transition_mins, transition_curve) = monitor_settings(config, monitor_override);
+    // Overrides only change the values, they can't enable it for a single monitor
+    let values = config.color_temperature.map(|values| {
+        monitor_override
+            .and_then(|o| o.color_temperature)
+            .unwrap_or(values)
+    });
+    (values, transition_mins, transition_curve)
+}
+
+/// The values that are the same for every monitor when calculating its brightness
+struct BrightnessInputs<'a> {
+    sun: &'a sunrise_sunset_calculator::SunriseSunsetResult,
+    time_now: i64,
+    /// The maximum reduction, and the cloud cover percentage
+    cloud_cover: Option<(u32, f64)>,
+    /// The weight, and the brightness measured by the ambient light sensor
+    ambient: Option<(u32, u32)>,
+    manual_brightness: Option<u32>,
+}
+
+/// Calculate the brightness for a monitor, or None if it is disabled by its override
+fn monitor_brightness(
+    config: &SsbConfig,
+    monitor_override: Option<&MonitorOverrideCompiled>,
+    inputs: &BrightnessInputs,
+    name: &str,
+) -> Option<BrightnessDetails> {
+    let (monitor_values, transition_mins, transition_curve) =
+        monitor_settings(config, monitor_override);
+    let BrightnessValues {
+        brightness_day,
+        brightness_night,
+    } = monitor_values?;
+    let effective_day = match inputs.cloud_cover {
+        Some((max_reduction, cloud_cover)) => cloud_adjusted_day_brightness(
+            brightness_day,
+            brightness_night,
+            cloud_cover,
+            max_reduction,
+        ),
+        None => brightness_day,
+    };
+    let mut brightness = calculate_brightness(
+        effective_day,
+        brightness_night,
+        transition_mins,
+        transition_curve,
+        inputs.sun,
+        inputs.time_now,
+    );
+    if let Some((weight, sensor)) = inputs.ambient {
+        brightness.brightness = blend(brightness.brightness, sensor, weight);
+    }
+    if let Some(manual) = inputs.manual_brightness {
+        // Stays at this brightness until the manual brightness is cleared
+        brightness.brightness = manual;
+        brightness.expiry_time = None;
+    }
+    log::debug!(
+        "Computed brightness for '{}' = {:?} (day={}) (night={}) (transition={}min {:?})",
+        name,
+        brightness,
+        effective_day,
+        brightness_night,
+        transition_mins,
+        transition_curve,
+    );
+    Some(BrightnessDetails {
+        expiry_time: brightness.expiry_time,
+        brightness: brightness.brightness,
+        brightness_day,
+        brightness_night,
+    })
+}
+
+/// How long to wait for a device to accept a new brightness before giving up on it
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times to try setting the brightness of a device (DDC/CI writes can fail transiently)
+const WRITE_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, this is doubled after each failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// State that is persisted between each call to apply_brightness
+#[derive(Default)]
+pub struct ApplyState {
+    fader: Fader,
+    /// Devices that still have a write in progress, e.g. one that previously timed out
+    writes_in_progress: Arc<Mutex<HashSet<String>>>,
+    /// The last brightness that was successfully applied to each device
+    last_applied: HashMap<String, u32>,
+    /// The last raw brightness that was successfully applied to each LED device
+    last_applied_leds: HashMap<String, u32>,
+    /// The last adjustment that was successfully applied to each output's gamma table
+    #[cfg(target_os = "linux")]
+    last_applied_gamma: HashMap<String, Adjustment>,
+    #[cfg(target_os = "linux")]
+    gamma: GammaController,
+    /// Whether the next apply should read back unchanged devices to check they haven't drifted
+    verify: bool,
+    /// The latest brightness measured by the ambient light sensor
+    pub ambient_brightness: Option<u32>,
+    /// The latest cloud cover percentage
+    pub cloud_cover: Option<f64>,
+    /// The latest location found automatically
+    pub automatic_location: Option<AutomaticLocation>,
+    /// Brightness set manually for every monitor, until it is cleared
+    pub manual_brightness: Option<u32>,
+}
+
+impl ApplyState {
+    /// Forget the previously applied brightness values, so that every device is written to on the
+    /// next apply. This should be used when the devices may have changed, e.g. hotplug or resume.
+    pub fn invalidate(&mut self) {
+        self.last_applied.clear();
+        self.last_applied_leds.clear();
+        #[cfg(target_os = "linux")]
+        self.last_applied_gamma.clear();
+    }
+
+    /// On the next apply, read the brightness of the devices that would otherwise be skipped
+    /// because they are unchanged, and only write to those that no longer match.
+    /// This is for monitors that reset their own brightness, e.g. when waking from standby.
+    pub fn reassert(&mut self) {
+        self.verify = true;
+    }
+}
+
+pub(crate) struct WriteOutcome {
+    pub(crate) result: Result<(), String>,
+    details: WriteDetails,
+    /// The device was read back and already had the correct brightness, so it wasn't written to
+    verified: bool,
+}
+
+/// Calculate and apply the brightness for every monitor.
+/// If fading is enabled in the config then the monitors will be faded towards their new brightness
+/// in the background, otherwise the devices are all written to concurrently.
 pub fn apply_brightness(
-    brightness_day: u32,
-    brightness_night: u32,
-    transition_mins: u32,
+    config: &SsbConfig,
     location: Location,
-    overrides: Vec<MonitorOverride>,
+    location_source: LocationSource,
+    state: &mut ApplyState,
 ) -> ApplyResults {
-    let overrides = overrides
+    let overrides = config
+        .overrides
         .iter()
         .map(MonitorOverrideCompiled::from)
         .collect::<Vec<_>>();
@@ -118,62 +429,127 @@ pub fn apply_brightness(
     });
     log::debug!("Monitors: {:?}, Errors: {:?}", monitors, failed_monitors);
 
-    let monitor_results = monitors
+    // Only fades that have completed count as applied, failed ones will be retried
+    let finished_fades = state.fader.finished();
+    let mut fade_errors = HashMap::new();
+    for fade in &finished_fades {
+        match &fade.result {
+            Ok(_) => {
+                state
+                    .last_applied
+                    .insert(fade.device_name.clone(), fade.target);
+            }
+            Err(e) => {
+                state.last_applied.remove(&fade.device_name);
+                fade_errors.insert(fade.device_name.clone(), e.clone());
+            }
+        }
+    }
+
+    // Forget about any devices that have been disconnected since the last apply
+    let device_names = monitors
+        .iter()
+        .filter_map(|m| m.device_name().ok())
+        .collect::<HashSet<_>>();
+    state
+        .last_applied
+        .retain(|name, _| device_names.contains(name));
+    state.fader.retain(&device_names);
+
+    // Fading may have been disabled since the last apply
+    if config.fade.is_none() {
+        state.fader.clear();
+    }
+    let verify = std::mem::take(&mut state.verify);
+    let manual_brightness = state.manual_brightness;
+    let ambient = config
+        .ambient_light
+        .as_ref()
+        .zip(state.ambient_brightness)
+        .map(|(settings, brightness)| (settings.weight, brightness));
+    let cloud_cover = config
+        .weather
+        .as_ref()
+        .zip(state.cloud_cover)
+        .map(|(settings, cloud_cover)| (settings.max_reduction, cloud_cover));
+    let inputs = BrightnessInputs {
+        sun: &sun,
+        time_now: epoch_time_now,
+        cloud_cover,
+        ambient,
+        manual_brightness,
+    };
+    let mut writing = HashSet::new();
+
+    // Compute the brightness for each monitor, then start writing to the devices
+    let (write_sender, write_receiver) = mpsc::channel();
+    let mut monitor_results = monitors
         .into_iter()
         .map(|m| {
             let properties = MonitorProperties::from_device(&m);
-            let monitor_values = match match_monitor(&overrides, &properties) {
-                None => Some(BrightnessValues {
-                    brightness_day,
-                    brightness_night,
-                }),
-                Some(o) => o.brightness,
-            };
-
-            if let Some(BrightnessValues {
-                brightness_day,
-                brightness_night,
-            }) = monitor_values
+            let monitor_override = match_monitor(&overrides, &properties);
+            if let Some(brightness) =
+                monitor_brightness(config, monitor_override, &inputs, &properties.device_name)
             {
-                let brightness = calculate_brightness(
-                    brightness_day,
-                    brightness_night,
-                    transition_mins,
-                    &sun,
-                    epoch_time_now,
-                );
-                log::debug!(
-                    "Computed brightness for '{}' = {:?} (day={}) (night={})",
-                    properties.device_name,
-                    brightness,
-                    brightness_day,
-                    brightness_night
-                );
-
-                let error = m.set(brightness.brightness).err();
-                if let Some(err) = error.as_ref() {
-                    log::error!(
-                        "Failed to set brightness for '{}': {:?}",
+                let fading =
+                    state.fader.target(&properties.device_name) == Some(brightness.brightness);
+                let unchanged = fading
+                    || state.last_applied.get(&properties.device_name)
+                        == Some(&brightness.brightness);
+                if fading {
+                    log::info!(
+                        "Already fading brightness for '{}' to {}%",
                         properties.device_name,
-                        err
+                        brightness.brightness
                     );
-                } else {
+                } else if unchanged && verify {
+                    // Writes directly rather than fading, as the device has already jumped away
+                    writing.insert(properties.device_name.clone());
+                    start_write(
+                        m,
+                        properties.device_name.clone(),
+                        brightness.brightness,
+                        true,
+                        &state.writes_in_progress,
+                        write_sender.clone(),
+                    );
+                } else if unchanged {
                     log::info!(
-                        "Successfully set brightness for '{}' to {}%",
+                        "Brightness for '{}' is unchanged at {}%",
                         properties.device_name,
                         brightness.brightness
                     );
+                } else if let Some(settings) = config.fade.as_ref() {
+                    let ddcci = properties.is_ddcci();
+                    let name = properties.device_name.as_str();
+                    state
+                        .fader
+                        .fade(name, m, brightness.brightness, settings, ddcci);
+                    log::info!(
+                        "Fading brightness for '{}' to {}%",
+                        properties.device_name,
+                        brightness.brightness
+                    );
+                } else {
+                    writing.insert(properties.device_name.clone());
+                    start_write(
+                        m,
+                        properties.device_name.clone(),
+                        brightness.brightness,
+                        false,
+                        &state.writes_in_progress,
+                        write_sender.clone(),
+                    );
                 }
 
+                let error = fade_errors.remove(&properties.device_name);
                 MonitorResult {
                     properties,
-                    brightness: Some(BrightnessDetails {
-                        expiry_time: brightness.expiry_time,
-                        brightness: brightness.brightness,
-                        brightness_day,
-                        brightness_night,
-                    }),
-                    error: error.map(|e| e.to_string()),
+                    brightness: Some(brightness),
+                    error,
+                    write: None,
+                    unchanged: (unchanged && !verify) || fading,
+                    software_dimming: false,
                 }
             } else {
                 log::info!(
@@ -184,16 +560,445 @@ pub fn apply_brightness(
                     properties,
                     brightness: None,
                     error: None,
+                    write: None,
+                    unchanged: false,
+                    software_dimming: false,
                 }
             }
         })
         .sorted_by_key(|m| m.properties.device_name.clone())
         .collect::<Vec<_>>();
+    drop(write_sender);
+
+    // Wait for the writes to complete, but don't let a hung device block the others forever
+    let mut outcomes = HashMap::new();
+    let deadline = Instant::now() + WRITE_TIMEOUT;
+    while let Ok((name, outcome)) =
+        write_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        outcomes.insert(name, outcome);
+    }
+
+    for monitor in monitor_results
+        .iter_mut()
+        .filter(|m| writing.contains(&m.properties.device_name))
+    {
+        let name = &monitor.properties.device_name;
+        let brightness = monitor.brightness.as_ref().unwrap().brightness;
+        let outcome = outcomes.remove(name).unwrap_or_else(|| WriteOutcome {
+            result: Err(format!(
+                "Timed out after {}s setting brightness",
+                WRITE_TIMEOUT.as_secs()
+            )),
+            details: WriteDetails {
+                duration_ms: WRITE_TIMEOUT.as_millis() as u64,
+                attempts: 0,
+            },
+            verified: false,
+        });
+        match &outcome.result {
+            Ok(_) if outcome.verified => {
+                log::info!("Brightness for '{}' is still {}%", name, brightness);
+                monitor.unchanged = true;
+                continue;
+            }
+            Ok(_) => {
+                log::info!(
+                    "Successfully set brightness for '{}' to {}% (took {}ms)",
+                    name,
+                    brightness,
+                    outcome.details.duration_ms
+                );
+                state.last_applied.insert(name.clone(), brightness);
+            }
+            Err(e) => {
+                log::error!("Failed to set brightness for '{}': {}", name, e);
+                state.last_applied.remove(name);
+            }
+        }
+        monitor.error = outcome.result.err();
+        monitor.write = Some(outcome.details);
+    }
+
+    #[cfg(target_os = "linux")]
+    let color_temperature = if config.software_dimming.is_some()
+        || config.color_temperature.is_some()
+    {
+        let (dimmed, color_temperature) = apply_gamma(config, &overrides, &inputs, state, verify);
+        monitor_results.extend(dimmed);
+        color_temperature
+    } else {
+        state.gamma.reset();
+        state.last_applied_gamma.clear();
+        vec![]
+    };
+    #[cfg(not(target_os = "linux"))]
+    let color_temperature = vec![];
+
+    #[cfg(target_os = "linux")]
+    let leds = match &config.leds {
+        Some(settings) => crate::leds::apply_leds(
+            Path::new(crate::leds::LEDS_PATH),
+            settings,
+            config.transition_mins,
+            config.transition_curve,
+            &sun,
+            epoch_time_now,
+            &mut state.last_applied_leds,
+            verify,
+        ),
+        None => vec![],
+    };
+    #[cfg(not(target_os = "linux"))]
+    let leds = vec![];
 
     ApplyResults {
         unknown_devices: failed_monitors.into_iter().map(|f| f.to_string()).collect(),
+        location,
+        location_source,
+        ambient_brightness: ambient.map(|(_, brightness)| brightness),
+        cloud_cover: cloud_cover.map(|(_, cloud_cover)| cloud_cover),
+        manual_brightness,
         monitors: monitor_results,
+        leds,
+        color_temperature,
+        phase: calculate_phase(config.transition_mins, &sun, epoch_time_now),
         sun: sun.into(),
+        finished_fades,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchedulePoint {
+    pub time: i64,
+    pub brightness: u32,
+    pub sun_visible: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonitorSchedule {
+    pub device_name: String,
+    pub points: Vec<SchedulePoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Schedule {
+    /// For monitors that don't match any override
+    pub default: Vec<SchedulePoint>,
+    /// For each of these monitors, unless it is disabled by an override
+    pub monitors: Vec<MonitorSchedule>,
+}
+
+/// Calculate the brightness at every `step` seconds for `duration` seconds from `start`.
+/// This ignores the ambient light, cloud cover and manual brightness, as they can't be predicted.
+pub fn preview_schedule(
+    config: &SsbConfig,
+    location: Location,
+    monitors: &[&MonitorProperties],
+    start: i64,
+    duration: i64,
+    step: i64,
+) -> Schedule {
+    let suns = (0..=duration / step)
+        .map(|i| {
+            let time = start + i * step;
+            let sun = SunriseSunsetParameters::new(time, location.latitude, location.longitude)
+                .calculate()
+                .unwrap();
+            (time, sun)
+        })
+        .collect::<Vec<_>>();
+    let points = |(values, transition_mins, curve): (Option<BrightnessValues>, u32, _)| {
+        values.map(|values| {
+            suns.iter()
+                .map(|(time, sun)| SchedulePoint {
+                    time: *time,
+                    brightness: calculate_brightness(
+                        values.brightness_day,
+                        values.brightness_night,
+                        transition_mins,
+                        curve,
+                        sun,
+                        *time,
+                    )
+                    .brightness,
+                    sun_visible: sun.visible,
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    let overrides = config
+        .overrides
+        .iter()
+        .map(MonitorOverrideCompiled::from)
+        .collect::<Vec<_>>();
+    Schedule {
+        default: points(monitor_settings(config, None)).unwrap(),
+        monitors: monitors
+            .iter()
+            .filter_map(|m| {
+                let settings = monitor_settings(config, match_monitor(&overrides, m));
+                Some(MonitorSchedule {
+                    device_name: m.device_name.clone(),
+                    points: points(settings)?,
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Set the gamma tables of the outputs, to dim those that have no backlight control and to
+/// adjust their colour temperature
+#[cfg(target_os = "linux")]
+fn apply_gamma(
+    config: &SsbConfig,
+    overrides: &[MonitorOverrideCompiled],
+    inputs: &BrightnessInputs,
+    state: &mut ApplyState,
+    verify: bool,
+) -> (Vec<MonitorResult>, Vec<ColorTemperatureResult>) {
+    let outputs = match state.gamma.outputs() {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            log::warn!("Gamma adjustment is unavailable: {:#}", e);
+            return (vec![], vec![]);
+        }
+    };
+    state
+        .last_applied_gamma
+        .retain(|name, _| outputs.contains(name));
+    let dimming = config.software_dimming.as_ref().map(|settings| {
+        let hardware = hardware_outputs(Path::new(DRM_PATH), Path::new(BACKLIGHT_PATH));
+        let patterns = settings
+            .outputs
+            .iter()
+            .map(|p| WildMatch::new(p))
+            .collect::<Vec<_>>();
+        (settings.min_brightness, hardware, patterns)
+    });
+    let mut monitor_results = vec![];
+    let mut temperature_results = vec![];
+    for name in outputs.into_iter().sorted() {
+        let properties = MonitorProperties {
+            device_name: name.clone(),
+        };
+        let monitor_override = match_monitor(overrides, &properties);
+        let mut adjustment = Adjustment::IDENTITY;
+
+        let min_brightness = match &dimming {
+            Some((min_brightness, hardware, patterns))
+                if !hardware.contains(&normalise_output_name(&name))
+                    && patterns.iter().any(|p| p.matches(&name)) =>
+            {
+                Some(*min_brightness)
+            }
+            _ => None,
+        };
+        let brightness = min_brightness.and_then(|min_brightness| {
+            let brightness = monitor_brightness(config, monitor_override, inputs, &name);
+            match &brightness {
+                // Never dimmed below the minimum, so that the output remains readable
+                Some(b) => adjustment.brightness = b.brightness.max(min_brightness),
+                None => log::info!("Skipping '{}' due to monitor override", name),
+            }
+            brightness
+        });
+
+        let (values, transition_mins, transition_curve) =
+            temperature_settings(config, monitor_override);
+        let temperature = values.map(|values| {
+            let temperature = calculate_temperature(
+                values.temperature_day,
+                values.temperature_night,
+                transition_mins,
+                transition_curve,
+                inputs.sun,
+                inputs.time_now,
+            );
+            adjustment.temperature = temperature.temperature;
+            (values, temperature)
+        });
+
+        if brightness.is_none() && temperature.is_none() {
+            // Restore any output that is no longer adjusted, e.g. after changing an override
+            if state.last_applied_gamma.remove(&name).is_some() {
+                if let Err(e) = state.gamma.set(&name, Adjustment::IDENTITY) {
+                    log::warn!("Unable to reset gamma for '{}': {:#}", name, e);
+                }
+            }
+            if min_brightness.is_some() {
+                monitor_results.push(MonitorResult {
+                    properties,
+                    brightness: None,
+                    error: None,
+                    write: None,
+                    unchanged: false,
+                    software_dimming: true,
+                });
+            }
+            continue;
+        }
+
+        let unchanged = !verify && state.last_applied_gamma.get(&name) == Some(&adjustment);
+        let (error, write) = if unchanged {
+            log::info!(
+                "Gamma for '{}' is unchanged at {}% brightness and {}K",
+                name,
+                adjustment.brightness,
+                adjustment.temperature
+            );
+            (None, None)
+        } else {
+            let start = Instant::now();
+            let result = state.gamma.set(&name, adjustment);
+            let write = WriteDetails {
+                duration_ms: start.elapsed().as_millis() as u64,
+                attempts: 1,
+            };
+            match result {
+                Ok(_) => {
+                    log::info!(
+                        "Successfully set gamma for '{}' to {}% brightness and {}K",
+                        name,
+                        adjustment.brightness,
+                        adjustment.temperature
+                    );
+                    state.last_applied_gamma.insert(name.clone(), adjustment);
+                    (None, Some(write))
+                }
+                Err(e) => {
+                    log::error!("Failed to set gamma for '{}': {:#}", name, e);
+                    state.last_applied_gamma.remove(&name);
+                    (Some(format!("{:#}", e)), Some(write))
+                }
+            }
+        };
+
+        if let Some((values, temperature)) = temperature {
+            let TemperatureResult {
+                expiry_time,
+                temperature,
+            } = temperature;
+            temperature_results.push(ColorTemperatureResult {
+                output: name.clone(),
+                expiry_time,
+                temperature,
+                temperature_day: values.temperature_day,
+                temperature_night: values.temperature_night,
+                error: error.clone(),
+                unchanged,
+            });
+        }
+        if min_brightness.is_some() {
+            let dimmed = brightness.is_some();
+            monitor_results.push(MonitorResult {
+                properties,
+                error: error.filter(|_| dimmed),
+                write: write.filter(|_| dimmed),
+                unchanged: unchanged && dimmed,
+                brightness,
+                software_dimming: true,
+            });
+        }
+    }
+    (monitor_results, temperature_results)
+}
+
+/// Set the brightness of a device on a new thread, the outcome is sent to the channel.
+/// If verify is set then the device is read first, and only written to if it doesn't match.
+fn start_write(
+    device: BrightnessDevice,
+    name: String,
+    brightness: u32,
+    verify: bool,
+    writes_in_progress: &Arc<Mutex<HashSet<String>>>,
+    sender: mpsc::Sender<(String, WriteOutcome)>,
+) {
+    if !writes_in_progress.lock().unwrap().insert(name.clone()) {
+        let outcome = WriteOutcome {
+            result: Err("The previous write to this device has not yet completed".to_owned()),
+            details: WriteDetails {
+                duration_ms: 0,
+                attempts: 0,
+            },
+            verified: false,
+        };
+        sender.send((name, outcome)).unwrap();
+        return;
+    }
+    let writes_in_progress = writes_in_progress.clone();
+    thread::spawn(move || {
+        let outcome = if verify {
+            verify_or_set(&device, &name, brightness)
+        } else {
+            set_with_retry(&device, &name, brightness)
+        };
+        writes_in_progress.lock().unwrap().remove(&name);
+        // The receiver will have gone away if this write timed out
+        sender.send((name, outcome)).ok();
+    });
+}
+
+/// Read the current brightness of the device, and set it if it has drifted from the target
+fn verify_or_set(device: &BrightnessDevice, name: &str, brightness: u32) -> WriteOutcome {
+    let start = Instant::now();
+    match device.get() {
+        Ok(current) if current == brightness => WriteOutcome {
+            result: Ok(()),
+            details: WriteDetails {
+                duration_ms: start.elapsed().as_millis() as u64,
+                attempts: 0,
+            },
+            verified: true,
+        },
+        Ok(current) => {
+            log::warn!(
+                "Brightness for '{}' has drifted from {}% to {}%, re-applying",
+                name,
+                brightness,
+                current
+            );
+            set_with_retry(device, name, brightness)
+        }
+        Err(e) => {
+            log::warn!("Unable to read brightness for '{name}', re-applying: {e}");
+            set_with_retry(device, name, brightness)
+        }
+    }
+}
+
+pub(crate) fn set_with_retry(
+    device: &BrightnessDevice,
+    name: &str,
+    brightness: u32,
+) -> WriteOutcome {
+    let start = Instant::now();
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        match device.set(brightness) {
+            Ok(_) => break Ok(()),
+            Err(e) if attempts < WRITE_ATTEMPTS => {
+                let backoff = RETRY_BACKOFF * 2_u32.pow(attempts - 1);
+                log::warn!(
+                    "Failed to set brightness for '{}' (attempt {}), retrying in {:?}: {}",
+                    name,
+                    attempts,
+                    backoff,
+                    e
+                );
+                thread::sleep(backoff);
+            }
+            Err(e) => break Err(e.to_string()),
+        }
+    };
+    WriteOutcome {
+        result,
+        details: WriteDetails {
+            duration_ms: start.elapsed().as_millis() as u64,
+            attempts,
+        },
+        verified: false,
     }
 }
 
@@ -212,6 +1017,19 @@ impl MonitorProperties {
         }
     }
 
+    /// Whether the monitor is controlled using DDC/CI, which is much slower than an internal backlight
+    pub fn is_ddcci(&self) -> bool {
+        cfg_if::cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                // The ddcci-backlight driver names devices after the ddcci bus
+                self.device_name.starts_with("ddcci")
+            } else {
+                // Internal displays can't be reliably distinguished, so assume the slower rate
+                true
+            }
+        }
+    }
+
     pub fn to_map(&self) -> HashMap<MonitorProperty, &str> {
         let mut map = HashMap::<_, &str>::new();
         map.insert(MonitorProperty::DeviceName, &self.device_name);
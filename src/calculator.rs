@@ -1,5 +1,7 @@
 //! Algorithm for computing the brightness percentage for a given time
 
+use crate::config::TransitionCurve;
+use serde::Serialize;
 use sunrise_sunset_calculator::SunriseSunsetResult;
 
 #[derive(Debug)]
@@ -60,10 +62,74 @@ fn sine_curve(
     }
 }
 
+fn linear_curve(
+    time_now: i64,
+    transition: u32,
+    event_time: i64,
+    decreasing: bool,
+    low_brightness: u32,
+    high_brightness: u32,
+) -> BrightnessResult {
+    let range = (high_brightness - low_brightness) as f64;
+    let transition = transition as f64;
+    // Fraction of the transition that has elapsed, the transition is centred on the event
+    let progress = ((time_now - event_time) as f64 + transition / 2.0) / transition;
+    let progress = progress.clamp(0.0, 1.0);
+    let brightness = if decreasing {
+        high_brightness as f64 - (range * progress)
+    } else {
+        low_brightness as f64 + (range * progress)
+    };
+    let brightness = brightness.round() as u32;
+
+    // Work out the expiry time; when the brightness will change to the next integer value
+    let next_update_brightness = if decreasing {
+        brightness.saturating_sub(1).max(low_brightness)
+    } else {
+        (brightness + 1).min(high_brightness)
+    };
+    let next_progress = if decreasing {
+        (high_brightness - next_update_brightness) as f64 / range
+    } else {
+        (next_update_brightness - low_brightness) as f64 / range
+    };
+    let expiry_offset = ((next_progress * transition) - transition / 2.0).round() as i64;
+    // Don't get stuck into an infinite loop if rounding puts the expiry in the past
+    let expiry_time = (expiry_offset + event_time).max(time_now + 1);
+    BrightnessResult {
+        expiry_time: Some(expiry_time),
+        brightness,
+    }
+}
+
+fn transition_curve(
+    curve: TransitionCurve,
+    time_now: i64,
+    transition: u32,
+    event_time: i64,
+    decreasing: bool,
+    low_brightness: u32,
+    high_brightness: u32,
+) -> BrightnessResult {
+    let f = match curve {
+        TransitionCurve::Sine => sine_curve,
+        TransitionCurve::Linear => linear_curve,
+    };
+    f(
+        time_now,
+        transition,
+        event_time,
+        decreasing,
+        low_brightness,
+        high_brightness,
+    )
+}
+
 pub fn calculate_brightness(
     brightness_day: u32,
     brightness_night: u32,
     transition_mins: u32,
+    curve: TransitionCurve,
     sun: &SunriseSunsetResult,
     time_now: i64,
 ) -> BrightnessResult {
@@ -99,7 +165,8 @@ pub fn calculate_brightness(
 
     if time_now < time_a {
         let event = if sun.visible { sun.rise } else { sun.set };
-        sine_curve(
+        transition_curve(
+            curve,
             time_now,
             transition_secs,
             event,
@@ -110,7 +177,8 @@ pub fn calculate_brightness(
     } else if time_now >= time_b {
         // Must be greater or equal to or it would get stuck in a loop
         let event = if sun.visible { sun.set } else { sun.rise };
-        sine_curve(
+        transition_curve(
+            curve,
             time_now,
             transition_secs,
             event,
@@ -131,6 +199,148 @@ pub fn calculate_brightness(
     }
 }
 
+/// A part of the day, dawn and dusk are the transitions centred on sunrise and sunset
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Dawn,
+    Day,
+    Dusk,
+    Night,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Dawn => "dawn",
+            Phase::Day => "day",
+            Phase::Dusk => "dusk",
+            Phase::Night => "night",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct PhaseResult {
+    pub phase: Phase,
+    /// Unix time that the next phase starts
+    pub expiry_time: i64,
+}
+
+/// The current phase, using the same transition times as `calculate_brightness`
+pub fn calculate_phase(
+    transition_mins: u32,
+    sun: &SunriseSunsetResult,
+    time_now: i64,
+) -> PhaseResult {
+    let half_transition_secs = (transition_mins * 60 / 2) as i64;
+    let (phase, expiry_time) = if sun.visible {
+        if time_now < sun.rise + half_transition_secs {
+            (Phase::Dawn, sun.rise + half_transition_secs)
+        } else if time_now >= sun.set - half_transition_secs {
+            (Phase::Dusk, sun.set + half_transition_secs)
+        } else {
+            (Phase::Day, sun.set - half_transition_secs)
+        }
+    } else if time_now < sun.set + half_transition_secs {
+        (Phase::Dusk, sun.set + half_transition_secs)
+    } else if time_now >= sun.rise - half_transition_secs {
+        (Phase::Dawn, sun.rise + half_transition_secs)
+    } else {
+        (Phase::Night, sun.rise - half_transition_secs)
+    };
+    PhaseResult { phase, expiry_time }
+}
+
+/// Reduce the day brightness according to the cloud cover percentage (0-100).
+/// When completely overcast the day brightness is reduced by `max_reduction` percent,
+/// but it is never reduced below the night brightness (unless night is brighter than day).
+pub fn cloud_adjusted_day_brightness(
+    brightness_day: u32,
+    brightness_night: u32,
+    cloud_cover: f64,
+    max_reduction: u32,
+) -> u32 {
+    let reduction = (cloud_cover.clamp(0.0, 100.0) / 100.0) * (max_reduction as f64 / 100.0);
+    let reduced = (brightness_day as f64 * (1.0 - reduction)).round() as u32;
+    if brightness_night < brightness_day {
+        reduced.max(brightness_night)
+    } else {
+        reduced
+    }
+}
+
+/// Blend the brightness computed from the sun position with the ambient light sensor brightness
+pub fn blend(solar: u32, sensor: u32, weight: u32) -> u32 {
+    let weight = weight as f64 / 100.0;
+    (solar as f64 * (1.0 - weight) + sensor as f64 * weight).round() as u32
+}
+
+/// Colour temperatures are calculated in steps of this many Kelvin, so that the gamma tables
+/// aren't rewritten every few seconds during a transition
+pub const TEMPERATURE_STEP: u32 = 50;
+
+#[derive(Debug)]
+pub struct TemperatureResult {
+    /// Unix time that the colour temperature should be re-calculated
+    pub expiry_time: Option<i64>,
+    /// Colour temperature in Kelvin for the current time
+    pub temperature: u32,
+}
+
+/// Calculate the colour temperature, using the same timing and curve as the brightness
+pub fn calculate_temperature(
+    temperature_day: u32,
+    temperature_night: u32,
+    transition_mins: u32,
+    curve: TransitionCurve,
+    sun: &SunriseSunsetResult,
+    time_now: i64,
+) -> TemperatureResult {
+    let steps = |temperature: u32| (temperature + TEMPERATURE_STEP / 2) / TEMPERATURE_STEP;
+    let result = calculate_brightness(
+        steps(temperature_day),
+        steps(temperature_night),
+        transition_mins,
+        curve,
+        sun,
+        time_now,
+    );
+    TemperatureResult {
+        expiry_time: result.expiry_time,
+        temperature: result.brightness * TEMPERATURE_STEP,
+    }
+}
+
+/// Convert a brightness percentage to the nearest of a device's levels, e.g. keyboard
+/// backlights often only have a few
+pub fn percent_to_level(percent: u32, max_level: u32) -> u32 {
+    (percent as f64 * max_level as f64 / 100.0).round() as u32
+}
+
+/// The elevation of the sun above the horizon in degrees, at a Unix time and location.
+/// This uses the low precision formulas from the Astronomical Almanac, accurate to about 0.1°.
+pub fn sun_elevation(time: i64, latitude: f64, longitude: f64) -> f64 {
+    // Days since the J2000 epoch
+    let days = time as f64 / 86400.0 - 10957.5;
+    let mean_longitude = 280.460 + 0.9856474 * days;
+    let mean_anomaly = (357.528 + 0.9856003 * days).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.0000004 * days).to_radians();
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+    let right_ascension =
+        (obliquity.cos() * ecliptic_longitude.sin()).atan2(ecliptic_longitude.cos());
+    // Greenwich mean sidereal time in degrees
+    let sidereal_time = 280.46061837 + 360.98564736629 * days;
+    let hour_angle = (sidereal_time + longitude).to_radians() - right_ascension;
+    let latitude = latitude.to_radians();
+    (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -191,4 +401,165 @@ mod tests {
         let r = sine_curve(end_of_transition, t_secs, rise, false, low, high);
         assert_eq!(high, r.brightness); // 76
     }
+
+    #[test]
+    fn test_sunset_linear_curve() {
+        let low = 40;
+        let high = 80;
+        let t_secs = 60 * 60; //60 minutes
+        let set = Utc.ymd(2018, 12, 2).and_hms(16, 0, 0).timestamp(); // Fictional
+
+        // At start of the transition it should equal the day brightness
+        let transition_start = Utc.ymd(2018, 12, 2).and_hms(15, 30, 0).timestamp();
+        let r = linear_curve(transition_start, t_secs, set, true, low, high);
+        assert_eq!(high, r.brightness); // 80
+
+        // Brightness decreases by 1% every 90 seconds
+        assert_eq!(Some(transition_start + 90), r.expiry_time);
+
+        // A quarter of the way through it should be a quarter of the way down
+        let before_sunset = Utc.ymd(2018, 12, 2).and_hms(15, 45, 0).timestamp();
+        let r = linear_curve(before_sunset, t_secs, set, true, low, high);
+        assert_eq!(70, r.brightness);
+
+        // At sunset it should be half way between the day and night brightness
+        let r = linear_curve(set, t_secs, set, true, low, high);
+        assert_eq!(60, r.brightness);
+
+        // At end of the transition it should equal the night brightness
+        let transition_end = Utc.ymd(2018, 12, 2).and_hms(16, 30, 0).timestamp();
+        let r = linear_curve(transition_end, t_secs, set, true, low, high);
+        assert_eq!(low, r.brightness); // 40
+    }
+
+    #[test]
+    fn test_sunrise_linear_curve() {
+        let low = 35;
+        let high = 76;
+        let t_secs = 40 * 60; //40 minutes
+        let rise = Utc.ymd(2018, 12, 2).and_hms(8, 0, 0).timestamp(); // Fictional
+
+        // At start of the transition it should equal the night brightness
+        let start_of_transition = Utc.ymd(2018, 12, 2).and_hms(7, 40, 0).timestamp();
+        let r = linear_curve(start_of_transition, t_secs, rise, false, low, high);
+        assert_eq!(low, r.brightness); // 35
+        assert!(r.expiry_time.unwrap() > start_of_transition);
+
+        // At sunrise it should be half way between the day and night brightness
+        let r = linear_curve(rise, t_secs, rise, false, low, high);
+        assert_eq!(56, r.brightness); // 55.5 is rounded to 56
+
+        // At end of the transition it should equal the daytime brightness
+        let end_of_transition = Utc.ymd(2018, 12, 2).and_hms(8, 20, 0).timestamp();
+        let r = linear_curve(end_of_transition, t_secs, rise, false, low, high);
+        assert_eq!(high, r.brightness); // 76
+    }
+
+    #[test]
+    fn test_cloud_adjusted_day_brightness() {
+        // Clear sky leaves the day brightness as it is
+        assert_eq!(cloud_adjusted_day_brightness(80, 40, 0.0, 30), 80);
+        // Half overcast gives half the maximum reduction
+        assert_eq!(cloud_adjusted_day_brightness(80, 40, 50.0, 30), 68);
+        assert_eq!(cloud_adjusted_day_brightness(80, 40, 100.0, 30), 56);
+        // Never darker than night
+        assert_eq!(cloud_adjusted_day_brightness(80, 40, 100.0, 100), 40);
+        // Unless night is brighter than day
+        assert_eq!(cloud_adjusted_day_brightness(50, 90, 100.0, 20), 40);
+    }
+
+    #[test]
+    fn test_blend() {
+        assert_eq!(blend(100, 40, 50), 70);
+        assert_eq!(blend(100, 40, 0), 100);
+        assert_eq!(blend(100, 40, 100), 40);
+    }
+
+    #[test]
+    fn test_calculate_phase() {
+        let rise = Utc.ymd(2018, 12, 2).and_hms(8, 0, 0).timestamp();
+        let set = Utc.ymd(2018, 12, 2).and_hms(16, 0, 0).timestamp();
+        let day = SunriseSunsetResult {
+            rise,
+            set,
+            visible: true,
+        };
+        let phase = |sun, hour, min| {
+            let time = Utc.ymd(2018, 12, 2).and_hms(hour, min, 0).timestamp();
+            let result = calculate_phase(40, sun, time);
+            assert!(result.expiry_time > time);
+            (result.phase, result.expiry_time)
+        };
+        let at = |hour, min| Utc.ymd(2018, 12, 2).and_hms(hour, min, 0).timestamp();
+        assert_eq!(phase(&day, 8, 10), (Phase::Dawn, at(8, 20)));
+        assert_eq!(phase(&day, 8, 20), (Phase::Day, at(15, 40)));
+        assert_eq!(phase(&day, 15, 40), (Phase::Dusk, at(16, 20)));
+
+        // At night the sunset is in the past, and the sunrise is in the future
+        let night = SunriseSunsetResult {
+            rise: rise + 86400,
+            set,
+            visible: false,
+        };
+        assert_eq!(phase(&night, 16, 10), (Phase::Dusk, at(16, 20)));
+        assert_eq!(phase(&night, 16, 20), (Phase::Night, at(7, 40) + 86400));
+        assert_eq!(phase(&night, 23, 0), (Phase::Night, at(7, 40) + 86400));
+        let dawn = calculate_phase(40, &night, at(7, 50) + 86400);
+        assert_eq!(dawn.phase, Phase::Dawn);
+        assert_eq!(dawn.expiry_time, at(8, 20) + 86400);
+    }
+
+    #[test]
+    fn test_calculate_temperature() {
+        let rise = Utc.ymd(2018, 12, 2).and_hms(8, 0, 0).timestamp();
+        let set = Utc.ymd(2018, 12, 2).and_hms(16, 0, 0).timestamp();
+        let sun = SunriseSunsetResult {
+            rise,
+            set,
+            visible: true,
+        };
+        let at = |hour, min| Utc.ymd(2018, 12, 2).and_hms(hour, min, 0).timestamp();
+        let temperature =
+            |time| calculate_temperature(6500, 3500, 60, TransitionCurve::Linear, &sun, time);
+
+        let r = temperature(at(12, 0));
+        assert_eq!(r.temperature, 6500);
+        assert_eq!(r.expiry_time, Some(at(15, 30)));
+
+        // Half way through the transition at sunset, then one step after the expiry
+        let r = temperature(set);
+        assert_eq!(r.temperature, 5000);
+        let expiry = r.expiry_time.unwrap();
+        assert!(expiry > set && expiry - set <= 60);
+        assert_eq!(temperature(expiry).temperature, 4950);
+
+        // Rounded to the nearest step
+        let r = calculate_temperature(6520, 6480, 60, TransitionCurve::Sine, &sun, at(12, 0));
+        assert_eq!(r.temperature, 6500);
+        assert_eq!(r.expiry_time, None);
+    }
+
+    #[test]
+    fn test_percent_to_level() {
+        assert_eq!(percent_to_level(0, 3), 0);
+        assert_eq!(percent_to_level(50, 3), 2);
+        assert_eq!(percent_to_level(100, 3), 3);
+        assert_eq!(percent_to_level(40, 255), 102);
+    }
+
+    #[test]
+    fn test_sun_elevation() {
+        // Overhead at noon on the Tropic of Cancer at the June solstice
+        let time = Utc.ymd(2023, 6, 21).and_hms(12, 0, 0).timestamp();
+        assert!((sun_elevation(time, 23.44, 0.0) - 89.5).abs() < 1.0);
+        // Approximately 62° at noon in London at the June solstice
+        let time = Utc.ymd(2023, 6, 21).and_hms(12, 0, 0).timestamp();
+        assert!((sun_elevation(time, 51.5, 0.0) - 62.0).abs() < 1.0);
+        // Below the horizon at midnight
+        let time = Utc.ymd(2023, 12, 21).and_hms(0, 0, 0).timestamp();
+        assert!((sun_elevation(time, 51.5, 0.0) + 61.0).abs() < 1.0);
+        // Rising in the east, so negative before sunrise in New York
+        let time = Utc.ymd(2023, 3, 20).and_hms(10, 0, 0).timestamp();
+        assert!(sun_elevation(time, 40.7, -74.0) < 0.0);
+    }
 }
@@ -1,13 +1,29 @@
 //! Entry point for CLI driven application
 use anyhow::Context;
-use clap::Parser;
-use solar_screen_brightness::apply::apply_brightness;
+use clap::{Parser, Subcommand};
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::ambient::AmbientLightWatcher;
+use solar_screen_brightness::apply::{apply_brightness, ApplyState};
 use solar_screen_brightness::common::{install_logger, APP_NAME};
-use solar_screen_brightness::config::SsbConfig;
-use solar_screen_brightness::controller::BrightnessController;
+use solar_screen_brightness::config::{get_default_config_path, Geocoder, OverrideSet, SsbConfig};
+use solar_screen_brightness::controller::{BrightnessController, Message};
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::dbus_service::{reload_running_instance, DbusService};
 use solar_screen_brightness::event_watcher::EventWatcher;
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::geoclue::{locate_now, GeoclueWatcher};
+use solar_screen_brightness::geocoder::search;
+use solar_screen_brightness::http_api::HttpApiServer;
+use solar_screen_brightness::location::resolve_location;
+use solar_screen_brightness::mqtt::MqttClient;
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::systemd::{
+    connected_to_journal, install_user_service, service_name, JournalLogger,
+};
+use solar_screen_brightness::theme::ThemeSwitcher;
 use solar_screen_brightness::unique::SsbUniqueInstance;
-use std::path::PathBuf;
+use solar_screen_brightness::weather::WeatherWatcher;
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, RwLock};
 
 #[derive(Parser, Debug)]
@@ -22,6 +38,117 @@ struct Args {
     /// Override the config file path
     #[arg(long)]
     config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage monitor overrides
+    #[command(subcommand)]
+    Overrides(OverridesCommand),
+    /// Manage the location
+    #[command(subcommand)]
+    Location(LocationCommand),
+    /// Run as a systemd service, sending readiness and watchdog notifications.
+    /// The config is reloaded on SIGHUP.
+    #[cfg(target_os = "linux")]
+    Daemon,
+    /// Install a systemd unit file for running the daemon
+    #[cfg(target_os = "linux")]
+    InstallService {
+        /// Install as a user service, which is the only kind supported
+        #[arg(long)]
+        user: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LocationCommand {
+    /// Search for the coordinates of a place by name
+    Search {
+        /// Name of the place, optionally followed by a comma and the country
+        #[arg(required = true)]
+        query: Vec<String>,
+        /// Only search the built in city database, instead of OpenStreetMap
+        #[arg(long)]
+        offline: bool,
+    },
+    /// List the saved locations
+    List,
+    /// Switch to a saved location
+    Use {
+        /// Name of the saved location
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OverridesCommand {
+    /// Export the configured monitor overrides to a file
+    Export {
+        /// Path of the overrides file to write
+        path: PathBuf,
+    },
+    /// Import monitor overrides from a file into the config
+    Import {
+        /// Path of the overrides file to read
+        path: PathBuf,
+        /// Replace the existing overrides instead of appending to them
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+/// The controller and the watchers that send it events, which are all stopped when dropped
+struct Running {
+    // Fields are dropped in order, the watchers must be stopped before the controller
+    _weather_watcher: WeatherWatcher,
+    #[cfg(target_os = "linux")]
+    _geoclue_watcher: GeoclueWatcher,
+    #[cfg(target_os = "linux")]
+    _ambient_light_watcher: AmbientLightWatcher,
+    _event_watcher: anyhow::Result<EventWatcher>,
+    #[cfg(target_os = "linux")]
+    _dbus_service: DbusService,
+    _http_api_server: HttpApiServer,
+    _mqtt_client: MqttClient,
+    _theme_switcher: ThemeSwitcher,
+    controller: BrightnessController,
+}
+
+impl Running {
+    fn start(config: Arc<RwLock<SsbConfig>>, config_path: PathBuf) -> Self {
+        let controller = BrightnessController::start(config.clone(), || {});
+        Self {
+            _theme_switcher: ThemeSwitcher::start(config.clone(), &controller),
+            _mqtt_client: MqttClient::start(config.clone(), &controller),
+            _http_api_server: HttpApiServer::start(
+                config.clone(),
+                config_path.clone(),
+                &controller,
+            ),
+            #[cfg(target_os = "linux")]
+            _dbus_service: DbusService::start(config.clone(), config_path, &controller),
+            _event_watcher: EventWatcher::start(&controller, None),
+            #[cfg(target_os = "linux")]
+            _ambient_light_watcher: AmbientLightWatcher::start(config.clone(), &controller),
+            #[cfg(target_os = "linux")]
+            _geoclue_watcher: GeoclueWatcher::start(config.clone(), &controller),
+            _weather_watcher: WeatherWatcher::start(config, &controller),
+            controller,
+        }
+    }
+}
+
+fn load_config(path: Option<PathBuf>) -> anyhow::Result<SsbConfig> {
+    let config = SsbConfig::load(path)
+        .context("Unable to load config file")?
+        .context("Config file does not exist")?;
+    if !config.automatic_location {
+        resolve_location(&config, None).context("Location is not configured")?;
+    }
+    Ok(config)
 }
 
 fn run(args: Args) -> anyhow::Result<()> {
@@ -30,53 +157,341 @@ fn run(args: Args) -> anyhow::Result<()> {
         APP_NAME,
         env!("CARGO_PKG_VERSION")
     );
-    let config = SsbConfig::load(args.config)
-        .context("Unable to load config file")?
-        .context("Config file does not exist")?;
-    config
-        .location
-        .as_ref()
-        .context("Location is not configured")?;
+    let config_path = args.config.unwrap_or_else(get_default_config_path);
+    let mut config = load_config(Some(config_path.clone()))?;
     if args.once {
+        // Write synchronously, so that the result includes any errors and the process doesn't
+        // exit before the writes have finished
+        config.fade = None;
+        #[cfg(target_os = "linux")]
+        let automatic = config.automatic_location.then(locate_now).flatten();
+        #[cfg(not(target_os = "linux"))]
+        let automatic = None;
+        let (location, location_source) =
+            resolve_location(&config, automatic).context("Location is not configured")?;
         let result = apply_brightness(
-            config.brightness_day,
-            config.brightness_night,
-            config.transition_mins,
-            config.location.unwrap(),
-            config.overrides,
+            &config,
+            location,
+            location_source,
+            &mut ApplyState::default(),
         );
         let pretty = serde_json::to_string_pretty(&result).unwrap();
         println!("{}", pretty);
     } else {
         let (tx, rx) = mpsc::channel();
-        let config = Arc::new(RwLock::new(config));
-        let controller = BrightnessController::start(config, || {});
-        let _event_watcher = EventWatcher::start(&controller, None);
+        let _running = Running::start(Arc::new(RwLock::new(config)), config_path);
         ctrlc::set_handler(move || tx.send(()).unwrap()).expect("Error setting Ctrl-C handler");
         rx.recv().expect("Could not receive from channel.");
     }
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
+fn run_daemon(config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    use sd_notify::NotifyState;
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use solar_screen_brightness::controller::HEARTBEAT_INTERVAL;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::{Duration, Instant};
+
+    log::info!(
+        "Starting {} (daemon), version: {}",
+        APP_NAME,
+        env!("CARGO_PKG_VERSION")
+    );
+    let config_path = config_path.unwrap_or_else(get_default_config_path);
+    let config = Arc::new(RwLock::new(load_config(Some(config_path.clone()))?));
+
+    let mut signals =
+        Signals::new([SIGHUP, SIGINT, SIGTERM]).context("Unable to register signal handlers")?;
+    let signals_handle = signals.handle();
+    let (tx, rx) = mpsc::channel();
+    let signals_thread = std::thread::spawn(move || {
+        for signal in signals.forever() {
+            tx.send(signal).unwrap();
+        }
+    });
+
+    let running = Running::start(config.clone(), config_path.clone());
+
+    // Ping the watchdog at half the interval systemd expects
+    let mut watchdog_usec = 0;
+    let watchdog_interval = sd_notify::watchdog_enabled(false, &mut watchdog_usec)
+        .then(|| Duration::from_micros(watchdog_usec) / 2);
+    let mut next_ping = Instant::now();
+    notify(&[NotifyState::Ready]);
+
+    let mut result = Ok(());
+    loop {
+        let received = match watchdog_interval {
+            Some(interval) => {
+                if next_ping <= Instant::now() {
+                    // Stop pinging if the controller has hung or panicked, so systemd restarts it
+                    let heartbeat = running.controller.heartbeat.lock().unwrap().elapsed();
+                    if heartbeat < interval.max(HEARTBEAT_INTERVAL * 2) {
+                        notify(&[NotifyState::Watchdog]);
+                    } else {
+                        log::error!(
+                            "BrightnessController has not responded for {}s, not notifying the watchdog",
+                            heartbeat.as_secs()
+                        );
+                    }
+                    next_ping = Instant::now() + interval;
+                }
+                rx.recv_timeout(next_ping.saturating_duration_since(Instant::now()))
+            }
+            None => rx.recv().map_err(|e| e.into()),
+        };
+        let signal = match received {
+            Ok(signal) => signal,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                // Still stop the controller cleanly, the error is logged on exit
+                result = Err(anyhow::anyhow!("Signal handler stopped unexpectedly"));
+                break;
+            }
+        };
+        if signal == SIGHUP {
+            log::info!("Received SIGHUP, reloading config");
+            notify(&[NotifyState::Reloading]);
+            reload_config(&config, &config_path, &running.controller);
+            notify(&[NotifyState::Ready]);
+        } else {
+            log::info!("Received signal {signal}, stopping");
+            break;
+        }
+    }
+
+    notify(&[NotifyState::Stopping]);
+    drop(running);
+    signals_handle.close();
+    if signals_thread.join().is_err() {
+        log::error!("Signal handler thread panicked");
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log::warn!("Unable to notify systemd: {e}");
+    }
+}
+
+/// Replace the running config with the config file, keeping the current config if it is invalid
+#[cfg(target_os = "linux")]
+fn reload_config(config: &RwLock<SsbConfig>, path: &Path, controller: &BrightnessController) {
+    match load_config(Some(path.to_path_buf())) {
+        Ok(new_config) => {
+            *config.write().unwrap() = new_config;
+            controller
+                .sender
+                .send(Message::Refresh("Config reload"))
+                .unwrap();
+        }
+        Err(e) => log::error!(
+            "Unable to reload config, keeping the current config: {:#}",
+            e
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run_install_service(config_path: Option<PathBuf>, user: bool) -> anyhow::Result<()> {
+    if !user {
+        anyhow::bail!("Only user services are supported, as the config is per user. Use --user");
+    }
+    let config_path = config_path
+        .map(|p| p.canonicalize())
+        .transpose()
+        .context("Unable to find the config file")?;
+    let path = install_user_service(config_path.as_deref())?;
+    println!("Installed {}", path.display());
+    println!("To start it now, and on login, run:");
+    println!("    systemctl --user daemon-reload");
+    println!("    systemctl --user enable --now {}", service_name());
+    Ok(())
+}
+
+/// When running as a systemd service, log to the journal so that entries have structured fields
+#[cfg(target_os = "linux")]
+fn install_daemon_logger(debug: bool) -> anyhow::Result<()> {
+    if connected_to_journal() {
+        match JournalLogger::install(debug) {
+            Ok(()) => return Ok(()),
+            Err(e) => eprintln!("Unable to log to the journal: {:#}", e),
+        }
+    }
+    install_logger(debug, false)
+}
+
+fn run_overrides(config_path: Option<PathBuf>, command: OverridesCommand) -> anyhow::Result<()> {
+    let config_path = config_path.unwrap_or_else(get_default_config_path);
+    let config =
+        SsbConfig::load(Some(config_path.clone())).context("Unable to load config file")?;
+    match command {
+        OverridesCommand::Export { path } => {
+            let set = OverrideSet {
+                overrides: config.unwrap_or_default().overrides,
+            };
+            set.save(&path).context("Unable to write overrides file")?;
+            println!(
+                "Exported {} overrides to {}",
+                set.overrides.len(),
+                path.display()
+            );
+        }
+        OverridesCommand::Import { path, replace } => {
+            let set = OverrideSet::load(&path)?;
+            let count = set.overrides.len();
+            let mut config = config.unwrap_or_default();
+            if replace {
+                config.overrides = set.overrides;
+            } else {
+                config.overrides.extend(set.overrides);
+            }
+            config
+                .save_to(&config_path)
+                .context("Unable to save config file")?;
+            println!(
+                "Imported {} overrides into {}",
+                count,
+                config_path.display()
+            );
+            reload_instance(&config_path);
+        }
+    }
+    Ok(())
+}
+
+/// Tell the running instance (if any) to reload the config file after it has been edited
+fn reload_instance(config_path: &Path) {
+    #[cfg(target_os = "linux")]
+    match zbus::blocking::Connection::session()
+        .map_err(zbus::fdo::Error::from)
+        .and_then(|c| reload_running_instance(&c, config_path))
+    {
+        Ok(true) => println!("Reloaded the running instance"),
+        Ok(false) => {}
+        // The running instance uses a different config file
+        Err(zbus::fdo::Error::InvalidArgs(detail)) => {
+            eprintln!("Warning: the running instance was not reloaded. {}", detail)
+        }
+        Err(e) => eprintln!(
+            "Unable to reload the running instance, restart it to apply the changes: {}",
+            e
+        ),
+    }
+    #[cfg(not(target_os = "linux"))]
+    println!(
+        "Restart any running instance using {} to apply the changes",
+        config_path.display()
+    );
+}
+
+fn run_location(config_path: Option<PathBuf>, command: LocationCommand) -> anyhow::Result<()> {
+    let config_path = config_path.unwrap_or_else(get_default_config_path);
+    match command {
+        LocationCommand::Search { query, offline } => {
+            let geocoder = if offline {
+                Geocoder::Offline
+            } else {
+                Geocoder::OpenStreetMap
+            };
+            let query = query.join(" ");
+            let results = search(geocoder, &query).context("Unable to search for location")?;
+            if results.is_empty() {
+                anyhow::bail!("No location could be found for '{}'", query);
+            }
+            for result in results {
+                println!(
+                    "{} ({:.5}, {:.5})",
+                    result.display_name(),
+                    result.location.latitude,
+                    result.location.longitude
+                );
+            }
+        }
+        LocationCommand::List => {
+            let config = SsbConfig::load(Some(config_path))
+                .context("Unable to load config file")?
+                .unwrap_or_default();
+            for saved in &config.saved_locations {
+                let active = config.active_location.as_ref() == Some(&saved.name);
+                println!(
+                    "{} {} ({:.5}, {:.5})",
+                    if active { "*" } else { " " },
+                    saved.name,
+                    saved.location.latitude,
+                    saved.location.longitude
+                );
+            }
+        }
+        LocationCommand::Use { name } => {
+            let mut config = SsbConfig::load(Some(config_path.clone()))
+                .context("Unable to load config file")?
+                .context("Config file does not exist")?;
+            config.use_saved_location(&name)?;
+            config
+                .save_to(&config_path)
+                .context("Unable to save config file")?;
+            println!(
+                "Switched to location '{}' in {}",
+                name,
+                config_path.display()
+            );
+            reload_instance(&config_path);
+        }
+    }
+    Ok(())
+}
+
 fn main() {
-    let args: Args = Args::parse();
-
-    // Check this is the only instance running
-    let _unique_instance = match SsbUniqueInstance::try_acquire() {
-        Ok(i) => i,
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(1);
+    let mut args: Args = Args::parse();
+
+    // Commands that only edit the config file don't need to be the unique instance
+    let command = args.command.take();
+    let runs_controller = match &command {
+        None => true,
+        #[cfg(target_os = "linux")]
+        Some(Command::Daemon) => true,
+        Some(_) => false,
+    };
+    let _unique_instance = if runs_controller {
+        // Check this is the only instance running
+        match SsbUniqueInstance::try_acquire() {
+            Ok(i) => Some(i),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
         }
+    } else {
+        None
     };
 
     // Setup logging
-    if let Err(e) = install_logger(args.debug, false) {
+    let logger = match &command {
+        #[cfg(target_os = "linux")]
+        Some(Command::Daemon) => install_daemon_logger(args.debug),
+        _ => install_logger(args.debug, false),
+    };
+    if let Err(e) = logger {
         eprintln!("Unable to install logger: {:#}", e);
         std::process::exit(1);
     }
     // Run the application logic
-    if let Err(e) = run(args) {
+    let result = match command {
+        None => run(args),
+        Some(Command::Overrides(command)) => run_overrides(args.config, command),
+        Some(Command::Location(command)) => run_location(args.config, command),
+        #[cfg(target_os = "linux")]
+        Some(Command::Daemon) => run_daemon(args.config),
+        #[cfg(target_os = "linux")]
+        Some(Command::InstallService { user }) => run_install_service(args.config, user),
+    };
+    if let Err(e) = result {
         log::error!("{:#}", e);
         std::process::exit(1);
     }
@@ -1,16 +1,20 @@
 //! SSB Config file definition
 use crate::common::config_directory;
+use crate::geocoder::SearchResult;
 use anyhow::Context;
 use enum_iterator::Sequence;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tempfile::NamedTempFile;
 use validator::Validate;
 
 const CONFIG_FILE_NAME: &str = "config.json";
 
+/// How many location search results are kept in the history
+const SEARCH_HISTORY_LENGTH: usize = 5;
+
 #[derive(Debug, Deserialize, Serialize, Validate, Clone, Copy, PartialEq)]
 pub struct Location {
     #[validate(range(min = -90, max = 90))]
@@ -19,6 +23,14 @@ pub struct Location {
     pub longitude: f64,
 }
 
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct NamedLocation {
+    #[validate(length(min = 1))]
+    pub name: String,
+    #[validate]
+    pub location: Location,
+}
+
 #[derive(Debug, Deserialize, Serialize, Validate, Clone)]
 pub struct SsbConfig {
     #[validate(range(max = 100))]
@@ -27,11 +39,392 @@ pub struct SsbConfig {
     pub brightness_night: u32,
     #[validate(range(max = 360))]
     pub transition_mins: u32,
+    #[serde(default)]
+    pub transition_curve: TransitionCurve,
     #[validate]
     pub location: Option<Location>,
+    /// Find the location automatically using GeoClue (Linux only), the configured location is
+    /// used whenever it is unavailable
+    #[serde(default)]
+    pub automatic_location: bool,
+    /// Named locations that can be switched between, e.g. home and office
+    #[serde(default)]
+    #[validate]
+    pub saved_locations: Vec<NamedLocation>,
+    /// The name of the saved location that is currently in use
+    #[serde(default)]
+    pub active_location: Option<String>,
     #[serde(default)]
     #[validate]
     pub overrides: Vec<MonitorOverride>,
+    #[serde(default)]
+    #[validate]
+    pub fade: Option<FadeSettings>,
+    /// Periodically check the brightness of every monitor and correct any that have drifted,
+    /// for monitors that reset themselves without notifying the OS
+    #[serde(default)]
+    #[validate(range(min = 1, max = 1440))]
+    pub reassert_mins: Option<u32>,
+    /// Blend the brightness with the light measured by an ambient light sensor
+    #[serde(default)]
+    #[validate]
+    pub ambient_light: Option<AmbientLightSettings>,
+    /// Reduce the day brightness when it is cloudy
+    #[serde(default)]
+    #[validate]
+    pub weather: Option<WeatherSettings>,
+    /// Where to search for locations by name
+    #[serde(default)]
+    pub geocoder: Geocoder,
+    /// The most recently chosen location search results, newest first
+    #[serde(default)]
+    #[validate]
+    pub search_history: Vec<SearchResult>,
+    /// Serve a REST API on localhost, e.g. for dashboards
+    #[serde(default)]
+    #[validate]
+    pub http_api: Option<HttpApiSettings>,
+    /// Publish the state to an MQTT broker and subscribe to commands, e.g. for Home Assistant
+    #[serde(default)]
+    #[validate]
+    pub mqtt: Option<MqttSettings>,
+    /// Commands to run when the brightness or state changes
+    #[serde(default)]
+    #[validate]
+    pub hooks: Vec<Hook>,
+    /// Switch the desktop between the light and dark theme at dawn and dusk
+    #[serde(default)]
+    #[validate]
+    pub theme: Option<ThemeSettings>,
+    /// Also set the brightness of LED devices such as keyboard backlights (Linux only)
+    #[serde(default)]
+    #[validate]
+    pub leds: Option<LedSettings>,
+    /// Dim outputs that have no backlight control by adjusting their gamma tables (Linux only)
+    #[serde(default)]
+    #[validate]
+    pub software_dimming: Option<SoftwareDimmingSettings>,
+    /// Warm the colour of the outputs at night by adjusting their gamma tables (Linux only)
+    #[serde(default)]
+    #[validate]
+    pub color_temperature: Option<ColorTemperatureValues>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq, Sequence)]
+#[serde(rename_all = "snake_case")]
+pub enum Geocoder {
+    /// The embedded GeoNames city database, which works without network access but only contains
+    /// cities with a population over 1000
+    Offline,
+    /// The OpenStreetMap Nominatim API, falling back to the offline database if it fails
+    #[default]
+    OpenStreetMap,
+}
+
+impl Geocoder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Geocoder::Offline => "Offline",
+            Geocoder::OpenStreetMap => "OpenStreetMap",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct WeatherSettings {
+    pub provider: WeatherProvider,
+    /// How much the day brightness is reduced by when it is completely overcast
+    #[validate(range(max = 100))]
+    pub max_reduction: u32,
+    /// How often to fetch the cloud cover
+    #[validate(range(min = 5, max = 1440))]
+    pub refresh_mins: u32,
+}
+
+impl Default for WeatherSettings {
+    fn default() -> Self {
+        WeatherSettings {
+            provider: WeatherProvider::default(),
+            max_reduction: 30,
+            refresh_mins: 30,
+        }
+    }
+}
+
+/// Where the cloud cover percentage is obtained from
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WeatherProvider {
+    /// A file containing the cloud cover percentage, e.g. written by another program
+    File { path: PathBuf },
+    /// A command that prints the cloud cover percentage.
+    /// The location is passed in the SSB_LATITUDE and SSB_LONGITUDE environment variables.
+    Command { command: Vec<String> },
+    /// An HTTP API compatible with the Open-Meteo forecast API
+    OpenMeteo {
+        #[serde(default = "default_open_meteo_url")]
+        url: String,
+    },
+}
+
+impl Default for WeatherProvider {
+    fn default() -> Self {
+        WeatherProvider::OpenMeteo {
+            url: default_open_meteo_url(),
+        }
+    }
+}
+
+fn default_open_meteo_url() -> String {
+    "https://api.open-meteo.com/v1/forecast".to_owned()
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct HttpApiSettings {
+    /// The port to listen on, the API is only ever bound to localhost
+    #[validate(range(min = 1))]
+    pub port: u16,
+    /// Serve Prometheus metrics at `/metrics`
+    #[serde(default)]
+    pub metrics: bool,
+}
+
+impl Default for HttpApiSettings {
+    fn default() -> Self {
+        HttpApiSettings {
+            port: 8185,
+            metrics: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// The brightness of a monitor has changed, the hook is run once for each monitor
+    BrightnessChanged,
+    /// Dawn, day, dusk or night has started
+    PhaseChanged,
+    /// Automatic brightness has been paused, but not when the session is locked or asleep
+    Paused,
+    Resumed,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct Hook {
+    #[validate(length(min = 1))]
+    pub events: Vec<HookEvent>,
+    /// The program and its arguments, details of the event are passed in environment variables
+    #[validate(length(min = 1))]
+    pub command: Vec<String>,
+    /// The command is killed if it takes longer than this
+    #[serde(default = "default_hook_timeout_secs")]
+    #[validate(range(min = 1, max = 3600))]
+    pub timeout_secs: u32,
+}
+
+fn default_hook_timeout_secs() -> u32 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, Default, PartialEq)]
+pub struct ThemeSettings {
+    /// Switch when dawn and dusk start, rather than when they end
+    #[serde(default)]
+    pub at_transition_start: bool,
+    /// Run this command instead of setting the GNOME color scheme, with SSB_THEME set to
+    /// `light` or `dark`
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct SoftwareDimmingSettings {
+    /// Only outputs with a matching name are dimmed, e.g. HDMI-1
+    #[serde(default = "default_software_dimming_outputs")]
+    pub outputs: Vec<String>,
+    /// The outputs are never dimmed below this, so that they remain readable
+    #[serde(default = "default_software_dimming_min_brightness")]
+    #[validate(range(max = 100))]
+    pub min_brightness: u32,
+}
+
+impl Default for SoftwareDimmingSettings {
+    fn default() -> Self {
+        SoftwareDimmingSettings {
+            outputs: default_software_dimming_outputs(),
+            min_brightness: default_software_dimming_min_brightness(),
+        }
+    }
+}
+
+fn default_software_dimming_outputs() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+fn default_software_dimming_min_brightness() -> u32 {
+    20
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone)]
+pub struct LedSettings {
+    /// Devices in /sys/class/leds with a matching name are managed
+    #[serde(default = "default_led_pattern")]
+    pub pattern: String,
+    #[validate(range(max = 100))]
+    pub brightness_day: u32,
+    #[validate(range(max = 100))]
+    pub brightness_night: u32,
+    #[serde(default)]
+    #[validate]
+    pub overrides: Vec<LedOverride>,
+}
+
+impl Default for LedSettings {
+    fn default() -> Self {
+        LedSettings {
+            pattern: default_led_pattern(),
+            brightness_day: 0,
+            brightness_night: 100,
+            overrides: vec![],
+        }
+    }
+}
+
+fn default_led_pattern() -> String {
+    "*kbd_backlight*".to_owned()
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone)]
+pub struct LedOverride {
+    /// Matched against the device name
+    pub pattern: String,
+    /// The day and night brightness for this device, or None to leave it alone
+    #[validate]
+    pub brightness: Option<BrightnessValues>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct MqttSettings {
+    #[validate(length(min = 1))]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The state is published, and commands are received, under this topic
+    #[serde(default = "default_mqtt_base_topic")]
+    #[validate(length(min = 1))]
+    pub base_topic: String,
+    /// Publish Home Assistant discovery messages under this prefix, None to disable discovery
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: Option<String>,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            host: "localhost".to_owned(),
+            port: default_mqtt_port(),
+            username: None,
+            password: None,
+            base_topic: default_mqtt_base_topic(),
+            discovery_prefix: default_mqtt_discovery_prefix(),
+        }
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_base_topic() -> String {
+    "solar-screen-brightness".to_owned()
+}
+
+fn default_mqtt_discovery_prefix() -> Option<String> {
+    Some("homeassistant".to_owned())
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, PartialEq)]
+pub struct AmbientLightSettings {
+    /// How much the sensor contributes to the brightness, from 0 (ignored) to 100 (sensor only)
+    #[validate(range(max = 100))]
+    pub weight: u32,
+    /// Illuminance to brightness mapping, the brightness is interpolated between these points
+    #[validate(length(min = 1))]
+    #[validate]
+    pub mapping: Vec<LuxMapping>,
+    /// How much each new sample affects the smoothed illuminance, 1.0 disables smoothing
+    #[validate(range(min = 0.01, max = 1.0))]
+    pub smoothing: f64,
+    /// The sensor brightness must change by at least this much before the monitors are updated
+    #[validate(range(max = 100))]
+    pub hysteresis: u32,
+    #[validate(range(min = 1, max = 3600))]
+    pub sample_interval_secs: u32,
+}
+
+impl Default for AmbientLightSettings {
+    fn default() -> Self {
+        AmbientLightSettings {
+            weight: 50,
+            mapping: vec![
+                LuxMapping {
+                    lux: 0.0,
+                    brightness: 20,
+                },
+                LuxMapping {
+                    lux: 50.0,
+                    brightness: 40,
+                },
+                LuxMapping {
+                    lux: 400.0,
+                    brightness: 80,
+                },
+                LuxMapping {
+                    lux: 1000.0,
+                    brightness: 100,
+                },
+            ],
+            smoothing: 0.3,
+            hysteresis: 5,
+            sample_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, Copy, PartialEq)]
+pub struct LuxMapping {
+    #[validate(range(min = 0))]
+    pub lux: f64,
+    #[validate(range(max = 100))]
+    pub brightness: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Validate, Clone, Copy, PartialEq)]
+pub struct FadeSettings {
+    /// How long it should take to fade from the current to the target brightness
+    #[validate(range(min = 100, max = 60000))]
+    pub duration_ms: u32,
+    /// Minimum time between brightness steps for internal backlights
+    #[validate(range(min = 10, max = 10000))]
+    pub step_interval_ms: u32,
+    /// Minimum time between brightness steps for DDC/CI monitors, which are much slower to write
+    #[validate(range(min = 10, max = 10000))]
+    pub ddcci_step_interval_ms: u32,
+}
+
+impl Default for FadeSettings {
+    fn default() -> Self {
+        FadeSettings {
+            duration_ms: 2000,
+            step_interval_ms: 20,
+            ddcci_step_interval_ms: 200,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, Hash, PartialEq, Sequence)]
@@ -60,12 +453,40 @@ impl MonitorProperty {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq, Sequence)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionCurve {
+    #[default]
+    Sine,
+    Linear,
+}
+
+impl TransitionCurve {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionCurve::Sine => "Sine",
+            TransitionCurve::Linear => "Linear",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Validate, Clone)]
 pub struct MonitorOverride {
     pub pattern: String,
     pub key: MonitorProperty,
     #[validate]
     pub brightness: Option<BrightnessValues>,
+    /// Overrides the global transition_mins for this monitor
+    #[serde(default)]
+    #[validate(range(max = 360))]
+    pub transition_mins: Option<u32>,
+    /// Overrides the global transition_curve for this monitor
+    #[serde(default)]
+    pub transition_curve: Option<TransitionCurve>,
+    /// Overrides the global color_temperature for this monitor
+    #[serde(default)]
+    #[validate]
+    pub color_temperature: Option<ColorTemperatureValues>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, Copy, Clone)]
@@ -76,6 +497,34 @@ pub struct BrightnessValues {
     pub brightness_night: u32,
 }
 
+/// Colour temperatures in Kelvin, 6500K leaves the colours unchanged
+#[derive(Debug, Serialize, Deserialize, Validate, Copy, Clone, Eq, PartialEq)]
+pub struct ColorTemperatureValues {
+    #[serde(default = "default_temperature_day")]
+    #[validate(range(min = 1000, max = 10000))]
+    pub temperature_day: u32,
+    #[serde(default = "default_temperature_night")]
+    #[validate(range(min = 1000, max = 10000))]
+    pub temperature_night: u32,
+}
+
+impl Default for ColorTemperatureValues {
+    fn default() -> Self {
+        ColorTemperatureValues {
+            temperature_day: default_temperature_day(),
+            temperature_night: default_temperature_night(),
+        }
+    }
+}
+
+fn default_temperature_day() -> u32 {
+    6500
+}
+
+fn default_temperature_night() -> u32 {
+    3500
+}
+
 impl SsbConfig {
     pub fn load(path_override: Option<PathBuf>) -> anyhow::Result<Option<Self>> {
         let path = path_override.unwrap_or_else(get_default_config_path);
@@ -95,18 +544,97 @@ impl SsbConfig {
     }
 
     pub fn save(&self) -> anyhow::Result<()> {
-        let path = get_default_config_path();
+        self.save_to(&get_default_config_path())
+    }
+
+    /// Move the search result to the front of the history, removing the oldest if it is full
+    pub fn add_search_history(&mut self, result: SearchResult) {
+        self.search_history.retain(|r| *r != result);
+        self.search_history.insert(0, result);
+        self.search_history.truncate(SEARCH_HISTORY_LENGTH);
+    }
+
+    /// Save the location under this name and make it active, replacing any saved location with
+    /// the same name
+    pub fn save_location(&mut self, name: String, location: Location) {
+        match self.saved_locations.iter_mut().find(|l| l.name == name) {
+            Some(saved) => saved.location = location,
+            None => self.saved_locations.push(NamedLocation {
+                name: name.clone(),
+                location,
+            }),
+        }
+        self.location = Some(location);
+        self.active_location = Some(name);
+    }
+
+    pub fn remove_saved_location(&mut self, name: &str) {
+        self.saved_locations.retain(|l| l.name != name);
+        if self.active_location.as_deref() == Some(name) {
+            self.active_location = None;
+        }
+    }
+
+    /// Switch to the saved location with this name
+    pub fn use_saved_location(&mut self, name: &str) -> anyhow::Result<()> {
+        let saved = self
+            .saved_locations
+            .iter()
+            .find(|l| l.name == name)
+            .context(format!("There is no saved location named '{}'", name))?;
+        self.location = Some(saved.location);
+        self.active_location = Some(saved.name.clone());
+        Ok(())
+    }
+
+    pub fn save_to(&self, path: &Path) -> anyhow::Result<()> {
         let serialised = serde_json::to_string_pretty(&self).unwrap();
-        let parent = path.parent().expect("config path must have parent");
-        let mut temp_file = NamedTempFile::new_in(parent)?;
-        temp_file.write_all(serialised.as_bytes())?;
-        temp_file.flush()?;
-        temp_file.persist(&path)?;
+        write_atomically(path, serialised.as_bytes())?;
         log::debug!("Successfully saved config to {}", path.display());
         Ok(())
     }
 }
 
+/// A standalone file containing a set of monitor overrides, used to share them between machines
+#[derive(Debug, Deserialize, Serialize, Validate)]
+pub struct OverrideSet {
+    #[validate]
+    pub overrides: Vec<MonitorOverride>,
+}
+
+impl OverrideSet {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .context(format!("Unable to read file '{}'", path.display()))?;
+        let set = serde_json::from_str::<OverrideSet>(&contents).context(format!(
+            "Unable to deserialize overrides file '{}'",
+            path.display()
+        ))?;
+        set.validate()
+            .context(format!("Invalid overrides file '{}'", path.display()))?;
+        Ok(set)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let serialised = serde_json::to_string_pretty(&self).unwrap();
+        write_atomically(path, serialised.as_bytes())?;
+        log::debug!("Successfully saved overrides to {}", path.display());
+        Ok(())
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let mut temp_file = NamedTempFile::new_in(parent)?;
+    temp_file.write_all(contents)?;
+    temp_file.flush()?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
 pub fn get_default_config_path() -> PathBuf {
     config_directory().join(CONFIG_FILE_NAME)
 }
@@ -117,8 +645,25 @@ impl Default for SsbConfig {
             brightness_day: 100,
             brightness_night: 60,
             transition_mins: 40,
+            transition_curve: TransitionCurve::default(),
             location: None,
+            automatic_location: false,
+            saved_locations: vec![],
+            active_location: None,
             overrides: vec![],
+            fade: None,
+            reassert_mins: None,
+            ambient_light: None,
+            weather: None,
+            geocoder: Geocoder::default(),
+            search_history: vec![],
+            http_api: None,
+            mqtt: None,
+            hooks: vec![],
+            theme: None,
+            leds: None,
+            software_dimming: None,
+            color_temperature: None,
         }
     }
 }
@@ -1,23 +1,66 @@
-use crate::apply::{apply_brightness, ApplyResults};
+use crate::apply::{apply_brightness, ApplyResults, ApplyState};
+use crate::calculator::Phase;
 use crate::config::SsbConfig;
+use crate::hooks::{enabled_event, update_events, HookRunner};
+use crate::location::{resolve_location, AutomaticLocation};
+use crate::metrics::Metrics;
 use human_repr::HumanDuration;
+use serde::Serialize;
 use std::mem::take;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
-use std::sync::{mpsc, Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// The controller wakes up at least this often while waiting, to show that it is still running
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
 pub enum Message {
     Shutdown,
     Refresh(&'static str),
+    /// Refresh and write to every device, even if its brightness is unchanged.
+    /// Used when the devices may have been reset, e.g. after being reconnected.
+    ForceRefresh(&'static str),
+    /// Stop changing the brightness until enabled again, e.g. when paused by the user
     Disable(&'static str),
     Enable(&'static str),
+    /// Stop changing the brightness while the session is locked or asleep. This is tracked
+    /// separately from Disable, so that unlocking doesn't resume a pause.
+    Inhibit(&'static str),
+    /// The session has been unlocked or woken, the brightness is re-applied to every monitor
+    Uninhibit(&'static str),
+    /// The brightness measured by the ambient light sensor has changed, or is no longer available
+    AmbientLight(Option<u32>),
+    /// The cloud cover percentage has changed, or is no longer available
+    CloudCover(Option<f64>),
+    /// The location found automatically has changed, or is no longer available
+    AutomaticLocation(Option<AutomaticLocation>),
+    /// Set every monitor to this brightness instead of the calculated brightness, or clear it
+    ManualBrightness(Option<u32>),
+}
+
+/// The state of the controller, as reported by the D-Bus and HTTP APIs
+#[derive(Serialize)]
+pub struct ControllerStatus<'a> {
+    pub enabled: bool,
+    pub results: &'a Option<ApplyResults>,
 }
 
+/// Called after every update, and whenever the controller is enabled or disabled
+type Listener = Box<dyn Fn() + Send>;
+
 pub struct BrightnessController {
     pub sender: mpsc::Sender<Message>,
     pub last_result: Arc<RwLock<Option<ApplyResults>>>,
+    /// False while the controller is disabled, e.g. when paused or the session is locked
+    pub enabled: Arc<AtomicBool>,
+    pub metrics: Arc<Mutex<Metrics>>,
+    /// When the controller thread was last running, this stops changing if it hangs or panics
+    pub heartbeat: Arc<Mutex<Instant>>,
+    listeners: Arc<Mutex<Vec<Listener>>>,
     join_handle: Option<JoinHandle<()>>,
 }
 
@@ -28,16 +71,45 @@ impl BrightnessController {
     ) -> BrightnessController {
         let (sender, receiver) = mpsc::channel();
         let last_result = Arc::new(RwLock::new(None));
+        let enabled = Arc::new(AtomicBool::new(true));
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let heartbeat = Arc::new(Mutex::new(Instant::now()));
+        let listeners = Arc::new(Mutex::new(Vec::<Listener>::new()));
         let cloned = last_result.clone();
+        let enabled_cloned = enabled.clone();
+        let metrics_cloned = metrics.clone();
+        let heartbeat_cloned = heartbeat.clone();
+        let listeners_cloned = listeners.clone();
         let join_handle = thread::spawn(move || {
-            run(config, receiver, cloned, on_update);
+            let shared = Shared {
+                last_result: cloned,
+                enabled: enabled_cloned,
+                metrics: metrics_cloned,
+                heartbeat: heartbeat_cloned,
+            };
+            run(config, receiver, shared, move || {
+                on_update();
+                for listener in listeners_cloned.lock().unwrap().iter() {
+                    listener();
+                }
+            });
         });
         BrightnessController {
             sender,
             last_result,
+            enabled,
+            metrics,
+            heartbeat,
+            listeners,
             join_handle: Some(join_handle),
         }
     }
+
+    /// Add a function to be called after every update, and whenever the controller is enabled
+    /// or disabled. It is called from the controller thread, so must not block.
+    pub fn add_listener<F: Fn() + Send + 'static>(&self, listener: F) {
+        self.listeners.lock().unwrap().push(Box::new(listener));
+    }
 }
 
 impl Drop for BrightnessController {
@@ -48,37 +120,86 @@ impl Drop for BrightnessController {
     }
 }
 
+/// State that is written by the controller thread, and read by others
+struct Shared {
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    enabled: Arc<AtomicBool>,
+    metrics: Arc<Mutex<Metrics>>,
+    heartbeat: Arc<Mutex<Instant>>,
+}
+
 fn run<F: Fn()>(
     config: Arc<RwLock<SsbConfig>>,
     receiver: mpsc::Receiver<Message>,
-    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    shared: Shared,
     on_update: F,
 ) {
     log::info!("Starting BrightnessController");
-    let mut enabled = true;
+    let mut paused = false;
+    let mut was_paused = false;
+    let mut inhibited = false;
+    let mut apply_state = ApplyState::default();
+    let mut reassert_time = None;
+    let hook_runner = HookRunner::start();
 
     loop {
+        let enabled = !paused && !inhibited;
+        let was_enabled = shared.enabled.swap(enabled, Ordering::SeqCst);
+        shared.metrics.lock().unwrap().set_enabled(enabled);
+        // The hooks are only run when paused or resumed, not when the session is locked
+        if was_paused != paused {
+            was_paused = paused;
+            let hooks = &config.read().unwrap().hooks;
+            hook_runner.trigger(hooks, vec![enabled_event(!paused)]);
+        }
         let timeout = if enabled {
             // Apply brightness using latest config
             let config = config.read().unwrap().clone();
-            let result = apply(config);
+            // Only schedule a new re-assert once the previous one has run (or the interval has
+            // changed), otherwise frequent refreshes would keep postponing it
+            reassert_time = match (config.reassert_mins, reassert_time) {
+                (Some(mins), Some((scheduled_mins, time))) if mins == scheduled_mins => {
+                    Some((mins, time))
+                }
+                (Some(mins), _) => Some((
+                    mins,
+                    SystemTime::now() + Duration::from_secs(mins as u64 * 60),
+                )),
+                (None, _) => None,
+            };
+            let result = apply(&config, &mut apply_state);
             let timeout = calculate_timeout(&result);
+            shared
+                .metrics
+                .lock()
+                .unwrap()
+                .record(result.as_ref(), timeout);
 
             // Update last result
-            *last_result.write().unwrap() = result;
+            let mut last_result = shared.last_result.write().unwrap();
+            if let Some(phase) = phase_change(&last_result, &result) {
+                log::info!("Phase changed to {}", phase.name());
+            }
+            hook_runner.trigger(&config.hooks, update_events(&last_result, &result));
+            *last_result = result;
+            drop(last_result);
             on_update();
-            timeout
+            // Wake up to re-assert the brightness even if it isn't due to change
+            match (timeout, reassert_time.map(|(_, time)| time)) {
+                (Some(t), Some(r)) => Some(t.min(r)),
+                (t, r) => t.or(r),
+            }
         } else {
             log::info!("BrightnessController is disabled, skipping update");
+            if was_enabled {
+                on_update();
+            }
             None
         };
 
         // Sleep until receiving message or timeout
-        let rx_result = match timeout {
-            None => {
-                log::info!("Brightness Worker sleeping indefinitely");
-                receiver.recv().map_err(|e| e.into())
-            }
+        match timeout {
+            None => log::info!("Brightness Worker sleeping indefinitely"),
             Some(timeout) => {
                 let duration = timeout
                     .duration_since(SystemTime::now())
@@ -87,7 +208,21 @@ fn run<F: Fn()>(
                     "BrightnessController sleeping for {}s",
                     duration.human_duration()
                 );
-                receiver.recv_timeout(duration)
+            }
+        }
+        // Wake up regularly to update the heartbeat, but don't refresh until the timeout
+        let rx_result = loop {
+            *shared.heartbeat.lock().unwrap() = Instant::now();
+            let remaining =
+                timeout.map(|t| t.duration_since(SystemTime::now()).unwrap_or_default());
+            match remaining {
+                Some(remaining) if remaining <= HEARTBEAT_INTERVAL => {
+                    break receiver.recv_timeout(remaining)
+                }
+                _ => match receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    result => break result,
+                },
             }
         };
 
@@ -99,30 +234,76 @@ fn run<F: Fn()>(
             Ok(Message::Refresh(src)) => {
                 log::info!("Refreshing due to '{src}'");
             }
+            Ok(Message::ForceRefresh(src)) => {
+                log::info!("Force refreshing due to '{src}'");
+                apply_state.invalidate();
+            }
             Ok(Message::Disable(src)) => {
                 log::info!("Disabling BrightnessController due to '{src}'");
-                enabled = false;
+                paused = true;
             }
             Ok(Message::Enable(src)) => {
                 log::info!("Enabling BrightnessController due to '{src}'");
-                enabled = true;
+                paused = false;
+                // The monitors may have been reset while disabled
+                apply_state.invalidate();
+            }
+            Ok(Message::Inhibit(src)) => {
+                log::info!("Inhibiting BrightnessController due to '{src}'");
+                inhibited = true;
+            }
+            Ok(Message::Uninhibit(src)) => {
+                log::info!("Uninhibiting BrightnessController due to '{src}'");
+                inhibited = false;
+                // The monitors may have been reset while asleep
+                apply_state.invalidate();
+            }
+            Ok(Message::AmbientLight(brightness)) => {
+                log::info!("Refreshing due to ambient light brightness {brightness:?}");
+                apply_state.ambient_brightness = brightness;
+            }
+            Ok(Message::CloudCover(cloud_cover)) => {
+                log::info!("Refreshing due to cloud cover {cloud_cover:?}");
+                apply_state.cloud_cover = cloud_cover;
+            }
+            Ok(Message::AutomaticLocation(location)) => {
+                log::info!("Refreshing due to automatic location {location:?}");
+                apply_state.automatic_location = location;
+            }
+            Ok(Message::ManualBrightness(brightness)) => {
+                log::info!("Refreshing due to manual brightness {brightness:?}");
+                apply_state.manual_brightness = brightness;
             }
             Err(RecvTimeoutError::Timeout) => {
-                log::debug!("Refreshing due to timeout")
+                if matches!(reassert_time, Some((_, r)) if r <= SystemTime::now()) {
+                    log::info!("Re-asserting brightness");
+                    apply_state.reassert();
+                    reassert_time = None;
+                } else {
+                    log::debug!("Refreshing due to timeout")
+                }
             }
             Err(RecvTimeoutError::Disconnected) => panic!("Unexpected disconnection"),
         }
     }
 }
 
-// The time at which the brightness should be re-applied
-fn calculate_timeout(results: &Option<ApplyResults>) -> Option<SystemTime> {
+// The time at which the brightness should be re-applied, or the next phase starts
+pub fn calculate_timeout(results: &Option<ApplyResults>) -> Option<SystemTime> {
     if let Some(results) = results {
         results
             .monitors
             .iter()
             .flat_map(|m| m.brightness.as_ref().map(|b| b.expiry_time))
+            .chain(
+                results
+                    .leds
+                    .iter()
+                    .flat_map(|l| l.brightness.as_ref().map(|b| b.expiry_time)),
+            )
+            .chain(results.color_temperature.iter().map(|t| t.expiry_time))
             .flatten()
+            .chain([results.phase.expiry_time])
             .min()
             .map(|e| UNIX_EPOCH + Duration::from_secs(e as u64))
     } else {
@@ -130,16 +311,19 @@ fn calculate_timeout(results: &Option<ApplyResults>) -> Option<SystemTime> {
     }
 }
 
+/// The new phase, if it has changed since the previous results
+fn phase_change(previous: &Option<ApplyResults>, current: &Option<ApplyResults>) -> Option<Phase> {
+    let current = current.as_ref()?.phase.phase;
+    match previous {
+        Some(previous) if previous.phase.phase == current => None,
+        _ => Some(current),
+    }
+}
+
 // Calculate and apply the brightness
-fn apply(config: SsbConfig) -> Option<ApplyResults> {
-    if let Some(location) = config.location {
-        Some(apply_brightness(
-            config.brightness_day,
-            config.brightness_night,
-            config.transition_mins,
-            location,
-            config.overrides,
-        ))
+fn apply(config: &SsbConfig, state: &mut ApplyState) -> Option<ApplyResults> {
+    if let Some((location, source)) = resolve_location(config, state.automatic_location) {
+        Some(apply_brightness(config, location, source, state))
     } else {
         log::warn!("Skipping apply because no location is configured");
         None
@@ -0,0 +1,369 @@
+//! Exports a D-Bus object on the session bus, so that desktop extensions and scripts can control
+//! the running instance and be notified of changes without polling
+
+use crate::apply::ApplyResults;
+use crate::config::{Location, SsbConfig};
+use crate::controller::{calculate_timeout, BrightnessController, ControllerStatus, Message};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::UNIX_EPOCH;
+use validator::Validate;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo};
+
+pub const BUS_NAME: &str = "org.solar_screen_brightness";
+pub const OBJECT_PATH: &str = "/org/solar_screen_brightness";
+
+struct SsbInterface {
+    config: Arc<RwLock<SsbConfig>>,
+    config_path: PathBuf,
+    controller: Mutex<mpsc::Sender<Message>>,
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl SsbInterface {
+    fn send(&self, message: Message) {
+        self.controller.lock().unwrap().send(message).unwrap();
+    }
+}
+
+#[dbus_interface(name = "org.solar_screen_brightness")]
+impl SsbInterface {
+    /// Re-calculate and apply the brightness now
+    fn refresh(&self) {
+        self.send(Message::Refresh("D-Bus Refresh"));
+    }
+
+    /// Stop changing the brightness until resumed
+    fn pause(&self) {
+        self.send(Message::Disable("D-Bus Pause"));
+    }
+
+    fn resume(&self) {
+        self.send(Message::Enable("D-Bus Resume"));
+    }
+
+    /// Set and save the location
+    fn set_location(&self, latitude: f64, longitude: f64) -> fdo::Result<()> {
+        let location = Location {
+            latitude,
+            longitude,
+        };
+        location
+            .validate()
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        let mut config = self.config.write().unwrap();
+        config.location = Some(location);
+        config.active_location = None;
+        self.send(Message::Refresh("D-Bus SetLocation"));
+        config
+            .save_to(&self.config_path)
+            .map_err(|e| fdo::Error::Failed(format!("Unable to save config: {:#}", e)))
+    }
+
+    /// Replace the running config with the config file, e.g. after it was edited by `ssb-cli`.
+    /// This fails if `config_path` isn't the file that this instance was started with.
+    fn reload(&self, config_path: &str) -> fdo::Result<()> {
+        if !same_file(Path::new(config_path), &self.config_path) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "The running instance uses the config file '{}'",
+                self.config_path.display()
+            )));
+        }
+        let config = SsbConfig::load(Some(self.config_path.clone()))
+            .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("Config file does not exist")))
+            .map_err(|e| fdo::Error::Failed(format!("Unable to reload config: {:#}", e)))?;
+        *self.config.write().unwrap() = config;
+        self.send(Message::Refresh("D-Bus Reload"));
+        Ok(())
+    }
+
+    /// The latest results as JSON, in the same format as `ssb-cli --once`
+    fn get_status(&self) -> String {
+        let results = self.last_result.read().unwrap();
+        serde_json::to_string(&ControllerStatus {
+            enabled: self.enabled(),
+            results: &results,
+        })
+        .unwrap()
+    }
+
+    /// False while paused, or while the session is locked
+    #[dbus_interface(property)]
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// The brightness of each monitor, by device name
+    #[dbus_interface(property)]
+    fn brightness(&self) -> HashMap<String, u32> {
+        let results = self.last_result.read().unwrap();
+        results
+            .iter()
+            .flat_map(|r| &r.monitors)
+            .filter_map(|m| {
+                let brightness = m.brightness.as_ref()?.brightness;
+                Some((m.properties.device_name.clone(), brightness))
+            })
+            .collect()
+    }
+
+    /// Unix time of the next brightness change, or 0 if none is scheduled
+    #[dbus_interface(property)]
+    fn next_update(&self) -> i64 {
+        calculate_timeout(&self.last_result.read().unwrap())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    }
+}
+
+enum Event {
+    Updated,
+    Stop,
+}
+
+/// Serves the D-Bus object on its own thread, and emits PropertiesChanged signals whenever the
+/// BrightnessController updates
+pub struct DbusService {
+    events: mpsc::Sender<Event>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl DbusService {
+    pub fn start(
+        config: Arc<RwLock<SsbConfig>>,
+        config_path: PathBuf,
+        controller: &BrightnessController,
+    ) -> Self {
+        let interface = SsbInterface {
+            config,
+            config_path,
+            controller: Mutex::new(controller.sender.clone()),
+            last_result: controller.last_result.clone(),
+            enabled: controller.enabled.clone(),
+        };
+        let (events, events_rx) = mpsc::channel();
+        let listener = Mutex::new(events.clone());
+        controller.add_listener(move || {
+            listener.lock().unwrap().send(Event::Updated).ok();
+        });
+        let join_handle = std::thread::spawn(move || {
+            let builder = match ConnectionBuilder::session() {
+                Ok(builder) => builder,
+                Err(e) => {
+                    log::error!("Unable to connect to the session bus: {e}");
+                    return;
+                }
+            };
+            run(builder, interface, events_rx)
+        });
+        Self {
+            events,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for DbusService {
+    fn drop(&mut self) {
+        log::info!("Stopping D-Bus service");
+        self.events.send(Event::Stop).ok();
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+/// Whether the paths refer to the same file, the paths are only compared if either doesn't exist
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Ask the running instance to reload the config file, returns false if there is no instance
+/// serving the D-Bus object. Fails if the instance was started with a different config file.
+pub fn reload_running_instance(connection: &Connection, config_path: &Path) -> fdo::Result<bool> {
+    let config_path = config_path.to_string_lossy();
+    let args = (config_path.as_ref(),);
+    match connection.call_method(Some(BUS_NAME), OBJECT_PATH, Some(BUS_NAME), "Reload", &args) {
+        Ok(_) => Ok(true),
+        Err(e) => match fdo::Error::from(e) {
+            fdo::Error::ServiceUnknown(_) => Ok(false),
+            e => Err(e),
+        },
+    }
+}
+
+fn serve(builder: ConnectionBuilder, interface: SsbInterface) -> zbus::Result<Connection> {
+    builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, interface)?
+        .build()
+}
+
+fn run(builder: ConnectionBuilder, interface: SsbInterface, events: mpsc::Receiver<Event>) {
+    let connection = match serve(builder, interface) {
+        Ok(connection) => {
+            log::info!("Serving {BUS_NAME} on the session bus");
+            Some(connection)
+        }
+        Err(e) => {
+            log::error!("Unable to start the D-Bus service: {e}");
+            None
+        }
+    };
+    while let Ok(Event::Updated) = events.recv() {
+        if let Some(connection) = &connection {
+            if let Err(e) = emit_properties_changed(connection) {
+                log::warn!("Unable to emit D-Bus signals: {e}");
+            }
+        }
+    }
+    log::debug!("D-Bus service thread exiting");
+}
+
+fn emit_properties_changed(connection: &Connection) -> zbus::Result<()> {
+    let interface = connection
+        .object_server()
+        .interface::<_, SsbInterface>(OBJECT_PATH)?;
+    let context = interface.signal_context();
+    futures::executor::block_on(async {
+        let interface = interface.get();
+        interface.enabled_changed(context).await?;
+        interface.brightness_changed(context).await?;
+        interface.next_update_changed(context).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::PrivateBus;
+    use zbus::dbus_proxy;
+
+    #[dbus_proxy(
+        interface = "org.solar_screen_brightness",
+        default_service = "org.solar_screen_brightness",
+        default_path = "/org/solar_screen_brightness"
+    )]
+    trait Ssb {
+        fn refresh(&self) -> zbus::Result<()>;
+        fn pause(&self) -> zbus::Result<()>;
+        fn resume(&self) -> zbus::Result<()>;
+        fn set_location(&self, latitude: f64, longitude: f64) -> zbus::Result<()>;
+        fn get_status(&self) -> zbus::Result<String>;
+        #[dbus_proxy(property)]
+        fn enabled(&self) -> zbus::Result<bool>;
+        #[dbus_proxy(property)]
+        fn brightness(&self) -> zbus::Result<HashMap<String, u32>>;
+        #[dbus_proxy(property)]
+        fn next_update(&self) -> zbus::Result<i64>;
+    }
+
+    fn results() -> ApplyResults {
+        let mut results = ApplyResults::for_test(&[("eDP-1", 80)]);
+        results.monitors[0].brightness.as_mut().unwrap().expiry_time = Some(1690000000);
+        results.phase.expiry_time = 1690003600;
+        results
+    }
+
+    #[test]
+    fn test_dbus_service() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping test because dbus-daemon is not available");
+            return;
+        };
+        let config_file = tempfile::NamedTempFile::new().unwrap();
+        let config = Arc::new(RwLock::new(SsbConfig::default()));
+        let last_result = Arc::new(RwLock::new(None));
+        let enabled = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = mpsc::channel();
+        let interface = SsbInterface {
+            config: config.clone(),
+            config_path: config_file.path().to_path_buf(),
+            controller: Mutex::new(tx),
+            last_result: last_result.clone(),
+            enabled: enabled.clone(),
+        };
+        let (events, events_rx) = mpsc::channel();
+        let builder = ConnectionBuilder::address(bus.address.as_str()).unwrap();
+        let service = std::thread::spawn(move || run(builder, interface, events_rx));
+
+        let client = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap();
+        // Wait for the service to own the name
+        let dbus = zbus::blocking::fdo::DBusProxy::new(&client).unwrap();
+        while !dbus.name_has_owner(BUS_NAME.try_into().unwrap()).unwrap() {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let proxy = SsbProxyBlocking::new(&client).unwrap();
+
+        proxy.refresh().unwrap();
+        assert_eq!(rx.recv().unwrap(), Message::Refresh("D-Bus Refresh"));
+        proxy.pause().unwrap();
+        assert_eq!(rx.recv().unwrap(), Message::Disable("D-Bus Pause"));
+        proxy.resume().unwrap();
+        assert_eq!(rx.recv().unwrap(), Message::Enable("D-Bus Resume"));
+
+        assert!(proxy.set_location(91.0, 0.0).is_err());
+        proxy.set_location(48.86, 2.35).unwrap();
+        assert_eq!(rx.recv().unwrap(), Message::Refresh("D-Bus SetLocation"));
+        let saved = SsbConfig::load(Some(config_file.path().to_path_buf()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.location.unwrap().latitude, 48.86);
+        assert_eq!(config.read().unwrap().location, saved.location);
+
+        // Changes made to the config file by another process
+        let mut edited = saved.clone();
+        edited.reassert_mins = Some(15);
+        edited.save_to(config_file.path()).unwrap();
+        assert!(reload_running_instance(&client, config_file.path()).unwrap());
+        assert_eq!(rx.recv().unwrap(), Message::Refresh("D-Bus Reload"));
+        assert_eq!(config.read().unwrap().reassert_mins, Some(15));
+        // The instance was started with a different config file
+        let other_file = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(
+            reload_running_instance(&client, other_file.path()),
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+
+        assert!(proxy.enabled().unwrap());
+        assert!(proxy.brightness().unwrap().is_empty());
+        assert_eq!(proxy.next_update().unwrap(), 0);
+
+        // Cached properties are updated by the PropertiesChanged signals, NextUpdate is sent last
+        let mut changes = proxy.receive_next_update_changed();
+        *last_result.write().unwrap() = Some(results());
+        enabled.store(false, Ordering::SeqCst);
+        events.send(Event::Updated).unwrap();
+        let change = changes.next().unwrap();
+        assert_eq!(change.get().unwrap(), 1690000000);
+        assert!(!proxy.enabled().unwrap());
+        assert_eq!(
+            proxy.brightness().unwrap(),
+            HashMap::from([("eDP-1".to_owned(), 80)])
+        );
+
+        let status: serde_json::Value = serde_json::from_str(&proxy.get_status().unwrap()).unwrap();
+        assert_eq!(status["enabled"], false);
+        assert_eq!(
+            status["results"]["monitors"][0]["brightness"]["brightness"],
+            80
+        );
+
+        events.send(Event::Stop).unwrap();
+        service.join().unwrap();
+        while dbus.name_has_owner(BUS_NAME.try_into().unwrap()).unwrap() {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(!reload_running_instance(&client, config_file.path()).unwrap());
+    }
+}
@@ -1,18 +1,20 @@
+mod logind;
+
 use crate::common::local_data_directory;
 use crate::controller::{BrightnessController, Message};
 use crate::gui::UserEvent;
 use egui_winit::winit::event_loop::{EventLoop, EventLoopProxy};
+use futures::channel::oneshot;
 use nix::fcntl::{open, OFlag};
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::stat::Mode;
 use nix::unistd::{close, pipe, read, write};
-use std::ffi::CString;
 use std::os::unix::prelude::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::thread::JoinHandle;
-use udev::ffi::{udev_device_get_action, udev_monitor_receive_device};
-use udev::{AsRaw, MonitorBuilder};
+use std::time::{Duration, Instant};
+use udev::{EventType, MonitorBuilder};
 
 pub const MSG_OPEN_WINDOW: u8 = 1;
 const MSG_STOP_WATCHING: u8 = 2;
@@ -22,23 +24,81 @@ pub fn get_ipc_path() -> PathBuf {
 }
 
 pub struct EventWatcher {
-    ddcci_write_end: RawFd,
-    ddcci_watcher_thread: Option<JoinHandle<()>>,
+    devices_write_end: RawFd,
+    devices_watcher_thread: Option<JoinHandle<()>>,
     ipc_path: PathBuf,
     ipc_watcher_thread: Option<JoinHandle<()>>,
+    logind_stop: Option<oneshot::Sender<()>>,
+    logind_watcher_thread: Option<JoinHandle<()>>,
+}
+
+/// How long to wait for a burst of device events to finish before refreshing
+const DEVICE_EVENT_DEBOUNCE: Duration = Duration::from_millis(1500);
+
+/// Collects a burst of udev events (e.g. a dock reconnecting several monitors) into one message
+#[derive(Default)]
+struct DeviceEvents {
+    deadline: Option<Instant>,
+    added: bool,
+    removed: bool,
+}
+
+impl DeviceEvents {
+    fn record(&mut self, event_type: EventType, subsystem: Option<&str>, now: Instant) {
+        match (event_type, subsystem) {
+            (EventType::Add | EventType::Bind, _) => self.added = true,
+            // Connector hotplug is reported as a change to the DRM card
+            (EventType::Change, Some("drm")) => self.added = true,
+            // Backlight changes are caused by our own writes (directly, through logind or while
+            // fading), so refreshing on them would never stop
+            (EventType::Change, _) => return,
+            (EventType::Remove | EventType::Unbind, _) => self.removed = true,
+            (EventType::Unknown, _) => return,
+        }
+        self.deadline = Some(now + DEVICE_EVENT_DEBOUNCE);
+    }
+
+    /// Milliseconds until the pending events should be sent, or -1 to wait indefinitely
+    fn poll_timeout(&self, now: Instant) -> i32 {
+        match self.deadline {
+            None => -1,
+            Some(deadline) => deadline.saturating_duration_since(now).as_millis() as i32,
+        }
+    }
+
+    /// The message to send once the burst of events has finished
+    fn take(&mut self, now: Instant) -> Option<Message> {
+        if self.deadline? > now {
+            return None;
+        }
+        let events = std::mem::take(self);
+        if events.added {
+            // Devices that were (re)connected may have been reset to their default brightness
+            Some(Message::ForceRefresh("udev add/hotplug"))
+        } else {
+            // Removed devices will no longer be enumerated when refreshing
+            Some(Message::Refresh("udev remove"))
+        }
+    }
 }
 
-/// Detect when DDC/CI monitors are added, tell the BrightnessController to refresh
-fn watch_ddcci(read: RawFd, controller: mpsc::Sender<Message>) {
+/// Detect when monitors are connected, disconnected or changed, tell the BrightnessController
+/// to refresh
+fn watch_devices(read: RawFd, controller: mpsc::Sender<Message>) {
     if let Err(e) = (|| -> anyhow::Result<()> {
-        let socket = MonitorBuilder::new()?.match_subsystem("ddcci")?.listen()?;
-        log::info!("Monitoring for DDC/CI connections");
+        let mut socket = MonitorBuilder::new()?
+            .match_subsystem("ddcci")?
+            .match_subsystem("drm")?
+            .match_subsystem("backlight")?
+            .listen()?;
+        log::info!("Monitoring for DDC/CI, DRM and backlight device events");
+        let mut pending = DeviceEvents::default();
         loop {
             let socket_fd = PollFd::new(socket.as_raw_fd(), PollFlags::POLLIN);
             let stop = PollFd::new(read, PollFlags::POLLIN);
             let mut pfds = vec![socket_fd, stop];
             log::trace!("Beginning udev monitor connections poll...");
-            poll(pfds.as_mut_slice(), -1)?;
+            poll(pfds.as_mut_slice(), pending.poll_timeout(Instant::now()))?;
             if let Some(e) = pfds[1].revents() {
                 if e.contains(PollFlags::POLLIN) {
                     close(read).ok();
@@ -47,23 +107,27 @@ fn watch_ddcci(read: RawFd, controller: mpsc::Sender<Message>) {
             }
             if let Some(e) = pfds[0].revents() {
                 if e.contains(PollFlags::POLLIN) {
-                    let action = unsafe {
-                        let dev = udev_monitor_receive_device(socket.as_raw());
-                        let raw = udev_device_get_action(dev);
-                        let cs = CString::from_raw(raw as *mut _);
-                        cs.to_str()?.to_owned()
-                    };
-                    if action == "add" {
-                        log::info!("Notified of ddcci add event, triggering refresh");
-                        controller.send(Message::Refresh("udev add")).unwrap();
+                    for event in socket.by_ref() {
+                        log::debug!(
+                            "Received udev {} event for {:?} ({:?})",
+                            event.event_type(),
+                            event.sysname(),
+                            event.subsystem()
+                        );
+                        let subsystem = event.subsystem().and_then(|s| s.to_str());
+                        pending.record(event.event_type(), subsystem, Instant::now());
                     }
                 }
             }
+            if let Some(message) = pending.take(Instant::now()) {
+                log::info!("Notified of device events, triggering refresh");
+                controller.send(message).unwrap();
+            }
         }
-        log::debug!("DDC/CI watcher thread exiting");
+        log::debug!("Device watcher thread exiting");
         Ok(())
     })() {
-        log::error!("Error occurred monitoring for DDC/CI connections: {:#}", e);
+        log::error!("Error occurred monitoring for device events: {:#}", e);
     }
 }
 
@@ -104,33 +168,84 @@ impl EventWatcher {
     ) -> anyhow::Result<Self> {
         let sender = controller.sender.clone();
         let (read, write) = pipe().unwrap();
-        let ddcci_watcher_thread = std::thread::spawn(move || watch_ddcci(read, sender));
+        let devices_watcher_thread = std::thread::spawn(move || watch_devices(read, sender));
 
         let ipc_path = get_ipc_path();
         let proxy = event_loop.map(|e| e.create_proxy());
         let ipc_path2 = ipc_path.clone();
         let ipc_watcher_thread = std::thread::spawn(move || watch_ipc_pipe(ipc_path2, proxy));
 
+        let sender = controller.sender.clone();
+        let (logind_stop, logind_stop_rx) = oneshot::channel();
+        let logind_watcher_thread =
+            std::thread::spawn(move || logind::watch_logind(logind_stop_rx, sender));
+
         Ok(Self {
-            ddcci_write_end: write,
-            ddcci_watcher_thread: Some(ddcci_watcher_thread),
+            devices_write_end: write,
+            devices_watcher_thread: Some(devices_watcher_thread),
             ipc_path,
             ipc_watcher_thread: Some(ipc_watcher_thread),
+            logind_stop: Some(logind_stop),
+            logind_watcher_thread: Some(logind_watcher_thread),
         })
     }
 }
 
 impl Drop for EventWatcher {
     fn drop(&mut self) {
-        log::info!("Stopping DDC/CI watcher");
-        write(self.ddcci_write_end, &[0]).unwrap();
-        close(self.ddcci_write_end).unwrap();
-        self.ddcci_watcher_thread.take().unwrap().join().unwrap();
+        log::info!("Stopping device watcher");
+        write(self.devices_write_end, &[0]).unwrap();
+        close(self.devices_write_end).unwrap();
+        self.devices_watcher_thread.take().unwrap().join().unwrap();
 
         log::info!("Stopping IPC watcher");
         let ipc_fd = open(&self.ipc_path, OFlag::O_WRONLY, Mode::empty()).unwrap();
         write(ipc_fd, vec![MSG_STOP_WATCHING].as_slice()).ok();
         close(ipc_fd).unwrap();
         self.ipc_watcher_thread.take().unwrap().join().unwrap();
+
+        log::info!("Stopping logind watcher");
+        // The thread may have already exited if logind was unavailable
+        self.logind_stop.take().unwrap().send(()).ok();
+        self.logind_watcher_thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_events_debounce() {
+        let start = Instant::now();
+        let mut events = DeviceEvents::default();
+        assert_eq!(events.poll_timeout(start), -1);
+        assert_eq!(events.take(start), None);
+
+        // A burst of events is only sent once it has gone quiet
+        events.record(EventType::Change, Some("drm"), start);
+        let later = start + Duration::from_millis(1000);
+        events.record(EventType::Add, Some("ddcci"), later);
+        assert_eq!(events.take(later), None);
+        assert_eq!(events.poll_timeout(later), 1500);
+        let quiet = later + DEVICE_EVENT_DEBOUNCE;
+        assert_eq!(
+            events.take(quiet),
+            Some(Message::ForceRefresh("udev add/hotplug"))
+        );
+        assert_eq!(events.take(quiet), None);
+        assert_eq!(events.poll_timeout(quiet), -1);
+
+        // Removals alone don't need to force writing to the remaining devices
+        events.record(EventType::Remove, Some("drm"), start);
+        assert_eq!(
+            events.take(start + DEVICE_EVENT_DEBOUNCE),
+            Some(Message::Refresh("udev remove"))
+        );
+
+        // Our own backlight writes and unknown events are ignored
+        events.record(EventType::Change, Some("backlight"), start);
+        events.record(EventType::Unknown, Some("drm"), start);
+        assert_eq!(events.poll_timeout(start), -1);
     }
 }
@@ -0,0 +1,278 @@
+//! Watches systemd-logind for suspend/resume and session lock/unlock events
+
+use crate::controller::Message;
+use futures::channel::oneshot;
+use futures::stream::{select_all, BoxStream};
+use futures::{FutureExt, StreamExt};
+use std::sync::mpsc;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{dbus_proxy, Connection};
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    fn get_session_by_pid(&self, pid: u32) -> zbus::Result<OwnedObjectPath>;
+
+    #[dbus_proxy(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1/user/self"
+)]
+trait User {
+    #[dbus_proxy(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+trait Session {
+    #[dbus_proxy(signal)]
+    fn lock(&self) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn unlock(&self) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum LogindEvent {
+    PrepareForSleep(bool),
+    Lock,
+    Unlock,
+}
+
+/// Whether the session is asleep or locked, the controller is only uninhibited once it is neither
+#[derive(Default)]
+struct SessionState {
+    sleeping: bool,
+    locked: bool,
+}
+
+impl SessionState {
+    /// The message to send to the controller, if the event changes whether it is inhibited
+    fn update(&mut self, event: LogindEvent) -> Option<Message> {
+        let was_inhibited = self.sleeping || self.locked;
+        match event {
+            LogindEvent::PrepareForSleep(start) => self.sleeping = start,
+            LogindEvent::Lock => self.locked = true,
+            LogindEvent::Unlock => self.locked = false,
+        }
+        match (was_inhibited, self.sleeping || self.locked, event) {
+            (false, true, LogindEvent::Lock) => Some(Message::Inhibit("logind Lock")),
+            (false, true, _) => Some(Message::Inhibit("logind PrepareForSleep")),
+            // Uninhibiting also forces the brightness to be re-applied to every monitor
+            (true, false, LogindEvent::Unlock) => Some(Message::Uninhibit("logind Unlock")),
+            (true, false, _) => Some(Message::Uninhibit("logind resume")),
+            _ => None,
+        }
+    }
+}
+
+/// Runs on its own thread, until the stop channel receives a value
+pub(super) fn watch_logind(stop: oneshot::Receiver<()>, controller: mpsc::Sender<Message>) {
+    let result = futures::executor::block_on(async {
+        let connection = Connection::system().await?;
+        watch(&connection, controller, stop).await
+    });
+    if let Err(e) = result {
+        log::error!("Error occurred watching logind: {:#}", e);
+    }
+    log::debug!("logind watcher thread exiting");
+}
+
+/// Forwards logind events on the connection to the BrightnessController
+async fn watch(
+    connection: &Connection,
+    controller: mpsc::Sender<Message>,
+    stop: oneshot::Receiver<()>,
+) -> zbus::Result<()> {
+    let manager = ManagerProxy::new(connection).await?;
+    let mut streams: Vec<BoxStream<LogindEvent>> = vec![manager
+        .receive_prepare_for_sleep()
+        .await?
+        .filter_map(|s| async move { s.args().ok().map(|a| LogindEvent::PrepareForSleep(a.start)) })
+        .boxed()];
+
+    match find_session(connection, &manager).await {
+        Ok(path) => {
+            log::info!("Monitoring logind session {}", path.as_str());
+            let session = SessionProxy::builder(connection)
+                .path(path)?
+                .build()
+                .await?;
+            streams.push(
+                session
+                    .receive_lock()
+                    .await?
+                    .map(|_| LogindEvent::Lock)
+                    .boxed(),
+            );
+            streams.push(
+                session
+                    .receive_unlock()
+                    .await?
+                    .map(|_| LogindEvent::Unlock)
+                    .boxed(),
+            );
+        }
+        Err(e) => {
+            log::warn!("Unable to find logind session, lock events will be ignored: {e}");
+        }
+    }
+
+    log::info!("Monitoring logind for sleep and lock events");
+    let mut events = select_all(streams);
+    let mut stop = stop.fuse();
+    let mut state = SessionState::default();
+    loop {
+        futures::select! {
+            event = events.next().fuse() => match event {
+                Some(event) => {
+                    log::info!("Received logind event {:?}", event);
+                    if let Some(message) = state.update(event) {
+                        controller.send(message).unwrap();
+                    }
+                }
+                None => break,
+            },
+            _ = stop => break,
+        }
+    }
+    Ok(())
+}
+
+/// The session this process belongs to, or otherwise the user's graphical session
+async fn find_session(
+    connection: &Connection,
+    manager: &ManagerProxy<'_>,
+) -> zbus::Result<OwnedObjectPath> {
+    match manager.get_session_by_pid(std::process::id()).await {
+        Ok(path) => Ok(path),
+        Err(e) => {
+            log::debug!("Process does not belong to a session ({e}), using the display session");
+            let user = UserProxy::new(connection).await?;
+            Ok(user.display().await?.1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::PrivateBus;
+    use std::time::Duration;
+    use zbus::{dbus_interface, ConnectionBuilder, SignalContext};
+
+    const SESSION_PATH: &str = "/org/freedesktop/login1/session/test";
+
+    struct FakeManager;
+
+    #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeManager {
+        fn get_session_by_pid(&self, _pid: u32) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(SESSION_PATH).unwrap()
+        }
+
+        #[dbus_interface(signal)]
+        async fn prepare_for_sleep(ctx: &SignalContext<'_>, start: bool) -> zbus::Result<()>;
+    }
+
+    struct FakeSession;
+
+    #[dbus_interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        #[dbus_interface(signal)]
+        async fn lock(ctx: &SignalContext<'_>) -> zbus::Result<()>;
+
+        #[dbus_interface(signal)]
+        async fn unlock(ctx: &SignalContext<'_>) -> zbus::Result<()>;
+    }
+
+    #[test]
+    fn test_session_state() {
+        let mut state = SessionState::default();
+        assert_eq!(
+            state.update(LogindEvent::Lock),
+            Some(Message::Inhibit("logind Lock"))
+        );
+        // Waking up while still locked doesn't resume
+        assert_eq!(state.update(LogindEvent::PrepareForSleep(true)), None);
+        assert_eq!(state.update(LogindEvent::PrepareForSleep(false)), None);
+        assert_eq!(
+            state.update(LogindEvent::Unlock),
+            Some(Message::Uninhibit("logind Unlock"))
+        );
+        assert_eq!(state.update(LogindEvent::Unlock), None);
+    }
+
+    #[test]
+    fn test_watch_logind() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping test because dbus-daemon is not available");
+            return;
+        };
+        let (server, client) = futures::executor::block_on(async {
+            let server = ConnectionBuilder::address(bus.address.as_str())?
+                .name("org.freedesktop.login1")?
+                .serve_at("/org/freedesktop/login1", FakeManager)?
+                .serve_at(SESSION_PATH, FakeSession)?
+                .build()
+                .await?;
+            let client = ConnectionBuilder::address(bus.address.as_str())?
+                .build()
+                .await?;
+            zbus::Result::Ok((server, client))
+        })
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let watcher = std::thread::spawn(move || {
+            futures::executor::block_on(watch(&client, tx, stop_rx)).unwrap();
+        });
+
+        let manager = SignalContext::new(&server, "/org/freedesktop/login1").unwrap();
+        let session = SignalContext::new(&server, SESSION_PATH).unwrap();
+        let timeout = Duration::from_millis(200);
+
+        // The watcher may not have subscribed yet, so keep emitting until it is received
+        let message = (0..25)
+            .find_map(|_| {
+                futures::executor::block_on(FakeManager::prepare_for_sleep(&manager, true))
+                    .unwrap();
+                rx.recv_timeout(timeout).ok()
+            })
+            .expect("Watcher did not receive PrepareForSleep");
+        assert_eq!(message, Message::Inhibit("logind PrepareForSleep"));
+        while rx.recv_timeout(timeout).is_ok() {}
+
+        futures::executor::block_on(FakeManager::prepare_for_sleep(&manager, false)).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::Uninhibit("logind resume")
+        );
+
+        futures::executor::block_on(FakeSession::lock(&session)).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::Inhibit("logind Lock")
+        );
+
+        futures::executor::block_on(FakeSession::unlock(&session)).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Message::Uninhibit("logind Unlock")
+        );
+
+        stop_tx.send(()).unwrap();
+        watcher.join().unwrap();
+    }
+}
@@ -122,7 +122,7 @@ unsafe extern "system" fn wndproc(
                 log::info!("Detected possible display change (WM_DISPLAYCHANGE)");
                 window_data
                     .sender
-                    .send(Message::Refresh("WM_DISPLAYCHANGE"))
+                    .send(Message::ForceRefresh("WM_DISPLAYCHANGE"))
                     .unwrap();
             }
             EXIT_LOOP => {
@@ -134,14 +134,14 @@ unsafe extern "system" fn wndproc(
                     log::info!("Detected WTS_SESSION_LOCK");
                     window_data
                         .sender
-                        .send(Message::Disable("WTS_SESSION_LOCK"))
+                        .send(Message::Inhibit("WTS_SESSION_LOCK"))
                         .unwrap();
                 }
                 WTS_SESSION_UNLOCK => {
                     log::info!("Detected WTS_SESSION_UNLOCK");
                     window_data
                         .sender
-                        .send(Message::Enable("WTS_SESSION_UNLOCK"))
+                        .send(Message::Uninhibit("WTS_SESSION_UNLOCK"))
                         .unwrap();
                 }
                 _ => {}
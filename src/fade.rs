@@ -0,0 +1,392 @@
+//! Gradually fades monitors towards their target brightness
+
+use crate::apply::set_with_retry;
+use crate::config::FadeSettings;
+use brightness::blocking::{Brightness, BrightnessDevice};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a fade may overrun its duration (e.g. due to slow or retried writes) before it is
+/// considered to have failed
+const FADE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for workers to finish their final write when stopping them
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct FadeTarget {
+    device: BrightnessDevice,
+    target: u32,
+    step_interval: Duration,
+    duration: Duration,
+}
+
+/// Sent by a worker once it has finished fading to a target, or failed to
+#[derive(Debug, PartialEq)]
+pub struct FadeResult {
+    pub device_name: String,
+    pub target: u32,
+    pub result: Result<(), String>,
+    /// How long the fade took, including any retries
+    pub duration_ms: u64,
+}
+
+struct InProgress {
+    target: u32,
+    started: Instant,
+    /// When the fade should have finished by
+    deadline: Instant,
+}
+
+struct FadeWorker {
+    sender: mpsc::Sender<FadeTarget>,
+    join_handle: JoinHandle<()>,
+}
+
+/// Owns a worker thread per monitor, each of which steps its monitor towards the latest target
+pub struct Fader {
+    workers: HashMap<String, FadeWorker>,
+    /// The fades that haven't finished yet
+    in_progress: HashMap<String, InProgress>,
+    results_sender: mpsc::Sender<FadeResult>,
+    results: mpsc::Receiver<FadeResult>,
+}
+
+impl Default for Fader {
+    fn default() -> Self {
+        let (results_sender, results) = mpsc::channel();
+        Fader {
+            workers: HashMap::new(),
+            in_progress: HashMap::new(),
+            results_sender,
+            results,
+        }
+    }
+}
+
+impl Fader {
+    /// Start fading a monitor to the target brightness, cancelling any fade already in progress.
+    /// This doesn't block, the device is only accessed from the worker thread.
+    pub fn fade(
+        &mut self,
+        device_name: &str,
+        device: BrightnessDevice,
+        target: u32,
+        settings: &FadeSettings,
+        ddcci: bool,
+    ) {
+        let step_interval = if ddcci {
+            settings.ddcci_step_interval_ms
+        } else {
+            settings.step_interval_ms
+        };
+        let duration = Duration::from_millis(settings.duration_ms as u64);
+        let fade_target = FadeTarget {
+            device,
+            target,
+            step_interval: Duration::from_millis(step_interval as u64),
+            duration,
+        };
+        let started = Instant::now();
+        self.in_progress.insert(
+            device_name.to_owned(),
+            InProgress {
+                target,
+                started,
+                deadline: started + duration + FADE_TIMEOUT,
+            },
+        );
+        let results = &self.results_sender;
+        let worker = self
+            .workers
+            .entry(device_name.to_owned())
+            .or_insert_with(|| FadeWorker::start(device_name.to_owned(), results.clone()));
+        if let Err(mpsc::SendError(fade_target)) = worker.sender.send(fade_target) {
+            // The worker has exited unexpectedly, replace it
+            let worker = FadeWorker::start(device_name.to_owned(), results.clone());
+            worker.sender.send(fade_target).unwrap();
+            if let Some(old) = self.workers.insert(device_name.to_owned(), worker) {
+                stop_workers(vec![(device_name.to_owned(), old)]);
+            }
+        }
+    }
+
+    /// The target of the fade in progress for a device, unless it has timed out
+    pub fn target(&self, device_name: &str) -> Option<u32> {
+        self.in_progress
+            .get(device_name)
+            .filter(|fade| fade.deadline > Instant::now())
+            .map(|fade| fade.target)
+    }
+
+    /// The fades that have finished or failed since this was last called, including those that
+    /// have timed out
+    pub fn finished(&mut self) -> Vec<FadeResult> {
+        let mut finished = self.results.try_iter().collect::<Vec<_>>();
+        for result in &finished {
+            if self.target(&result.device_name) == Some(result.target) {
+                self.in_progress.remove(&result.device_name);
+            }
+        }
+        let now = Instant::now();
+        let timed_out = self
+            .in_progress
+            .iter()
+            .filter(|(_, fade)| fade.deadline <= now)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for device_name in timed_out {
+            let fade = self.in_progress.remove(&device_name).unwrap();
+            log::warn!("Timed out fading '{device_name}' to {}%", fade.target);
+            finished.push(FadeResult {
+                device_name,
+                target: fade.target,
+                result: Err("Timed out fading brightness".to_owned()),
+                duration_ms: now.duration_since(fade.started).as_millis() as u64,
+            });
+        }
+        finished
+    }
+
+    /// Stop the workers for any devices that are no longer present
+    pub fn retain(&mut self, device_names: &HashSet<String>) {
+        self.in_progress
+            .retain(|name, _| device_names.contains(name));
+        let removed = self
+            .workers
+            .keys()
+            .filter(|name| !device_names.contains(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        let workers = removed
+            .into_iter()
+            .map(|name| {
+                log::debug!("Stopping fade worker for removed device '{name}'");
+                let worker = self.workers.remove(&name).unwrap();
+                (name, worker)
+            })
+            .collect::<Vec<_>>();
+        stop_workers(workers);
+    }
+
+    /// Stop all fades that are in progress
+    pub fn clear(&mut self) {
+        self.in_progress.clear();
+        stop_workers(self.workers.drain().collect());
+    }
+}
+
+/// Signal every worker to stop, then wait a limited time for them to exit. This runs on the
+/// controller thread, so a worker stuck writing to a hung device is detached rather than joined.
+fn stop_workers(workers: Vec<(String, FadeWorker)>) {
+    if workers.is_empty() {
+        return;
+    }
+    let handles = workers
+        .into_iter()
+        .map(|(name, worker)| {
+            drop(worker.sender);
+            (name, worker.join_handle)
+        })
+        .collect::<Vec<_>>();
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    for (name, handle) in handles {
+        while !handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if handle.is_finished() {
+            handle.join().ok();
+        } else {
+            log::warn!("Fade worker for '{name}' is still busy, not waiting for it to stop");
+        }
+    }
+}
+
+impl Drop for Fader {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl FadeWorker {
+    fn start(device_name: String, results: mpsc::Sender<FadeResult>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let join_handle = std::thread::spawn(move || run(device_name, receiver, results));
+        FadeWorker {
+            sender,
+            join_handle,
+        }
+    }
+}
+
+fn run(
+    device_name: String,
+    receiver: mpsc::Receiver<FadeTarget>,
+    results: mpsc::Sender<FadeResult>,
+) {
+    log::debug!("Starting fade worker for '{device_name}'");
+    let send_result = |target, result, started: Instant| {
+        // The Fader may have been dropped while this was fading
+        results
+            .send(FadeResult {
+                device_name: device_name.clone(),
+                target,
+                result,
+                duration_ms: started.elapsed().as_millis() as u64,
+            })
+            .ok();
+    };
+    let mut next = receiver.recv().ok();
+    while let Some(fade) = next.take() {
+        let started = Instant::now();
+        let start = match fade.device.get() {
+            Ok(start) => start,
+            Err(e) => {
+                log::error!("Failed to get brightness for '{device_name}', unable to fade: {e}");
+                let result = set_with_retry(&fade.device, &device_name, fade.target).result;
+                if let Err(e) = &result {
+                    log::error!("Failed to set brightness for '{device_name}': {e}");
+                }
+                send_result(fade.target, result, started);
+                next = receiver.recv().ok();
+                continue;
+            }
+        };
+        let steps = fade_steps(start, fade.target, fade.duration, fade.step_interval);
+        let delay = (fade.duration / steps.len() as u32).max(fade.step_interval);
+        log::debug!(
+            "Fading '{}' from {}% to {}% in {} steps",
+            device_name,
+            start,
+            fade.target,
+            steps.len()
+        );
+        for (idx, value) in steps.iter().enumerate() {
+            if let Err(e) = set_with_retry(&fade.device, &device_name, *value).result {
+                log::error!("Failed to set brightness for '{device_name}' during fade: {e}");
+                send_result(fade.target, Err(e), started);
+                break;
+            }
+            if idx == steps.len() - 1 {
+                log::info!("Finished fading '{device_name}' to {value}%");
+                send_result(fade.target, Ok(()), started);
+                break;
+            }
+            match receiver.recv_timeout(delay) {
+                Ok(new) => {
+                    log::debug!("Cancelling fade for '{device_name}' due to new target");
+                    next = Some(new);
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Shutting down, jump straight to the target rather than leaving it part way
+                    fade.device.set(fade.target).ok();
+                    return;
+                }
+            }
+        }
+        if next.is_none() {
+            next = receiver.recv().ok();
+        }
+    }
+    log::debug!("Fade worker for '{device_name}' exiting");
+}
+
+/// The intermediate brightness values to set, ending with the target
+fn fade_steps(start: u32, target: u32, duration: Duration, step_interval: Duration) -> Vec<u32> {
+    let distance = start.abs_diff(target);
+    if distance == 0 {
+        return vec![target];
+    }
+    // Limit the number of steps so that they are never closer together than the step interval
+    let max_steps = (duration.as_millis() / step_interval.as_millis().max(1)).max(1) as u32;
+    let steps = distance.min(max_steps);
+    (1..=steps)
+        .map(|i| {
+            let progress = (distance * i) as f64 / steps as f64;
+            if target > start {
+                start + progress.round() as u32
+            } else {
+                start - progress.round() as u32
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade_steps() {
+        let interval = Duration::from_millis(20);
+
+        // Every percentage is stepped through when there is enough time
+        let steps = fade_steps(50, 60, Duration::from_millis(1000), interval);
+        assert_eq!(steps, (51..=60).collect::<Vec<_>>());
+
+        // Decreasing brightness
+        let steps = fade_steps(60, 50, Duration::from_millis(1000), interval);
+        assert_eq!(steps, (50..=59).rev().collect::<Vec<_>>());
+
+        // Slow devices take bigger steps
+        let steps = fade_steps(
+            0,
+            100,
+            Duration::from_millis(1000),
+            Duration::from_millis(200),
+        );
+        assert_eq!(steps, vec![20, 40, 60, 80, 100]);
+
+        // Already at the target
+        assert_eq!(
+            fade_steps(40, 40, Duration::from_millis(1000), interval),
+            vec![40]
+        );
+    }
+
+    #[test]
+    fn test_finished_fades() {
+        let mut fader = Fader::default();
+        let fade = |target, deadline| InProgress {
+            target,
+            started: Instant::now(),
+            deadline,
+        };
+        let later = Instant::now() + Duration::from_secs(60);
+        fader.in_progress.insert("a".to_owned(), fade(50, later));
+        fader.in_progress.insert("b".to_owned(), fade(60, later));
+        fader
+            .in_progress
+            .insert("c".to_owned(), fade(70, Instant::now()));
+        assert_eq!(fader.target("a"), Some(50));
+        assert_eq!(fader.target("c"), None);
+
+        let succeeded = FadeResult {
+            device_name: "a".to_owned(),
+            target: 50,
+            result: Ok(()),
+            duration_ms: 1000,
+        };
+        let failed = FadeResult {
+            device_name: "b".to_owned(),
+            target: 60,
+            result: Err("DDC/CI error".to_owned()),
+            duration_ms: 400,
+        };
+        fader.results_sender.send(succeeded).unwrap();
+        fader.results_sender.send(failed).unwrap();
+        let finished = fader.finished();
+        assert_eq!(finished.len(), 3);
+        assert_eq!(finished[0].result, Ok(()));
+        assert_eq!(finished[1].result, Err("DDC/CI error".to_owned()));
+        // Fades that never report back are treated as failed
+        assert_eq!(finished[2].device_name, "c");
+        assert!(finished[2].result.is_err());
+        assert!(fader.in_progress.is_empty());
+        assert!(fader.finished().is_empty());
+    }
+}
@@ -0,0 +1,352 @@
+//! Software dimming and colour temperature using the display gamma tables, e.g. for outputs that
+//! have no backlight control. The tables are set using X11 RandR, or the wlr-gamma-control
+//! protocol on Wayland.
+
+mod wayland;
+mod x11;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+pub const DRM_PATH: &str = "/sys/class/drm";
+pub const BACKLIGHT_PATH: &str = "/sys/class/backlight";
+
+/// The adjustment to make to an output's gamma table
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Adjustment {
+    /// Percentage to scale every channel by
+    pub brightness: u32,
+    /// Colour temperature of the white point in Kelvin
+    pub temperature: u32,
+}
+
+impl Adjustment {
+    /// Leaves the output unchanged
+    pub const IDENTITY: Adjustment = Adjustment {
+        brightness: 100,
+        temperature: NEUTRAL_TEMPERATURE,
+    };
+}
+
+/// The colour temperature that displays are calibrated to, so it leaves the colours unchanged
+pub const NEUTRAL_TEMPERATURE: u32 = 6500;
+
+/// The approximate colour of a black body at this temperature, as red, green and blue factors
+/// between 0 and 1. This uses Tanner Helland's fit of the blackbody data, normalised so that
+/// 6500K is white.
+pub fn kelvin_to_rgb(kelvin: u32) -> [f64; 3] {
+    let raw = |kelvin: u32| {
+        let temp = kelvin.clamp(1000, 40000) as f64 / 100.0;
+        let red = if temp <= 66.0 {
+            255.0
+        } else {
+            329.698727446 * (temp - 60.0).powf(-0.1332047592)
+        };
+        let green = if temp <= 66.0 {
+            99.4708025861 * temp.ln() - 161.1195681661
+        } else {
+            288.1221695283 * (temp - 60.0).powf(-0.0755148492)
+        };
+        let blue = if temp >= 66.0 {
+            255.0
+        } else if temp <= 19.0 {
+            0.0
+        } else {
+            138.5177312231 * (temp - 10.0).ln() - 305.0447927307
+        };
+        [red, green, blue].map(|c| c.clamp(0.0, 255.0))
+    };
+    let neutral = raw(NEUTRAL_TEMPERATURE);
+    let colour = raw(kelvin);
+    [0, 1, 2].map(|i| (colour[i] / neutral[i]).min(1.0))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GammaRamp {
+    pub red: Vec<u16>,
+    pub green: Vec<u16>,
+    pub blue: Vec<u16>,
+}
+
+/// Calculate a gamma ramp with this many entries per channel, for the adjustment
+pub fn gamma_ramp(size: usize, adjustment: Adjustment) -> GammaRamp {
+    let brightness = adjustment.brightness.min(100) as f64 / 100.0;
+    let white_point = kelvin_to_rgb(adjustment.temperature);
+    let channel = |factor: f64| {
+        (0..size)
+            .map(|i| {
+                let input = if size > 1 {
+                    i as f64 / (size - 1) as f64
+                } else {
+                    1.0
+                };
+                (input * brightness * factor * u16::MAX as f64).round() as u16
+            })
+            .collect::<Vec<_>>()
+    };
+    GammaRamp {
+        red: channel(white_point[0]),
+        green: channel(white_point[1]),
+        blue: channel(white_point[2]),
+    }
+}
+
+/// DRM connector names (used by Wayland compositors) and RandR output names differ between
+/// drivers, e.g. HDMI-A-1, HDMI-1 and HDMI1 are the same output
+pub fn normalise_output_name(name: &str) -> String {
+    name.to_ascii_uppercase()
+        .replace("HDMI-A", "HDMI")
+        .replace('-', "")
+}
+
+/// The normalised names of the DRM connectors that have a backlight device. This includes internal
+/// panels, and monitors controlled by DDC/CI using the ddcci-backlight driver.
+pub fn hardware_outputs(drm_dir: &Path, backlight_dir: &Path) -> HashSet<String> {
+    let mut connectors = HashSet::new();
+    let mut buses = HashSet::new();
+    // A backlight that belongs to the graphics card rather than a connector, e.g. amdgpu_bl0
+    let mut panel_backlight = false;
+    for entry in fs::read_dir(backlight_dir).into_iter().flatten().flatten() {
+        let Ok(device) = fs::canonicalize(entry.path().join("device")) else {
+            continue;
+        };
+        // Internal panel backlights usually belong to the connector, e.g. .../card0/card0-eDP-1,
+        // ddcci backlights belong to the I2C bus of the connector, e.g. .../i2c-5/5-0037
+        let mut found = false;
+        for component in device.iter().map(|c| c.to_string_lossy()) {
+            if component.starts_with("i2c-") {
+                buses.insert(component.into_owned());
+                found = true;
+            } else if let Some(connector) = connector_name(&component) {
+                connectors.insert(normalise_output_name(connector));
+                found = true;
+            }
+        }
+        panel_backlight |= !found;
+    }
+    for entry in fs::read_dir(drm_dir).into_iter().flatten().flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some(connector) = connector_name(&file_name) else {
+            continue;
+        };
+        let bus = fs::canonicalize(entry.path().join("ddc"))
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+        let internal = ["eDP", "LVDS", "DSI"]
+            .iter()
+            .any(|prefix| connector.starts_with(prefix));
+        if matches!(bus, Some(bus) if buses.contains(&bus)) || (panel_backlight && internal) {
+            connectors.insert(normalise_output_name(connector));
+        }
+    }
+    connectors
+}
+
+/// The connector name from a DRM connector directory name, e.g. eDP-1 from card0-eDP-1
+fn connector_name(name: &str) -> Option<&str> {
+    let (card, connector) = name.split_once('-')?;
+    let index = card.strip_prefix("card")?;
+    index
+        .chars()
+        .all(|c| c.is_ascii_digit())
+        .then_some(connector)
+}
+
+trait Backend {
+    /// The names of the connected outputs whose gamma can be set
+    fn outputs(&mut self) -> anyhow::Result<Vec<String>>;
+
+    fn set_gamma(&mut self, output: &str, adjustment: Adjustment) -> anyhow::Result<()>;
+}
+
+fn connect() -> anyhow::Result<Box<dyn Backend>> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Ok(Box::new(wayland::WaylandGamma::connect()?))
+    } else if std::env::var_os("DISPLAY").is_some() {
+        Ok(Box::new(x11::X11Gamma::connect()?))
+    } else {
+        Err(anyhow::anyhow!("No Wayland or X11 display is available"))
+    }
+}
+
+/// Connects to the display server when first used. The gamma tables are reset when dropped.
+#[derive(Default)]
+pub struct GammaController {
+    backend: Option<Box<dyn Backend>>,
+    /// The outputs whose gamma has been changed
+    adjusted: HashSet<String>,
+}
+
+impl GammaController {
+    fn backend(&mut self) -> anyhow::Result<&mut Box<dyn Backend>> {
+        if self.backend.is_none() {
+            self.backend = Some(connect()?);
+        }
+        Ok(self.backend.as_mut().unwrap())
+    }
+
+    pub fn outputs(&mut self) -> anyhow::Result<Vec<String>> {
+        let result = self.backend()?.outputs();
+        if result.is_err() {
+            // Reconnect next time, e.g. if the display server was restarted
+            self.backend = None;
+        }
+        result
+    }
+
+    pub fn set(&mut self, output: &str, adjustment: Adjustment) -> anyhow::Result<()> {
+        self.backend()?.set_gamma(output, adjustment)?;
+        if adjustment == Adjustment::IDENTITY {
+            self.adjusted.remove(output);
+        } else {
+            self.adjusted.insert(output.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Reset every output that has been adjusted, e.g. when software dimming is disabled
+    pub fn reset(&mut self) {
+        for output in std::mem::take(&mut self.adjusted) {
+            if let Err(e) = self.set(&output, Adjustment::IDENTITY) {
+                log::warn!("Unable to reset gamma for '{output}': {:#}", e);
+            }
+        }
+    }
+}
+
+impl Drop for GammaController {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_gamma_ramp() {
+        let ramp = gamma_ramp(3, Adjustment::IDENTITY);
+        assert_eq!(ramp.red, vec![0, 32768, 65535]);
+        assert_eq!(ramp.red, ramp.green);
+        assert_eq!(ramp.red, ramp.blue);
+
+        let dimmed = Adjustment {
+            brightness: 50,
+            ..Adjustment::IDENTITY
+        };
+        let ramp = gamma_ramp(256, dimmed);
+        assert_eq!(ramp.red.len(), 256);
+        assert_eq!(ramp.red[0], 0);
+        assert_eq!(ramp.red[255], 32768);
+        assert!(ramp.red.windows(2).all(|w| w[0] <= w[1]));
+
+        let off = Adjustment {
+            brightness: 0,
+            ..Adjustment::IDENTITY
+        };
+        assert_eq!(gamma_ramp(4, off).red, vec![0; 4]);
+        assert_eq!(gamma_ramp(1, Adjustment::IDENTITY).red, vec![65535]);
+        assert_eq!(gamma_ramp(0, Adjustment::IDENTITY).red, Vec::<u16>::new());
+
+        // Warm colours reduce blue more than green, and leave red at full strength
+        let warm = gamma_ramp(
+            256,
+            Adjustment {
+                brightness: 50,
+                temperature: 3500,
+            },
+        );
+        assert_eq!(warm.red[255], 32768);
+        assert!(warm.green[255] < warm.red[255]);
+        assert!(warm.blue[255] < warm.green[255]);
+        assert!(warm.blue.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_kelvin_to_rgb() {
+        let close = |a: [f64; 3], b: [f64; 3]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 0.01);
+        assert!(close(kelvin_to_rgb(NEUTRAL_TEMPERATURE), [1.0, 1.0, 1.0]));
+        // A typical warm night setting, similar to an incandescent bulb
+        assert!(close(kelvin_to_rgb(3000), [1.0, 0.70, 0.44]));
+        // Candlelight has no blue at all
+        assert_eq!(kelvin_to_rgb(1900)[2], 0.0);
+        // Out of range temperatures are clamped
+        assert_eq!(kelvin_to_rgb(500), kelvin_to_rgb(1000));
+        // Cooler than neutral never boosts a channel above full strength
+        let cool = kelvin_to_rgb(10000);
+        assert!(cool[0] < 1.0 && cool[2] == 1.0);
+
+        // Green and blue increase with the temperature
+        let mut previous = kelvin_to_rgb(1000);
+        for kelvin in (1100..=6500).step_by(100) {
+            let rgb = kelvin_to_rgb(kelvin);
+            assert_eq!(rgb[0], 1.0);
+            assert!(rgb[1] > previous[1]);
+            assert!(rgb[2] >= previous[2]);
+            previous = rgb;
+        }
+    }
+
+    #[test]
+    fn test_normalise_output_name() {
+        assert_eq!(normalise_output_name("HDMI-A-1"), "HDMI1");
+        assert_eq!(normalise_output_name("HDMI-1"), "HDMI1");
+        assert_eq!(normalise_output_name("eDP-1"), "EDP1");
+        assert_eq!(normalise_output_name("DP-2"), normalise_output_name("DP2"));
+        assert_ne!(
+            normalise_output_name("DP-1"),
+            normalise_output_name("eDP-1")
+        );
+    }
+
+    #[test]
+    fn test_hardware_outputs() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |p: &str| root.path().join(p);
+        let devices = [
+            "devices/pci/drm/card0/card0-eDP-1/intel_backlight",
+            "devices/pci/drm/card0/card0-DP-1",
+            "devices/pci/drm/card0/card0-HDMI-A-1",
+            "devices/pci/i2c-5/5-0037/ddcci5",
+            "devices/pci/i2c-6",
+            "class/drm",
+            "class/backlight",
+        ];
+        for dir in devices {
+            fs::create_dir_all(path(dir)).unwrap();
+        }
+        let links = [
+            (
+                "class/backlight/intel_backlight/device",
+                "devices/pci/drm/card0/card0-eDP-1",
+            ),
+            (
+                "class/backlight/ddcci5/device",
+                "devices/pci/i2c-5/5-0037/ddcci5",
+            ),
+            ("class/drm/card0-eDP-1", "devices/pci/drm/card0/card0-eDP-1"),
+            ("class/drm/card0-DP-1", "devices/pci/drm/card0/card0-DP-1"),
+            (
+                "class/drm/card0-HDMI-A-1",
+                "devices/pci/drm/card0/card0-HDMI-A-1",
+            ),
+            ("devices/pci/drm/card0/card0-DP-1/ddc", "devices/pci/i2c-5"),
+            (
+                "devices/pci/drm/card0/card0-HDMI-A-1/ddc",
+                "devices/pci/i2c-6",
+            ),
+        ];
+        for (link, target) in links {
+            fs::create_dir_all(path(link).parent().unwrap()).unwrap();
+            symlink(path(target), path(link)).unwrap();
+        }
+        let outputs = hardware_outputs(&path("class/drm"), &path("class/backlight"));
+        assert_eq!(
+            outputs,
+            HashSet::from(["EDP1".to_owned(), "DP1".to_owned()])
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Sets the gamma tables using the wlr-gamma-control protocol, supported by wlroots based
+//! compositors. The compositor restores the original tables when the connection is closed.
+
+use crate::gamma::{gamma_ramp, Adjustment, Backend};
+use anyhow::{anyhow, Context};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::fd::AsFd;
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::wl_output::{self, WlOutput};
+use wayland_client::protocol::wl_registry::{self, WlRegistry};
+use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_protocols_wlr::gamma_control::v1::client::zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1;
+use wayland_protocols_wlr::gamma_control::v1::client::zwlr_gamma_control_v1::{
+    self, ZwlrGammaControlV1,
+};
+
+/// The output names are only sent since version 4
+const OUTPUT_VERSION: u32 = 4;
+
+struct Output {
+    /// The name of the registry global
+    global: u32,
+    output: WlOutput,
+    name: Option<String>,
+    control: Option<ZwlrGammaControlV1>,
+    gamma_size: Option<u32>,
+    /// The compositor refused to set the gamma, e.g. because another program is controlling it
+    failed: bool,
+}
+
+#[derive(Default)]
+struct State {
+    outputs: Vec<Output>,
+}
+
+impl State {
+    fn bind_output(
+        &mut self,
+        registry: &WlRegistry,
+        global: u32,
+        version: u32,
+        qh: &QueueHandle<Self>,
+    ) {
+        if version < OUTPUT_VERSION {
+            log::debug!("Ignoring wl_output {global} because it doesn't have a name");
+            return;
+        }
+        let output = registry.bind::<WlOutput, _, _>(global, OUTPUT_VERSION, qh, global);
+        self.outputs.push(Output {
+            global,
+            output,
+            name: None,
+            control: None,
+            gamma_size: None,
+            failed: false,
+        });
+    }
+
+    fn output(&mut self, global: u32) -> Option<&mut Output> {
+        self.outputs.iter_mut().find(|o| o.global == global)
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == "wl_output" => state.bind_output(registry, name, version, qh),
+            wl_registry::Event::GlobalRemove { name } => {
+                if let Some(index) = state.outputs.iter().position(|o| o.global == name) {
+                    let output = state.outputs.remove(index);
+                    if let Some(control) = output.control {
+                        control.destroy();
+                    }
+                    output.output.release();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: wl_output::Event,
+        global: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_output::Event::Name { name } = event {
+            if let Some(output) = state.output(*global) {
+                output.name = Some(name);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrGammaControlV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrGammaControlV1,
+        event: zwlr_gamma_control_v1::Event,
+        global: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Some(output) = state.output(*global) else {
+            return;
+        };
+        match event {
+            zwlr_gamma_control_v1::Event::GammaSize { size } => output.gamma_size = Some(size),
+            zwlr_gamma_control_v1::Event::Failed => output.failed = true,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(State: ignore ZwlrGammaControlManagerV1);
+
+pub struct WaylandGamma {
+    queue: EventQueue<State>,
+    state: State,
+    manager: ZwlrGammaControlManagerV1,
+}
+
+impl WaylandGamma {
+    pub fn connect() -> anyhow::Result<Self> {
+        let connection =
+            Connection::connect_to_env().context("Unable to connect to the Wayland display")?;
+        let (globals, mut queue) = registry_queue_init::<State>(&connection)?;
+        let qh = queue.handle();
+        let manager = globals
+            .bind::<ZwlrGammaControlManagerV1, _, _>(&qh, 1..=1, ())
+            .context("The compositor doesn't support wlr-gamma-control")?;
+        let mut state = State::default();
+        for global in globals.contents().clone_list() {
+            if global.interface == "wl_output" {
+                state.bind_output(globals.registry(), global.name, global.version, &qh);
+            }
+        }
+        queue.roundtrip(&mut state)?;
+        Ok(Self {
+            queue,
+            state,
+            manager,
+        })
+    }
+}
+
+impl Backend for WaylandGamma {
+    fn outputs(&mut self) -> anyhow::Result<Vec<String>> {
+        // Receive any outputs that have been added or removed since last time
+        self.queue.roundtrip(&mut self.state)?;
+        let qh = self.queue.handle();
+        for output in &mut self.state.outputs {
+            // Try again, in case the program that was controlling the gamma has exited
+            if output.failed {
+                if let Some(control) = output.control.take() {
+                    control.destroy();
+                }
+                output.failed = false;
+                output.gamma_size = None;
+            }
+            if output.control.is_none() {
+                let control = self
+                    .manager
+                    .get_gamma_control(&output.output, &qh, output.global);
+                output.control = Some(control);
+            }
+        }
+        self.queue.roundtrip(&mut self.state)?;
+        Ok(self
+            .state
+            .outputs
+            .iter()
+            .filter(|o| !o.failed && o.gamma_size.is_some())
+            .filter_map(|o| o.name.clone())
+            .collect())
+    }
+
+    fn set_gamma(&mut self, name: &str, adjustment: Adjustment) -> anyhow::Result<()> {
+        let output = self
+            .state
+            .outputs
+            .iter()
+            .find(|o| o.name.as_deref() == Some(name))
+            .ok_or_else(|| anyhow!("Output '{name}' is not connected"))?;
+        let (Some(control), Some(size)) = (&output.control, output.gamma_size) else {
+            return Err(anyhow!("Output '{name}' doesn't support gamma control"));
+        };
+        // The table is passed in a file, as the red, green then blue ramps in native byte order
+        let ramp = gamma_ramp(size as usize, adjustment);
+        let bytes = ramp
+            .red
+            .iter()
+            .chain(&ramp.green)
+            .chain(&ramp.blue)
+            .flat_map(|v| v.to_ne_bytes())
+            .collect::<Vec<_>>();
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&bytes)?;
+        file.seek(SeekFrom::Start(0))?;
+        control.set_gamma(file.as_fd());
+        self.queue.roundtrip(&mut self.state)?;
+        if self
+            .state
+            .outputs
+            .iter()
+            .any(|o| o.failed && o.name.as_deref() == Some(name))
+        {
+            return Err(anyhow!(
+                "The compositor refused to set the gamma for '{name}', it may be in use by another program"
+            ));
+        }
+        Ok(())
+    }
+}
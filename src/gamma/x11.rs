@@ -0,0 +1,73 @@
+//! Sets the gamma tables using the X11 RandR extension, they stay set after disconnecting
+
+use crate::gamma::{gamma_ramp, Adjustment, Backend};
+use anyhow::{anyhow, Context};
+use std::collections::HashMap;
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::{self, ConnectionExt};
+use x11rb::protocol::xproto::Window;
+use x11rb::rust_connection::RustConnection;
+
+pub struct X11Gamma {
+    connection: RustConnection,
+    root: Window,
+    /// The CRTC that each output was using when the outputs were last listed
+    crtcs: HashMap<String, randr::Crtc>,
+}
+
+impl X11Gamma {
+    pub fn connect() -> anyhow::Result<Self> {
+        let (connection, screen) =
+            x11rb::connect(None).context("Unable to connect to the X11 display")?;
+        let root = connection.setup().roots[screen].root;
+        connection
+            .randr_query_version(1, 3)?
+            .reply()
+            .context("RandR 1.3 is not supported")?;
+        Ok(Self {
+            connection,
+            root,
+            crtcs: HashMap::new(),
+        })
+    }
+}
+
+impl Backend for X11Gamma {
+    fn outputs(&mut self) -> anyhow::Result<Vec<String>> {
+        let resources = self
+            .connection
+            .randr_get_screen_resources_current(self.root)?
+            .reply()?;
+        self.crtcs.clear();
+        for output in resources.outputs {
+            let info = self
+                .connection
+                .randr_get_output_info(output, resources.config_timestamp)?
+                .reply()?;
+            // Disabled outputs don't have a CRTC
+            if info.connection == randr::Connection::CONNECTED && info.crtc != 0 {
+                let name = String::from_utf8_lossy(&info.name).into_owned();
+                self.crtcs.insert(name, info.crtc);
+            }
+        }
+        Ok(self.crtcs.keys().cloned().collect())
+    }
+
+    fn set_gamma(&mut self, output: &str, adjustment: Adjustment) -> anyhow::Result<()> {
+        let crtc = *self
+            .crtcs
+            .get(output)
+            .ok_or_else(|| anyhow!("Output '{output}' is not connected"))?;
+        let size = self
+            .connection
+            .randr_get_crtc_gamma_size(crtc)?
+            .reply()?
+            .size;
+        let ramp = gamma_ramp(size as usize, adjustment);
+        self.connection
+            .randr_set_crtc_gamma(crtc, &ramp.red, &ramp.green, &ramp.blue)?
+            .check()
+            .context(format!("Unable to set gamma for '{output}'"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! Finds the location automatically using the GeoClue2 D-Bus service
+
+use crate::config::{Location, SsbConfig};
+use crate::controller::{BrightnessController, Message};
+use crate::location::AutomaticLocation;
+use anyhow::anyhow;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{dbus_proxy, CacheProperties};
+
+/// Must match the name of the installed .desktop file for GeoClue to authorise the client
+const DESKTOP_ID: &str = "solar-screen-brightness";
+
+/// GCLUE_ACCURACY_LEVEL_CITY, which is plenty for calculating the sunrise and sunset
+const ACCURACY_LEVEL_CITY: u32 = 4;
+
+/// How long to wait for GeoClue to find the location
+const LOCATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether GeoClue has found the location
+const LOCATE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often to update the location
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How often to check whether automatic location has been enabled in the config
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait Manager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Client",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait Client {
+    fn start(&self) -> zbus::Result<()>;
+
+    fn stop(&self) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn location(&self) -> zbus::Result<OwnedObjectPath>;
+
+    #[dbus_proxy(property)]
+    fn set_desktop_id(&self, id: &str) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.GeoClue2.Location",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait Location {
+    #[dbus_proxy(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+
+    #[dbus_proxy(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+
+    #[dbus_proxy(property)]
+    fn accuracy(&self) -> zbus::Result<f64>;
+}
+
+/// Find the location once, e.g. for the CLI
+pub fn locate_now() -> Option<AutomaticLocation> {
+    let (_stop, stop_rx) = mpsc::channel();
+    let result = Connection::system()
+        .map_err(|e| e.into())
+        .and_then(|connection| locate(&connection, &stop_rx));
+    match result {
+        Ok(location) => location,
+        Err(e) => {
+            log::error!("Unable to find the location using GeoClue: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Start a GeoClue client and wait for it to find the location.
+/// Returns None if the stop channel receives a value while waiting.
+fn locate(
+    connection: &Connection,
+    stop: &mpsc::Receiver<()>,
+) -> anyhow::Result<Option<AutomaticLocation>> {
+    let manager = ManagerProxyBlocking::new(connection)?;
+    let client = ClientProxyBlocking::builder(connection)
+        .path(manager.get_client()?)?
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    client.set_desktop_id(DESKTOP_ID)?;
+    client.set_requested_accuracy_level(ACCURACY_LEVEL_CITY)?;
+    client.start()?;
+    let result = wait_for_location(connection, &client, stop);
+    if let Err(e) = client.stop() {
+        log::warn!("Unable to stop GeoClue client: {e}");
+    }
+    result
+}
+
+fn wait_for_location(
+    connection: &Connection,
+    client: &ClientProxyBlocking,
+    stop: &mpsc::Receiver<()>,
+) -> anyhow::Result<Option<AutomaticLocation>> {
+    let start = Instant::now();
+    loop {
+        // The location is "/" until GeoClue has found it
+        let path = client.location()?;
+        if path.as_str() != "/" {
+            let location = LocationProxyBlocking::builder(connection)
+                .path(path)?
+                .cache_properties(CacheProperties::No)
+                .build()?;
+            return Ok(Some(AutomaticLocation {
+                location: Location {
+                    latitude: location.latitude()?,
+                    longitude: location.longitude()?,
+                },
+                accuracy: location.accuracy()?,
+            }));
+        }
+        if start.elapsed() > LOCATE_TIMEOUT {
+            return Err(anyhow!(
+                "Timed out waiting for GeoClue to find the location"
+            ));
+        }
+        match stop.recv_timeout(LOCATE_POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Periodically finds the location using GeoClue on its own thread, while automatic location is
+/// enabled, and sends it to the BrightnessController
+pub struct GeoclueWatcher {
+    stop: Option<mpsc::Sender<()>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl GeoclueWatcher {
+    pub fn start(config: Arc<RwLock<SsbConfig>>, controller: &BrightnessController) -> Self {
+        let sender = controller.sender.clone();
+        let (stop, stop_rx) = mpsc::channel();
+        let join_handle = std::thread::spawn(move || run(config, sender, stop_rx));
+        Self {
+            stop: Some(stop),
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for GeoclueWatcher {
+    fn drop(&mut self) {
+        log::info!("Stopping GeoClue watcher");
+        drop(self.stop.take());
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn run(
+    config: Arc<RwLock<SsbConfig>>,
+    controller: mpsc::Sender<Message>,
+    stop: mpsc::Receiver<()>,
+) {
+    let mut connection = None;
+    let mut last_attempt: Option<Instant> = None;
+    let mut reported = None;
+    loop {
+        let enabled = config.read().unwrap().automatic_location;
+        let due = match last_attempt {
+            None => true,
+            Some(time) => time.elapsed() >= REFRESH_INTERVAL,
+        };
+        if !enabled {
+            last_attempt = None;
+        } else if due {
+            last_attempt = Some(Instant::now());
+            if connection.is_none() {
+                connection = Connection::system()
+                    .map_err(|e| log::error!("Unable to connect to the system bus: {e}"))
+                    .ok();
+            }
+            let location = match connection.as_ref().map(|c| locate(c, &stop)) {
+                Some(Ok(Some(location))) => {
+                    log::info!("GeoClue found location {location:?}");
+                    Some(location)
+                }
+                Some(Ok(None)) => break,
+                Some(Err(e)) => {
+                    log::error!("Unable to find the location using GeoClue: {:#}", e);
+                    None
+                }
+                None => None,
+            };
+            if location != reported {
+                reported = location;
+                controller
+                    .send(Message::AutomaticLocation(location))
+                    .unwrap();
+            }
+        }
+        match stop.recv_timeout(CONFIG_POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => break,
+        }
+    }
+    log::debug!("GeoClue watcher thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::PrivateBus;
+    use zbus::blocking::ConnectionBuilder;
+    use zbus::dbus_interface;
+
+    const CLIENT_PATH: &str = "/org/freedesktop/GeoClue2/Client/1";
+    const LOCATION_PATH: &str = "/org/freedesktop/GeoClue2/Client/1/Location/0";
+
+    struct FakeManager;
+
+    #[dbus_interface(name = "org.freedesktop.GeoClue2.Manager")]
+    impl FakeManager {
+        fn get_client(&self) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(CLIENT_PATH).unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeClient {
+        desktop_id: String,
+        accuracy_level: u32,
+        started: bool,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.GeoClue2.Client")]
+    impl FakeClient {
+        fn start(&mut self) -> zbus::fdo::Result<()> {
+            if self.desktop_id.is_empty() || self.accuracy_level == 0 {
+                return Err(zbus::fdo::Error::AccessDenied("Not configured".into()));
+            }
+            self.started = true;
+            Ok(())
+        }
+
+        fn stop(&mut self) {
+            self.started = false;
+        }
+
+        #[dbus_interface(property)]
+        fn location(&self) -> OwnedObjectPath {
+            let path = if self.started { LOCATION_PATH } else { "/" };
+            OwnedObjectPath::try_from(path).unwrap()
+        }
+
+        #[dbus_interface(property)]
+        fn desktop_id(&self) -> &str {
+            &self.desktop_id
+        }
+
+        #[dbus_interface(property)]
+        fn set_desktop_id(&mut self, id: &str) {
+            self.desktop_id = id.to_owned();
+        }
+
+        #[dbus_interface(property)]
+        fn requested_accuracy_level(&self) -> u32 {
+            self.accuracy_level
+        }
+
+        #[dbus_interface(property)]
+        fn set_requested_accuracy_level(&mut self, level: u32) {
+            self.accuracy_level = level;
+        }
+    }
+
+    struct FakeLocation;
+
+    #[dbus_interface(name = "org.freedesktop.GeoClue2.Location")]
+    impl FakeLocation {
+        #[dbus_interface(property)]
+        fn latitude(&self) -> f64 {
+            52.2053
+        }
+
+        #[dbus_interface(property)]
+        fn longitude(&self) -> f64 {
+            0.1218
+        }
+
+        #[dbus_interface(property)]
+        fn accuracy(&self) -> f64 {
+            2500.0
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping test because dbus-daemon is not available");
+            return;
+        };
+        let _server = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .name("org.freedesktop.GeoClue2")
+            .unwrap()
+            .serve_at("/org/freedesktop/GeoClue2/Manager", FakeManager)
+            .unwrap()
+            .serve_at(CLIENT_PATH, FakeClient::default())
+            .unwrap()
+            .serve_at(LOCATION_PATH, FakeLocation)
+            .unwrap()
+            .build()
+            .unwrap();
+        let client = ConnectionBuilder::address(bus.address.as_str())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let (_stop_tx, stop_rx) = mpsc::channel();
+        let location = locate(&client, &stop_rx).unwrap().unwrap();
+        assert_eq!(
+            location,
+            AutomaticLocation {
+                location: Location {
+                    latitude: 52.2053,
+                    longitude: 0.1218,
+                },
+                accuracy: 2500.0,
+            }
+        );
+    }
+}
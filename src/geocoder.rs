@@ -0,0 +1,337 @@
+//! Searching for the coordinates of a place by name
+
+use crate::common::APP_NAME;
+use crate::config::{Geocoder, Location};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use validator::Validate;
+
+/// Embedded city database used for offline searches
+const CITIES: &str = include_str!("../assets/cities.tsv");
+
+/// Names of the country codes used in the city database
+const COUNTRIES: &str = include_str!("../assets/countries.tsv");
+
+const NOMINATIM_URL: &str = "https://nominatim.openstreetmap.org/search";
+
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Maximum number of results returned by a search
+pub const MAX_RESULTS: usize = 10;
+
+/// Minimum similarity for a city to be considered a fuzzy match
+const FUZZY_THRESHOLD: f64 = 0.85;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Validate)]
+pub struct SearchResult {
+    pub name: String,
+    pub country: String,
+    #[validate]
+    pub location: Location,
+}
+
+impl SearchResult {
+    pub fn display_name(&self) -> String {
+        if self.country.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}, {}", self.name, self.country)
+        }
+    }
+}
+
+/// Search using the selected geocoder, online searches fall back to the offline database if they
+/// fail or return no results
+pub fn search(geocoder: Geocoder, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+    match geocoder {
+        Geocoder::Offline => Ok(search_offline(query)),
+        Geocoder::OpenStreetMap => match search_online(NOMINATIM_URL, query) {
+            Ok(results) if !results.is_empty() => Ok(results),
+            Ok(_) => Ok(search_offline(query)),
+            Err(e) => {
+                log::warn!(
+                    "Online search failed, falling back to offline search: {:#}",
+                    e
+                );
+                let results = search_offline(query);
+                if results.is_empty() {
+                    Err(e)
+                } else {
+                    Ok(results)
+                }
+            }
+        },
+    }
+}
+
+/// Fuzzy search the embedded city database. A country or region can be given after a comma,
+/// e.g. "Paris, FR" or "Manchester, England"
+pub fn search_offline(query: &str) -> Vec<SearchResult> {
+    let (city_query, area_query) = match query.split_once(',') {
+        Some((city, area)) => (normalise(city), Some(normalise(area))),
+        None => (normalise(query), None),
+    };
+    if city_query.is_empty() {
+        return vec![];
+    }
+    let countries = COUNTRIES
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('\t'))
+        .collect::<HashMap<_, _>>();
+    // The area is either a country, or a region within any country
+    let area = area_query.map(|area| {
+        let area_countries = countries
+            .iter()
+            .filter(|(code, country)| {
+                normalise(code) == area || normalise(country).starts_with(&area)
+            })
+            .map(|(code, _)| *code)
+            .collect::<HashSet<_>>();
+        (area, area_countries)
+    });
+    let mut matches = CITIES
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(parse_city)
+        .filter(|city| match &area {
+            Some((area, area_countries)) => {
+                area_countries.contains(city.country_code)
+                    || normalise(city.region).starts_with(area)
+            }
+            None => true,
+        })
+        .filter_map(|city| {
+            let score = [city.name, city.ascii_name]
+                .into_iter()
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| match_score(&city_query, &normalise(name)))
+                .max_by(f64::total_cmp)?;
+            Some((score, city))
+        })
+        .collect::<Vec<_>>();
+    // Stable, so cities earlier in the database are preferred when the scores are equal
+    matches.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    matches
+        .into_iter()
+        .take(MAX_RESULTS)
+        .map(|(_, city)| city.into_result(&countries))
+        .collect()
+}
+
+/// A line of the city database
+struct City<'a> {
+    name: &'a str,
+    ascii_name: &'a str,
+    region: &'a str,
+    country_code: &'a str,
+    location: Location,
+}
+
+impl City<'_> {
+    fn into_result(self, countries: &HashMap<&str, &str>) -> SearchResult {
+        // Include the region to tell apart cities with the same name, like online results do
+        let name = if self.region.is_empty() || self.region == self.name {
+            self.name.to_owned()
+        } else {
+            format!("{}, {}", self.name, self.region)
+        };
+        let country = countries
+            .get(self.country_code)
+            .copied()
+            .unwrap_or(self.country_code);
+        SearchResult {
+            name,
+            country: country.to_owned(),
+            location: self.location,
+        }
+    }
+}
+
+fn parse_city(line: &str) -> Option<City<'_>> {
+    let mut fields = line.split('\t');
+    Some(City {
+        name: fields.next()?,
+        ascii_name: fields.next()?,
+        region: fields.next()?,
+        country_code: fields.next()?,
+        location: Location {
+            latitude: fields.next()?.parse().ok()?,
+            longitude: fields.next()?.parse().ok()?,
+        },
+    })
+}
+
+/// How well the name matches the query, exact matches are preferred over prefixes, then
+/// substrings, then names that are similar (e.g. misspelt)
+fn match_score(query: &str, name: &str) -> Option<f64> {
+    if name == query {
+        Some(4.0)
+    } else if name.starts_with(query) {
+        Some(3.0)
+    } else if name.contains(query) {
+        Some(2.0)
+    } else {
+        let similarity = strsim::jaro_winkler(query, name);
+        (similarity >= FUZZY_THRESHOLD).then_some(similarity)
+    }
+}
+
+/// Lowercase, without accents or punctuation, so that e.g. "São Paulo" matches "sao paulo"
+fn normalise(value: &str) -> String {
+    // A single pass, as this is called for every city in the database
+    let mut normalised = String::with_capacity(value.len());
+    for c in value.chars().flat_map(char::to_lowercase).map(fold_accent) {
+        if c.is_alphanumeric() {
+            normalised.push(c);
+        } else if !normalised.is_empty() && !normalised.ends_with(' ') {
+            normalised.push(' ');
+        }
+    }
+    normalised.truncate(normalised.trim_end().len());
+    normalised
+}
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        c => c,
+    }
+}
+
+#[derive(Deserialize)]
+struct NominatimResult {
+    display_name: String,
+    lat: String,
+    lon: String,
+    address: Option<NominatimAddress>,
+}
+
+#[derive(Deserialize)]
+struct NominatimAddress {
+    country: Option<String>,
+}
+
+/// Search using the OpenStreetMap Nominatim API
+fn search_online(url: &str, query: &str) -> anyhow::Result<Vec<SearchResult>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(ONLINE_TIMEOUT)
+        .user_agent(format!("{}/{}", APP_NAME, env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let results = client
+        .get(url)
+        .query(&[
+            ("q", query),
+            ("format", "jsonv2"),
+            ("addressdetails", "1"),
+            ("limit", &MAX_RESULTS.to_string()),
+        ])
+        .send()?
+        .error_for_status()?
+        .json::<Vec<NominatimResult>>()
+        .context("Unable to parse search response")?;
+    results
+        .into_iter()
+        .map(|r| {
+            let country = r.address.and_then(|a| a.country).unwrap_or_default();
+            // The display name is the full address, which already ends with the country
+            let name = r
+                .display_name
+                .strip_suffix(&format!(", {country}"))
+                .unwrap_or(&r.display_name)
+                .to_owned();
+            Ok(SearchResult {
+                name,
+                country,
+                location: Location {
+                    latitude: r.lat.parse().context("Invalid latitude")?,
+                    longitude: r.lon.parse().context("Invalid longitude")?,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve_json_once;
+
+    fn names(results: &[SearchResult]) -> Vec<String> {
+        results.iter().map(|r| r.display_name()).collect()
+    }
+
+    #[test]
+    fn test_search_offline() {
+        let london = search_offline("london");
+        assert_eq!(names(&london)[0], "London, England, United Kingdom");
+        assert_eq!(london[0].location.latitude, 51.50853);
+
+        // Misspellings and accents
+        assert_eq!(
+            names(&search_offline("Lodnon"))[0],
+            "London, England, United Kingdom"
+        );
+        assert_eq!(names(&search_offline("Sao Paulo"))[0], "São Paulo, Brazil");
+
+        // Prefixes, and filtering by country name or code
+        assert_eq!(
+            names(&search_offline("new y"))[0],
+            "New York City, New York, United States"
+        );
+        assert_eq!(
+            names(&search_offline("sydney, au"))[0],
+            "Sydney, New South Wales, Australia"
+        );
+        assert!(search_offline("sydney, france").is_empty());
+
+        assert!(search_offline("").is_empty());
+        assert!(search_offline("qwertyuiop").is_empty());
+    }
+
+    #[test]
+    fn test_search_offline_towns() {
+        assert_eq!(
+            names(&search_offline("manchester"))[0],
+            "Manchester, England, United Kingdom"
+        );
+        // Filtering by region, and the ASCII name
+        let springfield = names(&search_offline("springfield, illinois"));
+        assert_eq!(springfield, vec!["Springfield, Illinois, United States"]);
+        assert_eq!(
+            names(&search_offline("Lodz"))[0],
+            "Łódź, Łódź Voivodeship, Poland"
+        );
+    }
+
+    #[test]
+    fn test_search_online() {
+        let body = r#"[
+            {"display_name":"Springfield, Sangamon County, Illinois, United States","lat":"39.7990175","lon":"-89.6439575","address":{"city":"Springfield","country":"United States"}},
+            {"display_name":"Springfield, Greene County, Missouri, United States","lat":"37.2081729","lon":"-93.2922715","address":{"city":"Springfield","country":"United States"}}
+        ]"#;
+        let (base_url, server) = serve_json_once(body);
+        let url = format!("{base_url}/search");
+        let results = search_online(&url, "springfield").unwrap();
+        assert_eq!(
+            names(&results),
+            vec![
+                "Springfield, Sangamon County, Illinois, United States",
+                "Springfield, Greene County, Missouri, United States"
+            ]
+        );
+        assert_eq!(results[1].name, "Springfield, Greene County, Missouri");
+        assert_eq!(results[1].location.longitude, -93.2922715);
+        let request_line = server.join().unwrap();
+        assert!(request_line.starts_with("GET /search?q=springfield&format=jsonv2"));
+    }
+}
@@ -1,28 +1,48 @@
-use crate::calculator::calculate_brightness;
-use crate::config::{Location, SsbConfig};
+use crate::calculator::{calculate_brightness, calculate_temperature};
+use crate::config::{
+    AmbientLightSettings, ColorTemperatureValues, FadeSettings, Location, SsbConfig,
+    TransitionCurve, WeatherSettings,
+};
 use crate::controller::Message;
 use crate::gui::app::{save_config, AppState, Page, SPACING};
 use chrono::{Duration, DurationRound, TimeZone};
-use egui::plot::{uniform_grid_spacer, GridInput, GridMark, Line, PlotBounds};
+use egui::plot::{uniform_grid_spacer, GridInput, GridMark, Legend, Line, PlotBounds};
 use egui::widgets::plot::Plot;
 use std::mem::take;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
-use sunrise_sunset_calculator::SunriseSunsetParameters;
+use sunrise_sunset_calculator::{SunriseSunsetParameters, SunriseSunsetResult};
 use validator::Validate;
 
+const DEFAULT_REASSERT_MINS: u32 = 10;
+
 pub struct BrightnessSettingsPage {
     brightness_day: u32,
     brightness_night: u32,
     transition_mins: u32,
+    transition_curve: TransitionCurve,
+    fade_enabled: bool,
+    fade: FadeSettings,
+    reassert_enabled: bool,
+    reassert_mins: u32,
+    ambient_enabled: bool,
+    ambient: AmbientLightSettings,
+    weather_enabled: bool,
+    weather: WeatherSettings,
+    color_temperature_enabled: bool,
+    color_temperature: ColorTemperatureValues,
     plot: Option<PlotData>,
 }
 
 struct PlotData {
     points: Vec<[f64; 2]>,
+    /// The colour temperature scaled to the brightness axis, if it is enabled
+    temperature_points: Option<Vec<[f64; 2]>>,
     generated_at: SystemTime,
     brightness_day: u32,
     brightness_night: u32,
     transition_mins: u32,
+    transition_curve: TransitionCurve,
+    color_temperature: Option<ColorTemperatureValues>,
     location: Location,
 }
 
@@ -42,6 +62,12 @@ impl PlotData {
         if config.transition_mins != self.transition_mins {
             return true;
         }
+        if config.transition_curve != self.transition_curve {
+            return true;
+        }
+        if config.color_temperature != self.color_temperature {
+            return true;
+        }
         let age = SystemTime::now().duration_since(self.generated_at).unwrap();
         if chrono::Duration::from_std(age).unwrap().num_minutes() > 5 {
             return true;
@@ -56,6 +82,17 @@ impl BrightnessSettingsPage {
             brightness_day: config.brightness_day,
             brightness_night: config.brightness_night,
             transition_mins: config.transition_mins,
+            transition_curve: config.transition_curve,
+            fade_enabled: config.fade.is_some(),
+            fade: config.fade.unwrap_or_default(),
+            reassert_enabled: config.reassert_mins.is_some(),
+            reassert_mins: config.reassert_mins.unwrap_or(DEFAULT_REASSERT_MINS),
+            ambient_enabled: config.ambient_light.is_some(),
+            ambient: config.ambient_light.clone().unwrap_or_default(),
+            weather_enabled: config.weather.is_some(),
+            weather: config.weather.clone().unwrap_or_default(),
+            color_temperature_enabled: config.color_temperature.is_some(),
+            color_temperature: config.color_temperature.unwrap_or_default(),
             plot: None,
         }
     }
@@ -64,6 +101,14 @@ impl BrightnessSettingsPage {
         config.brightness_night = self.brightness_night;
         config.brightness_day = self.brightness_day;
         config.transition_mins = self.transition_mins;
+        config.transition_curve = self.transition_curve;
+        config.fade = self.fade_enabled.then_some(self.fade);
+        config.reassert_mins = self.reassert_enabled.then_some(self.reassert_mins);
+        config.ambient_light = self.ambient_enabled.then(|| self.ambient.clone());
+        config.weather = self.weather_enabled.then(|| self.weather.clone());
+        config.color_temperature = self
+            .color_temperature_enabled
+            .then_some(self.color_temperature);
         assert!(config.validate().is_ok())
     }
 }
@@ -86,6 +131,88 @@ impl Page for BrightnessSettingsPage {
                 ui.add(egui::Slider::new(&mut self.transition_mins, 0u32..=360u32).suffix("min"));
                 ui.end_row();
 
+                ui.label("Transition Curve").on_hover_text("The shape of the brightness change during the transition");
+                egui::ComboBox::from_id_source("transition_curve")
+                    .selected_text(self.transition_curve.as_str())
+                    .show_ui(ui, |ui| {
+                        for curve in enum_iterator::all::<TransitionCurve>() {
+                            ui.selectable_value(&mut self.transition_curve, curve, curve.as_str());
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Smooth Fading").on_hover_text("Gradually fade to the new brightness instead of changing it instantly");
+                ui.add(egui::Checkbox::without_text(&mut self.fade_enabled));
+                ui.end_row();
+
+                if self.fade_enabled {
+                    ui.label("Fade Duration").on_hover_text("How long it takes to fade to the new brightness");
+                    ui.add(egui::DragValue::new(&mut self.fade.duration_ms).clamp_range(100u32..=60000u32).suffix("ms"));
+                    ui.end_row();
+
+                    ui.label("Fade Step Interval").on_hover_text("Minimum time between brightness changes for internal displays");
+                    ui.add(egui::DragValue::new(&mut self.fade.step_interval_ms).clamp_range(10u32..=10000u32).suffix("ms"));
+                    ui.end_row();
+
+                    ui.label("DDC/CI Step Interval").on_hover_text("Minimum time between brightness changes for external monitors, which are slower to update");
+                    ui.add(egui::DragValue::new(&mut self.fade.ddcci_step_interval_ms).clamp_range(10u32..=10000u32).suffix("ms"));
+                    ui.end_row();
+                }
+
+                ui.label("Re-assert Brightness").on_hover_text("Periodically check the monitors and correct any that have changed their own brightness, e.g. after waking from standby");
+                ui.add(egui::Checkbox::without_text(&mut self.reassert_enabled));
+                ui.end_row();
+
+                if self.reassert_enabled {
+                    ui.label("Re-assert Interval").on_hover_text("How often to check the monitor brightness");
+                    ui.add(egui::DragValue::new(&mut self.reassert_mins).clamp_range(1u32..=1440u32).suffix("min"));
+                    ui.end_row();
+                }
+
+                // Ambient light sensors are only supported through the Linux IIO subsystem
+                if cfg!(target_os = "linux") {
+                    ui.label("Ambient Light Sensor").on_hover_text("Adjust the brightness using the light measured by the ambient light sensor");
+                    ui.add(egui::Checkbox::without_text(&mut self.ambient_enabled));
+                    ui.end_row();
+
+                    if self.ambient_enabled {
+                        ui.label("Sensor Weight").on_hover_text("How much the sensor contributes to the brightness, compared to the position of the sun");
+                        ui.add(egui::Slider::new(&mut self.ambient.weight, 0u32..=100u32).suffix("%"));
+                        ui.end_row();
+
+                        ui.label("Sensor Hysteresis").on_hover_text("How much the sensor brightness must change before the monitors are updated");
+                        ui.add(egui::Slider::new(&mut self.ambient.hysteresis, 0u32..=100u32).suffix("%"));
+                        ui.end_row();
+                    }
+                }
+
+                ui.label("Weather Dimming").on_hover_text("Reduce the day brightness when it is cloudy");
+                ui.add(egui::Checkbox::without_text(&mut self.weather_enabled));
+                ui.end_row();
+
+                if self.weather_enabled {
+                    ui.label("Overcast Reduction").on_hover_text("How much the day brightness is reduced by when it is completely overcast");
+                    ui.add(egui::Slider::new(&mut self.weather.max_reduction, 0u32..=100u32).suffix("%"));
+                    ui.end_row();
+                }
+
+                // Gamma tables are only supported through X11 RandR and wlr-gamma-control
+                if cfg!(target_os = "linux") {
+                    ui.label("Colour Temperature").on_hover_text("Warm the colour of the monitors at night, using their gamma tables");
+                    ui.add(egui::Checkbox::without_text(&mut self.color_temperature_enabled));
+                    ui.end_row();
+
+                    if self.color_temperature_enabled {
+                        ui.label("Day Temperature").on_hover_text("6500K leaves the colours unchanged");
+                        ui.add(egui::Slider::new(&mut self.color_temperature.temperature_day, 1000u32..=10000u32).step_by(50.0).suffix("K"));
+                        ui.end_row();
+
+                        ui.label("Night Temperature").on_hover_text("Lower temperatures are warmer, e.g. 3500K is similar to a halogen bulb");
+                        ui.add(egui::Slider::new(&mut self.color_temperature.temperature_night, 1000u32..=10000u32).step_by(50.0).suffix("K"));
+                        ui.end_row();
+                    }
+                }
+
             });
         ui.add_space(SPACING);
         ui.with_layout(egui::Layout::left_to_right(egui::Align::LEFT), |ui| {
@@ -114,6 +241,21 @@ impl Page for BrightnessSettingsPage {
 }
 
 const LINE_NAME: &str = "Brightness";
+const TEMPERATURE_LINE_NAME: &str = "Colour Temperature";
+
+/// The colour temperatures at the bottom and top of the plot, so that they can share the
+/// brightness axis
+const TEMPERATURE_RANGE: (f64, f64) = (1000.0, 10000.0);
+
+fn temperature_to_plot(temperature: u32) -> f64 {
+    let (min, max) = TEMPERATURE_RANGE;
+    (temperature as f64 - min) / (max - min) * 100.0
+}
+
+fn plot_to_temperature(value: f64) -> f64 {
+    let (min, max) = TEMPERATURE_RANGE;
+    (value / 100.0 * (max - min) + min).round()
+}
 
 impl BrightnessSettingsPage {
     fn render_plot(&mut self, ui: &mut egui::Ui, app_state: &mut AppState) {
@@ -126,12 +268,16 @@ impl BrightnessSettingsPage {
                     config.brightness_day,
                     config.brightness_night,
                     config.transition_mins,
+                    config.transition_curve,
+                    config.color_temperature,
                 ),
                 Some(x) if x.is_stale(&config) => generate_plot_data(
                     location,
                     config.brightness_day,
                     config.brightness_night,
                     config.transition_mins,
+                    config.transition_curve,
+                    config.color_temperature,
                 ),
                 Some(x) => x,
             });
@@ -146,8 +292,17 @@ impl BrightnessSettingsPage {
             let line = Line::new(plot.points.clone())
                 .name(LINE_NAME)
                 .highlight(true);
+            let temperature_line = plot.temperature_points.clone().map(|points| {
+                Line::new(points)
+                    .name(TEMPERATURE_LINE_NAME)
+                    .color(egui::Color32::from_rgb(255, 150, 50))
+            });
 
-            Plot::new("brightness_curve")
+            let mut chart = Plot::new("brightness_curve");
+            if temperature_line.is_some() {
+                chart = chart.legend(Legend::default());
+            }
+            chart
                 .allow_drag(false)
                 .allow_zoom(false)
                 .allow_scroll(false)
@@ -157,6 +312,12 @@ impl BrightnessSettingsPage {
                 .label_formatter(|name, point| {
                     if name == LINE_NAME {
                         format!("{}\nBrightness {}%", convert_time(point.x), point.y)
+                    } else if name == TEMPERATURE_LINE_NAME {
+                        format!(
+                            "{}\nColour Temperature {}K",
+                            convert_time(point.x),
+                            plot_to_temperature(point.y)
+                        )
                     } else {
                         String::new()
                     }
@@ -164,7 +325,10 @@ impl BrightnessSettingsPage {
                 .x_axis_formatter(|val, _| convert_time(val))
                 .show(ui, |plot_ui| {
                     plot_ui.set_plot_bounds(PlotBounds::from_min_max([first, -5.0], [last, 105.0]));
-                    plot_ui.line(line)
+                    plot_ui.line(line);
+                    if let Some(temperature_line) = temperature_line {
+                        plot_ui.line(temperature_line);
+                    }
                 });
         }
     }
@@ -205,6 +369,8 @@ fn generate_plot_data(
     brightness_day: u32,
     brightness_night: u32,
     transition_mins: u32,
+    transition_curve: TransitionCurve,
+    color_temperature: Option<ColorTemperatureValues>,
 ) -> PlotData {
     log::debug!("Generating plot...");
     let timer_start = Instant::now();
@@ -219,6 +385,61 @@ fn generate_plot_data(
         .unwrap()
         .as_secs() as i64;
 
+    let points = sample_curve(location, graph_start, graph_end, |sun, time| {
+        let brightness = calculate_brightness(
+            brightness_day,
+            brightness_night,
+            transition_mins,
+            transition_curve,
+            sun,
+            time,
+        );
+        (brightness.brightness as f64, brightness.expiry_time)
+    });
+    let temperature_points = color_temperature.map(|values| {
+        sample_curve(location, graph_start, graph_end, |sun, time| {
+            let temperature = calculate_temperature(
+                values.temperature_day,
+                values.temperature_night,
+                transition_mins,
+                transition_curve,
+                sun,
+                time,
+            );
+            (
+                temperature_to_plot(temperature.temperature),
+                temperature.expiry_time,
+            )
+        })
+    });
+
+    log::debug!(
+        "Plot took {:?} {} points",
+        timer_start.elapsed(),
+        points.len()
+    );
+
+    PlotData {
+        points,
+        temperature_points,
+        generated_at: now,
+        brightness_day,
+        brightness_night,
+        transition_mins,
+        transition_curve,
+        color_temperature,
+        location,
+    }
+}
+
+/// Calculate the points of a curve between the start and end times, the function returns the
+/// value at a time and when it next changes
+fn sample_curve(
+    location: Location,
+    graph_start: i64,
+    graph_end: i64,
+    value_at: impl Fn(&SunriseSunsetResult, i64) -> (f64, Option<i64>),
+) -> Vec<[f64; 2]> {
     let mut points = Vec::new();
     let mut current = graph_start;
 
@@ -226,24 +447,18 @@ fn generate_plot_data(
         let sun = SunriseSunsetParameters::new(current, location.latitude, location.longitude)
             .calculate()
             .unwrap();
-        let brightness = calculate_brightness(
-            brightness_day,
-            brightness_night,
-            transition_mins,
-            &sun,
-            current,
-        );
-        let next_time = brightness.expiry_time.unwrap_or(graph_end).min(graph_end);
+        let (value, expiry_time) = value_at(&sun, current);
+        let next_time = expiry_time.unwrap_or(graph_end).min(graph_end);
 
         // Add some extra points in the "flat" zone to allow cursor to snap to the line
         // This is a bit of a hack, assuming if expiry is greater than 30 minutes,
         // to be completely accurate we would need to look ahead at the next calculation.
-        if brightness.expiry_time.unwrap_or(i64::MAX) - current > 1800 {
+        if expiry_time.unwrap_or(i64::MAX) - current > 1800 {
             for second in num::range_step(current, next_time, 240) {
-                points.push([second as f64, brightness.brightness as f64]);
+                points.push([second as f64, value]);
             }
         } else {
-            points.push([current as f64, brightness.brightness as f64]);
+            points.push([current as f64, value]);
         }
 
         if current == graph_end {
@@ -251,19 +466,5 @@ fn generate_plot_data(
         }
         current = next_time;
     }
-
-    log::debug!(
-        "Plot took {:?} {} points",
-        timer_start.elapsed(),
-        points.len()
-    );
-
-    PlotData {
-        points,
-        generated_at: now,
-        brightness_day,
-        brightness_night,
-        transition_mins,
-        location,
-    }
+    points
 }
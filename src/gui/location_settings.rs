@@ -1,17 +1,24 @@
-use crate::config::{Location, SsbConfig};
+use crate::config::{Geocoder, Location, SsbConfig};
 use crate::controller::Message;
+use crate::geocoder::{search, SearchResult};
 use crate::gui::app::{
-    save_config, set_red_widget_border, AppState, MessageModal, Modal, Page, SPACING,
+    save_config, set_red_widget_border, AppState, MessageModal, Modal, Page, Transitions, SPACING,
 };
 use crate::gui::UserEvent;
+use crate::location::LocationSource;
 use egui::{Context, Widget};
-use geocoding::{Forward, GeocodingError, Openstreetmap};
+use std::sync::{Arc, RwLock};
 use validator::Validate;
 
+const SEARCH_RESULTS_MAX_HEIGHT: f32 = 300.0;
+
 pub struct LocationSettingsPage {
     latitude: String,
     longitude: String,
     search_query: String,
+    geocoder: Geocoder,
+    automatic_location: bool,
+    new_location_name: String,
 }
 
 impl LocationSettingsPage {
@@ -21,14 +28,32 @@ impl LocationSettingsPage {
             latitude: coord_to_string(location.map(|l| l.latitude).unwrap_or(0.0)),
             longitude: coord_to_string(location.map(|l| l.longitude).unwrap_or(0.0)),
             search_query: String::default(),
+            geocoder: config.geocoder,
+            automatic_location: config.automatic_location,
+            new_location_name: String::default(),
         }
     }
 
-    fn copy_to_config(&self, config: &mut SsbConfig) {
-        config.location = Some(Location {
+    fn location(&self) -> Location {
+        Location {
             latitude: self.latitude.parse().unwrap(),
             longitude: self.longitude.parse().unwrap(),
-        });
+        }
+    }
+
+    fn copy_to_config(&self, config: &mut SsbConfig) {
+        let location = self.location();
+        // The location no longer matches the saved location if the coordinates were edited
+        let active_unchanged = config
+            .saved_locations
+            .iter()
+            .any(|l| Some(&l.name) == config.active_location.as_ref() && l.location == location);
+        if !active_unchanged {
+            config.active_location = None;
+        }
+        config.location = Some(location);
+        config.geocoder = self.geocoder;
+        config.automatic_location = self.automatic_location;
         assert!(config.validate().is_ok())
     }
 
@@ -47,20 +72,48 @@ impl LocationSettingsPage {
 
 impl Page for LocationSettingsPage {
     fn render(&mut self, ui: &mut egui::Ui, app_state: &mut AppState) {
+        self.render_approximate_notice(ui, app_state);
+
+        if cfg!(target_os = "linux") {
+            ui.checkbox(&mut self.automatic_location, "Automatic Location")
+                .on_hover_text(
+                    "Find the location using GeoClue, the location below is used if it is unavailable",
+                );
+            if self.automatic_location {
+                self.render_automatic_status(ui, app_state);
+            }
+            ui.add_space(SPACING);
+        }
+
         ui.with_layout(egui::Layout::left_to_right(egui::Align::LEFT), |ui| {
             ui.add(
                 egui::TextEdit::singleline(&mut self.search_query)
                     .hint_text("Enter a city or location name"),
             );
+            egui::ComboBox::from_id_source("geocoder")
+                .selected_text(self.geocoder.as_str())
+                .show_ui(ui, |ui| {
+                    for geocoder in enum_iterator::all::<Geocoder>() {
+                        ui.selectable_value(&mut self.geocoder, geocoder, geocoder.as_str());
+                    }
+                });
+            let hover_text = match self.geocoder {
+                Geocoder::Offline => "Search the built in city database",
+                Geocoder::OpenStreetMap => {
+                    "Search using OpenStreetMap, or the built in city database if it is unavailable"
+                }
+            };
             if ui
                 .add_enabled(!self.search_query.is_empty(), egui::Button::new("Search"))
-                .on_hover_text("Search using OpenStreetMap")
+                .on_hover_text(hover_text)
                 .clicked()
             {
-                on_search(app_state, self.search_query.clone());
+                on_search(app_state, self.geocoder, self.search_query.clone());
             }
         });
 
+        self.render_search_history(ui, app_state);
+
         ui.add_space(SPACING);
 
         let latitude_valid = self.is_latitude_valid();
@@ -105,6 +158,168 @@ impl Page for LocationSettingsPage {
                 .unwrap();
             save_config(&mut config, &app_state.transitions);
         }
+
+        ui.add_space(SPACING);
+        ui.separator();
+        ui.add_space(SPACING);
+
+        self.render_saved_locations(ui, app_state, save_enabled);
+    }
+}
+
+impl LocationSettingsPage {
+    fn render_search_history(&mut self, ui: &mut egui::Ui, app_state: &AppState) {
+        let config = app_state.config.read().unwrap();
+        if config.search_history.is_empty() {
+            return;
+        }
+        ui.add_space(SPACING);
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Recent:");
+            for result in &config.search_history {
+                if ui
+                    .small_button(result.display_name())
+                    .on_hover_text(format!(
+                        "{}, {}",
+                        coord_to_string(result.location.latitude),
+                        coord_to_string(result.location.longitude)
+                    ))
+                    .clicked()
+                {
+                    self.latitude = coord_to_string(result.location.latitude);
+                    self.longitude = coord_to_string(result.location.longitude);
+                }
+            }
+        });
+    }
+
+    fn render_saved_locations(
+        &mut self,
+        ui: &mut egui::Ui,
+        app_state: &mut AppState,
+        coordinates_valid: bool,
+    ) {
+        ui.heading("Saved Locations");
+        ui.add_space(SPACING);
+
+        let mut config = app_state.config.write().unwrap();
+        let mut selected = None;
+        let mut removed = None;
+        if config.saved_locations.is_empty() {
+            ui.label("Save the location above to quickly switch between places, e.g. from the tray menu.");
+        } else {
+            egui::Grid::new("saved_locations")
+                .striped(true)
+                .num_columns(4)
+                .show(ui, |ui| {
+                    for saved in &config.saved_locations {
+                        let name = egui::RichText::new(&saved.name);
+                        if config.active_location.as_ref() == Some(&saved.name) {
+                            ui.label(name.strong()).on_hover_text("In use");
+                        } else {
+                            ui.label(name);
+                        }
+                        ui.label(format!(
+                            "{}, {}",
+                            coord_to_string(saved.location.latitude),
+                            coord_to_string(saved.location.longitude)
+                        ));
+                        if ui.button("Use").clicked() {
+                            selected = Some(saved.name.clone());
+                        }
+                        if ui.button("Delete").clicked() {
+                            removed = Some(saved.name.clone());
+                        }
+                        ui.end_row();
+                    }
+                });
+        }
+
+        ui.add_space(SPACING);
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.new_location_name).hint_text("Name"));
+            let name = self.new_location_name.trim().to_owned();
+            if ui
+                .add_enabled(
+                    coordinates_valid && !name.is_empty(),
+                    egui::Button::new("Save Location"),
+                )
+                .on_hover_text("Save the coordinates above under this name")
+                .clicked()
+            {
+                config.save_location(name, self.location());
+                self.new_location_name.clear();
+                app_state
+                    .controller
+                    .send(Message::Refresh("Location change"))
+                    .unwrap();
+                save_config(&mut config, &app_state.transitions);
+            }
+        });
+
+        if let Some(name) = selected {
+            config.use_saved_location(&name).unwrap();
+            let location = config.location.unwrap();
+            self.latitude = coord_to_string(location.latitude);
+            self.longitude = coord_to_string(location.longitude);
+            app_state
+                .controller
+                .send(Message::Refresh("Location change"))
+                .unwrap();
+            save_config(&mut config, &app_state.transitions);
+        }
+        if let Some(name) = removed {
+            config.remove_saved_location(&name);
+            save_config(&mut config, &app_state.transitions);
+        }
+    }
+
+    fn render_automatic_status(&self, ui: &mut egui::Ui, app_state: &AppState) {
+        let results = app_state.results.read().unwrap();
+        let Some(results) = results.as_ref() else {
+            return;
+        };
+        match &results.location_source {
+            LocationSource::Automatic { .. } => {
+                ui.label(format!(
+                    "Using the location found by GeoClue ({}, {}).\n{}.",
+                    coord_to_string(results.location.latitude),
+                    coord_to_string(results.location.longitude),
+                    results.location_source.describe()
+                ));
+            }
+            _ if app_state.config.read().unwrap().automatic_location => {
+                ui.label(
+                    egui::RichText::new(
+                        "The automatic location is unavailable, using the location below.",
+                    )
+                    .color(egui::Color32::YELLOW),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn render_approximate_notice(&self, ui: &mut egui::Ui, app_state: &AppState) {
+        let results = app_state.results.read().unwrap();
+        let Some(results) = results.as_ref() else {
+            return;
+        };
+        if app_state.config.read().unwrap().location.is_some()
+            || !results.location_source.is_approximate()
+        {
+            return;
+        }
+        ui.label(
+            egui::RichText::new(format!(
+                "No location is configured, using an approximate location ({}, {}).\n{}.",
+                coord_to_string(results.location.latitude),
+                coord_to_string(results.location.longitude),
+                results.location_source.describe()
+            ))
+            .color(egui::Color32::YELLOW),
+        );
+        ui.add_space(SPACING);
     }
 }
 
@@ -124,7 +339,7 @@ impl Modal for SpinnerModal {
     }
 }
 
-fn on_search(app_state: &mut AppState, search_string: String) {
+fn on_search(app_state: &mut AppState, geocoder: Geocoder, search_string: String) {
     // Show the spinner modal
     app_state
         .transitions
@@ -132,26 +347,25 @@ fn on_search(app_state: &mut AppState, search_string: String) {
 
     // Start a background thread
     let transitions = app_state.transitions.clone();
+    let config = app_state.config.clone();
     let proxy = app_state.main_loop.clone();
     std::thread::spawn(move || {
-        let result = Openstreetmap::new()
-            .forward(&search_string)
-            .map_err(|x| match x {
-                GeocodingError::Request(r) => anyhow::Error::from(r),
-                _ => anyhow::Error::from(x),
-            });
-        match result.map(|e| e.into_iter().next()) {
-            Ok(None) => transitions.queue_state_transition(move |app| {
+        match search(geocoder, &search_string) {
+            Ok(results) if results.is_empty() => transitions.queue_state_transition(move |app| {
                 app.modal = Some(Box::new(MessageModal {
                     title: "No results".to_string(),
                     message: format!("No location could be found for '{}'", search_string),
                 }))
             }),
-            Ok(Some(p)) => {
+            Ok(mut results) if results.len() == 1 => {
+                use_search_result(&config, &transitions, results.remove(0));
+            }
+            Ok(results) => {
                 transitions.queue_state_transition(move |app| {
-                    app.location_settings_page.latitude = coord_to_string(p.y());
-                    app.location_settings_page.longitude = coord_to_string(p.x());
-                    app.modal = None;
+                    app.modal = Some(Box::new(SearchResultsModal {
+                        query: search_string,
+                        results,
+                    }));
                 });
             }
             Err(e) => {
@@ -171,3 +385,73 @@ fn on_search(app_state: &mut AppState, search_string: String) {
             .unwrap();
     });
 }
+
+/// Lets the user choose between the results when a search finds more than one location
+struct SearchResultsModal {
+    query: String,
+    results: Vec<SearchResult>,
+}
+
+impl Modal for SearchResultsModal {
+    fn render(&self, ctx: &Context, app_state: &mut AppState) {
+        egui::Window::new(format!("Results for '{}'", self.query))
+            .collapsible(false)
+            .resizable(true)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(SEARCH_RESULTS_MAX_HEIGHT)
+                    .show(ui, |ui| {
+                        egui::Grid::new("search_results")
+                            .striped(true)
+                            .num_columns(4)
+                            .show(ui, |ui| {
+                                ui.label("Name");
+                                ui.label("Country");
+                                ui.label("Coordinates");
+                                ui.end_row();
+
+                                for result in &self.results {
+                                    ui.label(&result.name);
+                                    ui.label(&result.country);
+                                    ui.label(format!(
+                                        "{}, {}",
+                                        coord_to_string(result.location.latitude),
+                                        coord_to_string(result.location.longitude)
+                                    ));
+                                    if ui.button("Select").clicked() {
+                                        use_search_result(
+                                            &app_state.config,
+                                            &app_state.transitions,
+                                            result.clone(),
+                                        );
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                ui.add_space(SPACING);
+                if ui.button("Cancel").clicked() {
+                    app_state.transitions.queue_state_transition(|app| {
+                        app.modal = None;
+                    });
+                };
+            });
+    }
+}
+
+/// Fill in the coordinates from the search result, and add it to the search history
+fn use_search_result(
+    config: &Arc<RwLock<SsbConfig>>,
+    transitions: &Transitions,
+    result: SearchResult,
+) {
+    let location = result.location;
+    let mut config = config.write().unwrap();
+    config.add_search_history(result);
+    save_config(&mut config, transitions);
+    transitions.queue_state_transition(move |app| {
+        app.location_settings_page.latitude = coord_to_string(location.latitude);
+        app.location_settings_page.longitude = coord_to_string(location.longitude);
+        app.modal = None;
+    });
+}
@@ -39,6 +39,8 @@ pub enum UserEvent {
     CloseWindow(&'static str),
     // Repaint now
     RepaintNow(&'static str),
+    // The config may have changed, so the tray menu should be updated
+    UpdateTray(&'static str),
 
     RequestRepaint {
         when: Instant,
@@ -1,14 +1,20 @@
 use crate::apply::ApplyResults;
-use crate::config::{BrightnessValues, MonitorOverride, MonitorProperty, SsbConfig};
+use crate::config::{
+    BrightnessValues, ColorTemperatureValues, MonitorOverride, MonitorProperty, OverrideSet,
+    SsbConfig, TransitionCurve,
+};
 use crate::controller::Message;
-use crate::gui::app::{save_config, set_red_widget_border, AppState, Page, SPACING};
+use crate::gui::app::{save_config, set_red_widget_border, AppState, MessageModal, Page, SPACING};
 use crate::gui::status::no_devices_found;
 use ellipse::Ellipse;
+use std::path::PathBuf;
 
-const MAX_OVERRIDES: usize = 10;
+/// The overrides list will scroll once it is taller than this
+const OVERRIDES_MAX_HEIGHT: f32 = 400.0;
 
 pub struct MonitorOverridePage {
     overrides: Vec<Override>,
+    transfer_path: String,
 }
 
 struct Override {
@@ -17,6 +23,9 @@ struct Override {
     disable: bool,
     day: u32,
     night: u32,
+    transition_mins: Option<u32>,
+    transition_curve: Option<TransitionCurve>,
+    color_temperature: Option<ColorTemperatureValues>,
 }
 
 impl Override {
@@ -25,6 +34,37 @@ impl Override {
     }
 }
 
+impl From<&MonitorOverride> for Override {
+    fn from(o: &MonitorOverride) -> Self {
+        Override {
+            key: o.key,
+            pattern: o.pattern.clone(),
+            disable: o.brightness.is_none(),
+            day: o.brightness.map(|b| b.brightness_day).unwrap_or(100),
+            night: o.brightness.map(|b| b.brightness_night).unwrap_or(60),
+            transition_mins: o.transition_mins,
+            transition_curve: o.transition_curve,
+            color_temperature: o.color_temperature,
+        }
+    }
+}
+
+impl From<&Override> for MonitorOverride {
+    fn from(o: &Override) -> Self {
+        MonitorOverride {
+            pattern: o.pattern.clone(),
+            key: o.key,
+            brightness: (!o.disable).then_some(BrightnessValues {
+                brightness_day: o.day,
+                brightness_night: o.night,
+            }),
+            transition_mins: o.transition_mins,
+            transition_curve: o.transition_curve,
+            color_temperature: o.color_temperature,
+        }
+    }
+}
+
 impl Page for MonitorOverridePage {
     fn render(&mut self, ui: &mut egui::Ui, app_state: &mut AppState) {
         {
@@ -42,33 +82,18 @@ impl Page for MonitorOverridePage {
 
 impl MonitorOverridePage {
     pub fn from_config(config: &SsbConfig) -> Self {
-        let overrides = config
-            .overrides
-            .iter()
-            .map(|o| Override {
-                key: o.key,
-                pattern: o.pattern.clone(),
-                disable: o.brightness.is_none(),
-                day: o.brightness.map(|b| b.brightness_day).unwrap_or(100),
-                night: o.brightness.map(|b| b.brightness_night).unwrap_or(60),
-            })
-            .collect();
-        Self { overrides }
+        let overrides = config.overrides.iter().map(Override::from).collect();
+        let transfer_path = dirs::home_dir()
+            .unwrap_or_default()
+            .join("ssb-overrides.json");
+        Self {
+            overrides,
+            transfer_path: transfer_path.display().to_string(),
+        }
     }
 
     fn copy_to_config(&self, config: &mut SsbConfig) {
-        config.overrides = self
-            .overrides
-            .iter()
-            .map(|o| MonitorOverride {
-                pattern: o.pattern.clone(),
-                key: o.key,
-                brightness: (!o.disable).then_some(BrightnessValues {
-                    brightness_day: o.day,
-                    brightness_night: o.night,
-                }),
-            })
-            .collect();
+        config.overrides = self.overrides.iter().map(MonitorOverride::from).collect();
     }
 
     fn is_valid(&self) -> bool {
@@ -129,103 +154,190 @@ impl MonitorOverridePage {
         ui.add_space(SPACING);
 
         let properties = enum_iterator::all::<MonitorProperty>().collect::<Vec<_>>();
+        let curves = enum_iterator::all::<TransitionCurve>().collect::<Vec<_>>();
 
         if !self.overrides.is_empty() {
-            egui::Grid::new("overrides_grid")
-                .striped(true)
-                .num_columns(8)
-                .min_col_width(0.0)
+            egui::ScrollArea::vertical()
+                .id_source("overrides_scroll")
+                .max_height(OVERRIDES_MAX_HEIGHT)
                 .show(ui, |ui| {
-                    ui.label("");
-                    ui.label("");
-                    ui.label("Property");
-                    ui.label("Pattern")
-                        .on_hover_text("You can use * as a wildcard match");
-                    ui.label("Disable")
-                        .on_hover_text("Disable automatic brightness");
-                    ui.label("Day");
-                    ui.label("Night");
-                    ui.label("");
-                    ui.end_row();
+                    egui::Grid::new("overrides_grid")
+                        .striped(true)
+                        .num_columns(if cfg!(target_os = "linux") { 11 } else { 10 })
+                        .min_col_width(0.0)
+                        .show(ui, |ui| {
+                            ui.label("");
+                            ui.label("");
+                            ui.label("Property");
+                            ui.label("Pattern")
+                                .on_hover_text("You can use * as a wildcard match");
+                            ui.label("Disable")
+                                .on_hover_text("Disable automatic brightness");
+                            ui.label("Day");
+                            ui.label("Night");
+                            ui.label("Transition")
+                                .on_hover_text("Override the transition minutes for this monitor");
+                            ui.label("Curve")
+                                .on_hover_text("Override the transition curve for this monitor");
+                            if cfg!(target_os = "linux") {
+                                ui.label("Colour").on_hover_text(
+                                    "Override the day and night colour temperature for this monitor",
+                                );
+                            }
+                            ui.label("");
+                            ui.end_row();
 
-                    let last_idx = self.overrides.len() - 1;
-                    for idx in 0..self.overrides.len() {
-                        if ui
-                            .add_enabled(idx != 0, egui::Button::new("⬆"))
-                            .on_hover_text("Move up")
-                            .clicked()
-                        {
-                            self.overrides.swap(idx, idx - 1);
-                        }
-                        if ui
-                            .add_enabled(idx != last_idx, egui::Button::new("⬇"))
-                            .on_hover_text("Move down")
-                            .clicked()
-                        {
-                            self.overrides.swap(idx, idx + 1);
-                        }
-                        let o = self.overrides.get_mut(idx).unwrap();
-                        egui::ComboBox::from_id_source(format!("override_key {}", idx))
-                            .selected_text(o.key.as_str())
-                            .show_ui(ui, |ui| {
-                                for property in &properties {
-                                    ui.selectable_value(&mut o.key, *property, property.as_str());
+                            let last_idx = self.overrides.len() - 1;
+                            for idx in 0..self.overrides.len() {
+                                if ui
+                                    .add_enabled(idx != 0, egui::Button::new("⬆"))
+                                    .on_hover_text("Move up")
+                                    .clicked()
+                                {
+                                    self.overrides.swap(idx, idx - 1);
                                 }
-                            });
+                                if ui
+                                    .add_enabled(idx != last_idx, egui::Button::new("⬇"))
+                                    .on_hover_text("Move down")
+                                    .clicked()
+                                {
+                                    self.overrides.swap(idx, idx + 1);
+                                }
+                                let o = self.overrides.get_mut(idx).unwrap();
+                                egui::ComboBox::from_id_source(format!("override_key {}", idx))
+                                    .selected_text(o.key.as_str())
+                                    .show_ui(ui, |ui| {
+                                        for property in &properties {
+                                            ui.selectable_value(
+                                                &mut o.key,
+                                                *property,
+                                                property.as_str(),
+                                            );
+                                        }
+                                    });
 
-                        ui.add_enabled_ui(true, |ui| {
-                            if o.pattern.is_empty() {
-                                set_red_widget_border(ui);
-                            }
-                            ui.add(
-                                egui::TextEdit::singleline(&mut o.pattern)
-                                    .min_size(egui::vec2(140.0, 0.0)),
-                            );
-                        });
+                                ui.add_enabled_ui(true, |ui| {
+                                    if o.pattern.is_empty() {
+                                        set_red_widget_border(ui);
+                                    }
+                                    ui.add(
+                                        egui::TextEdit::singleline(&mut o.pattern)
+                                            .min_size(egui::vec2(140.0, 0.0)),
+                                    );
+                                });
 
-                        ui.add(egui::Checkbox::without_text(&mut o.disable));
-
-                        if o.disable {
-                            ui.label("N/A");
-                            ui.label("N/A");
-                        } else {
-                            ui.add(
-                                egui::DragValue::new(&mut o.day)
-                                    .clamp_range(0u32..=100u32)
-                                    .suffix("%"),
-                            );
-                            ui.add(
-                                egui::DragValue::new(&mut o.night)
-                                    .clamp_range(0u32..=100u32)
-                                    .suffix("%"),
-                            );
-                        }
+                                ui.add(egui::Checkbox::without_text(&mut o.disable));
 
-                        if ui.button("❌").on_hover_text("Remove override").clicked() {
-                            self.overrides.remove(idx);
-                            return; // Important to avoid invalid index on next iteration
-                        };
+                                if o.disable {
+                                    (0..4).for_each(|_| {
+                                        ui.label("N/A");
+                                    });
+                                } else {
+                                    ui.add(
+                                        egui::DragValue::new(&mut o.day)
+                                            .clamp_range(0u32..=100u32)
+                                            .suffix("%"),
+                                    );
+                                    ui.add(
+                                        egui::DragValue::new(&mut o.night)
+                                            .clamp_range(0u32..=100u32)
+                                            .suffix("%"),
+                                    );
+                                    ui.horizontal(|ui| {
+                                        let mut custom = o.transition_mins.is_some();
+                                        if ui
+                                            .add(egui::Checkbox::without_text(&mut custom))
+                                            .on_hover_text("Use a custom transition time")
+                                            .changed()
+                                        {
+                                            o.transition_mins = custom.then_some(40);
+                                        }
+                                        if let Some(transition_mins) = o.transition_mins.as_mut() {
+                                            ui.add(
+                                                egui::DragValue::new(transition_mins)
+                                                    .clamp_range(0u32..=360u32)
+                                                    .suffix("min"),
+                                            );
+                                        } else {
+                                            ui.label("Default");
+                                        }
+                                    });
+                                    egui::ComboBox::from_id_source(format!(
+                                        "override_curve {}",
+                                        idx
+                                    ))
+                                    .selected_text(
+                                        o.transition_curve.map_or("Default", |c| c.as_str()),
+                                    )
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(
+                                            &mut o.transition_curve,
+                                            None,
+                                            "Default",
+                                        );
+                                        for curve in &curves {
+                                            ui.selectable_value(
+                                                &mut o.transition_curve,
+                                                Some(*curve),
+                                                curve.as_str(),
+                                            );
+                                        }
+                                    });
+                                }
 
-                        ui.end_row();
-                    }
+                                if cfg!(target_os = "linux") {
+                                    ui.horizontal(|ui| {
+                                        let mut custom = o.color_temperature.is_some();
+                                        if ui
+                                            .add(egui::Checkbox::without_text(&mut custom))
+                                            .on_hover_text("Use a custom colour temperature")
+                                            .changed()
+                                        {
+                                            o.color_temperature =
+                                                custom.then(ColorTemperatureValues::default);
+                                        }
+                                        if let Some(values) = o.color_temperature.as_mut() {
+                                            ui.add(
+                                                egui::DragValue::new(&mut values.temperature_day)
+                                                    .clamp_range(1000u32..=10000u32)
+                                                    .speed(10)
+                                                    .suffix("K"),
+                                            );
+                                            ui.add(
+                                                egui::DragValue::new(&mut values.temperature_night)
+                                                    .clamp_range(1000u32..=10000u32)
+                                                    .speed(10)
+                                                    .suffix("K"),
+                                            );
+                                        } else {
+                                            ui.label("Default");
+                                        }
+                                    });
+                                }
+
+                                if ui.button("❌").on_hover_text("Remove override").clicked() {
+                                    self.overrides.remove(idx);
+                                    return; // Important to avoid invalid index on next iteration
+                                };
+
+                                ui.end_row();
+                            }
+                        });
                 });
             ui.add_space(SPACING);
         }
 
         ui.horizontal(|ui| {
-            if ui
-                .add_enabled(
-                    self.overrides.len() < MAX_OVERRIDES,
-                    egui::Button::new("Create new override"),
-                )
-                .clicked()
-            {
+            if ui.button("Create new override").clicked() {
                 self.overrides.push(Override {
                     key: MonitorProperty::DeviceName,
                     pattern: "".to_string(),
                     disable: false,
                     day: 100,
                     night: 60,
+                    transition_mins: None,
+                    transition_curve: None,
+                    color_temperature: None,
                 })
             }
             if ui
@@ -243,5 +355,99 @@ impl MonitorOverridePage {
         });
 
         ui.add_space(SPACING);
+        ui.separator();
+        ui.add_space(SPACING);
+        self.render_import_export(ui, app_state);
+        ui.add_space(SPACING);
+    }
+
+    fn render_import_export(&mut self, ui: &mut egui::Ui, app_state: &mut AppState) {
+        ui.label(egui::RichText::new("Import / Export").size(14.0));
+        ui.add_space(SPACING);
+        ui.label("Share a set of overrides with other machines using an overrides file.");
+        ui.add_space(SPACING);
+
+        ui.horizontal(|ui| {
+            ui.label("File");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.transfer_path)
+                    .min_size(egui::vec2(300.0, 0.0)),
+            );
+        });
+        ui.add_space(SPACING);
+
+        let path_entered = !self.transfer_path.is_empty();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(path_entered, egui::Button::new("Import"))
+                .on_hover_text("Append the overrides from the file to the list above")
+                .clicked()
+            {
+                let path = PathBuf::from(&self.transfer_path);
+                match OverrideSet::load(&path) {
+                    Ok(set) => {
+                        log::info!(
+                            "Imported {} overrides from {}",
+                            set.overrides.len(),
+                            path.display()
+                        );
+                        self.overrides
+                            .extend(set.overrides.iter().map(Override::from));
+                        show_message(
+                            app_state,
+                            "Imported",
+                            format!(
+                                "Imported {} overrides, click Save to apply them",
+                                set.overrides.len()
+                            ),
+                        );
+                    }
+                    Err(e) => {
+                        log::error!("Unable to import overrides: {:#}", e);
+                        show_message(
+                            app_state,
+                            "Error",
+                            format!("Unable to import overrides: {:#}", e),
+                        );
+                    }
+                }
+            }
+            if ui
+                .add_enabled(path_entered && self.is_valid(), egui::Button::new("Export"))
+                .on_hover_text("Write the overrides in the list above to the file")
+                .clicked()
+            {
+                let path = PathBuf::from(&self.transfer_path);
+                let set = OverrideSet {
+                    overrides: self.overrides.iter().map(MonitorOverride::from).collect(),
+                };
+                match set.save(&path) {
+                    Ok(_) => show_message(
+                        app_state,
+                        "Exported",
+                        format!(
+                            "Exported {} overrides to {}",
+                            set.overrides.len(),
+                            path.display()
+                        ),
+                    ),
+                    Err(e) => {
+                        log::error!("Unable to export overrides: {:#}", e);
+                        show_message(
+                            app_state,
+                            "Error",
+                            format!("Unable to export overrides: {:#}", e),
+                        );
+                    }
+                }
+            }
+        });
     }
 }
+
+fn show_message(app_state: &AppState, title: &str, message: String) {
+    let title = title.to_string();
+    app_state.transitions.queue_state_transition(move |app| {
+        app.modal = Some(Box::new(MessageModal { title, message }));
+    });
+}
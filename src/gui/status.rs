@@ -1,7 +1,11 @@
-use crate::apply::ApplyResults;
+use crate::apply::{ApplyResults, WriteDetails};
+use crate::calculator::percent_to_level;
 use crate::gui::app::{AppState, Page, SPACING};
 use chrono::{Local, TimeZone};
 
+/// Writes taking longer than this are highlighted
+const SLOW_WRITE_MS: u64 = 1000;
+
 pub struct StatusPage;
 
 impl Page for StatusPage {
@@ -51,6 +55,48 @@ fn display_apply_results(results: &ApplyResults, ui: &mut egui::Ui) {
                 ui.label(sunrise);
                 ui.end_row();
             }
+            ui.label("Phase");
+            let phase_end = Local
+                .timestamp_opt(results.phase.expiry_time, 0)
+                .unwrap()
+                .format(date_format);
+            ui.label(format!(
+                "{} (until {})",
+                results.phase.phase.name(),
+                phase_end
+            ));
+            ui.end_row();
+            ui.label("Location");
+            let coordinates = format!(
+                "{:.5}, {:.5}",
+                results.location.latitude, results.location.longitude
+            );
+            if results.location_source.is_approximate() {
+                ui.label(
+                    egui::RichText::new(format!("{} (approximate)", coordinates))
+                        .color(egui::Color32::YELLOW),
+                )
+                .on_hover_text(format!(
+                    "{}\nConfigure a location for accurate sunrise and sunset times",
+                    results.location_source.describe()
+                ));
+            } else {
+                ui.label(coordinates)
+                    .on_hover_text(results.location_source.describe());
+            }
+            ui.end_row();
+            if let Some(ambient) = results.ambient_brightness {
+                ui.label("Ambient Light")
+                    .on_hover_text("Brightness measured by the ambient light sensor");
+                ui.label(format!("{}%", ambient));
+                ui.end_row();
+            }
+            if let Some(cloud_cover) = results.cloud_cover {
+                ui.label("Cloud Cover")
+                    .on_hover_text("The day brightness is reduced when it is cloudy");
+                ui.label(format!("{:.0}%", cloud_cover));
+                ui.end_row();
+            }
         });
 
     ui.add_space(SPACING);
@@ -59,9 +105,28 @@ fn display_apply_results(results: &ApplyResults, ui: &mut egui::Ui) {
 
     if results.monitors.is_empty() {
         ui.add(no_devices_found());
-        return;
+    } else {
+        display_monitors(results, ui);
+    }
+
+    if !results.leds.is_empty() {
+        ui.add_space(SPACING);
+        ui.separator();
+        ui.add_space(SPACING);
+        ui.heading("LED Devices");
+        display_leds(results, ui);
+    }
+
+    if !results.color_temperature.is_empty() {
+        ui.add_space(SPACING);
+        ui.separator();
+        ui.add_space(SPACING);
+        ui.heading("Colour Temperature");
+        display_color_temperature(results, ui);
     }
+}
 
+fn display_monitors(results: &ApplyResults, ui: &mut egui::Ui) {
     egui::Grid::new("monitors_grid")
         .striped(true)
         .num_columns(5)
@@ -79,19 +144,42 @@ fn display_apply_results(results: &ApplyResults, ui: &mut egui::Ui) {
             ui.end_row();
 
             results.monitors.iter().for_each(|monitor| {
-                ui.label(&monitor.properties.device_name);
+                if monitor.software_dimming {
+                    ui.label(format!("{} (software dimming)", monitor.properties.device_name))
+                        .on_hover_text(
+                            "This output has no backlight control, so it is dimmed using its gamma table",
+                        );
+                } else {
+                    ui.label(&monitor.properties.device_name);
+                }
                 if let Some(brightness) = &monitor.brightness {
                     ui.label(format!("{}%", brightness.brightness_day));
                     ui.label(format!("{}%", brightness.brightness_night));
                     ui.label(format!("{}%", brightness.brightness));
 
-                    match &monitor.error {
-                        None => ui
-                            .label("Ok")
-                            .on_hover_text("Brightness was applied successfully"),
-                        Some(e) => ui
+                    match (&monitor.error, &monitor.write) {
+                        (None, None) if monitor.unchanged => ui.label("Unchanged").on_hover_text(
+                            "The monitor was already set to this brightness, so it was not updated",
+                        ),
+                        (Some(e), write) => ui
                             .label(egui::RichText::new("Error").color(egui::Color32::RED))
-                            .on_hover_text(e),
+                            .on_hover_text(match write {
+                                Some(w) => format!("{}\n{}", e, describe_write(w)),
+                                None => e.clone(),
+                            }),
+                        (None, Some(w)) if w.duration_ms >= SLOW_WRITE_MS => ui
+                            .label(egui::RichText::new("Slow").color(egui::Color32::YELLOW))
+                            .on_hover_text(format!(
+                                "Brightness was applied successfully, but the monitor was slow to respond\n{}",
+                                describe_write(w)
+                            )),
+                        (None, Some(w)) => ui.label("Ok").on_hover_text(format!(
+                            "Brightness was applied successfully\n{}",
+                            describe_write(w)
+                        )),
+                        (None, None) => ui
+                            .label("Ok")
+                            .on_hover_text("Brightness is being faded to the new value"),
                     };
 
                     match brightness.expiry_time {
@@ -115,6 +203,107 @@ fn display_apply_results(results: &ApplyResults, ui: &mut egui::Ui) {
         });
 }
 
+fn display_leds(results: &ApplyResults, ui: &mut egui::Ui) {
+    egui::Grid::new("leds_grid")
+        .striped(true)
+        .num_columns(5)
+        .show(ui, |ui| {
+            ui.label("Name");
+            ui.label("Day")
+                .on_hover_text("Configured day time brightness");
+            ui.label("Night")
+                .on_hover_text("Configured night time brightness");
+            ui.label("Now").on_hover_text(
+                "The computed brightness, rounded to the nearest level the device supports",
+            );
+            ui.label("Status");
+            ui.end_row();
+
+            results.leds.iter().for_each(|led| {
+                ui.label(&led.name);
+                if let Some(brightness) = &led.brightness {
+                    ui.label(format!("{}%", brightness.brightness_day));
+                    ui.label(format!("{}%", brightness.brightness_night));
+                    ui.label(format!("{}%", brightness.brightness))
+                        .on_hover_text(format!(
+                            "Level {} of {}",
+                            percent_to_level(brightness.brightness, led.max_brightness),
+                            led.max_brightness
+                        ));
+                    match &led.error {
+                        Some(e) => ui
+                            .label(egui::RichText::new("Error").color(egui::Color32::RED))
+                            .on_hover_text(e),
+                        None if led.unchanged => ui.label("Unchanged").on_hover_text(
+                            "The device was already set to this brightness, so it was not updated",
+                        ),
+                        None => ui.label("Ok"),
+                    };
+                } else {
+                    (0..3).for_each(|_| {
+                        ui.label("N/A");
+                    });
+                    ui.label("Disabled")
+                        .on_hover_text("Dynamic brightness is disabled due to an LED override");
+                }
+                ui.end_row();
+            });
+        });
+}
+
+fn display_color_temperature(results: &ApplyResults, ui: &mut egui::Ui) {
+    egui::Grid::new("color_temperature_grid")
+        .striped(true)
+        .num_columns(6)
+        .show(ui, |ui| {
+            ui.label("Output");
+            ui.label("Day")
+                .on_hover_text("Configured day time colour temperature");
+            ui.label("Night")
+                .on_hover_text("Configured night time colour temperature");
+            ui.label("Now")
+                .on_hover_text("The computed colour temperature for this output");
+            ui.label("Status");
+            ui.label("Next update")
+                .on_hover_text("Time that the colour temperature will be changed");
+            ui.end_row();
+
+            results.color_temperature.iter().for_each(|output| {
+                ui.label(&output.output);
+                ui.label(format!("{}K", output.temperature_day));
+                ui.label(format!("{}K", output.temperature_night));
+                ui.label(format!("{}K", output.temperature));
+                match &output.error {
+                    Some(e) => ui
+                        .label(egui::RichText::new("Error").color(egui::Color32::RED))
+                        .on_hover_text(e),
+                    None if output.unchanged => ui.label("Unchanged").on_hover_text(
+                        "The output was already set to this colour temperature, so it was not updated",
+                    ),
+                    None => ui.label("Ok"),
+                };
+                match output.expiry_time {
+                    None => ui.label("Never"),
+                    Some(expiry_time) => {
+                        let changes_at = Local.timestamp_opt(expiry_time, 0).unwrap();
+                        ui.label(changes_at.format("%H:%M %P").to_string())
+                            .on_hover_text(changes_at.format("%b %d").to_string())
+                    }
+                };
+                ui.end_row();
+            });
+        });
+}
+
+fn describe_write(write: &WriteDetails) -> String {
+    format!(
+        "Took {}ms ({} attempt{})",
+        write.duration_ms,
+        write.attempts,
+        if write.attempts == 1 { "" } else { "s" }
+    )
+}
+
 pub fn no_devices_found() -> egui::Label {
     egui::Label::new(egui::RichText::new("No devices found").color(egui::Color32::RED))
 }
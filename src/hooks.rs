@@ -0,0 +1,275 @@
+//! Runs user configured commands when the brightness or state changes, e.g. to switch a terminal
+//! theme or send a notification
+
+use crate::apply::ApplyResults;
+use crate::config::{Hook, HookEvent};
+use anyhow::{anyhow, Context};
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// An event, and the environment variables describing it
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub kind: HookEvent,
+    pub env: Vec<(&'static str, String)>,
+}
+
+impl Event {
+    fn new(kind: HookEvent) -> Self {
+        Self { kind, env: vec![] }
+    }
+
+    fn with(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.env.push((key, value.to_string()));
+        self
+    }
+}
+
+fn event_name(kind: HookEvent) -> &'static str {
+    match kind {
+        HookEvent::BrightnessChanged => "brightness_changed",
+        HookEvent::PhaseChanged => "phase_changed",
+        HookEvent::Paused => "paused",
+        HookEvent::Resumed => "resumed",
+    }
+}
+
+/// The events caused by an update, by comparing the results with the previous results
+pub fn update_events(
+    previous: &Option<ApplyResults>,
+    current: &Option<ApplyResults>,
+) -> Vec<Event> {
+    let Some(current) = current else {
+        return vec![];
+    };
+    let mut events = vec![];
+    let previous_brightness = previous
+        .iter()
+        .flat_map(|p| &p.monitors)
+        .filter_map(|m| Some((&m.properties.device_name, m.brightness.as_ref()?.brightness)))
+        .collect::<HashMap<_, _>>();
+    for monitor in &current.monitors {
+        let Some(brightness) = &monitor.brightness else {
+            continue;
+        };
+        let name = &monitor.properties.device_name;
+        let old = previous_brightness.get(name).copied();
+        if old != Some(brightness.brightness) {
+            let old = old.map(|b| b.to_string()).unwrap_or_default();
+            events.push(
+                Event::new(HookEvent::BrightnessChanged)
+                    .with("SSB_MONITOR", name)
+                    .with("SSB_OLD_BRIGHTNESS", old)
+                    .with("SSB_NEW_BRIGHTNESS", brightness.brightness),
+            );
+        }
+    }
+    // The phase is unknown until the first update
+    if let Some(previous) = previous {
+        if previous.phase.phase != current.phase.phase {
+            events.push(Event::new(HookEvent::PhaseChanged));
+        }
+    }
+    // Every event describes the current phase
+    for event in &mut events {
+        event.env.extend([
+            ("SSB_PHASE", current.phase.phase.name().to_owned()),
+            ("SSB_SUNRISE", current.sun.rise.to_string()),
+            ("SSB_SUNSET", current.sun.set.to_string()),
+        ]);
+    }
+    events
+}
+
+/// The event caused by the controller being paused or resumed
+pub fn enabled_event(enabled: bool) -> Event {
+    Event::new(if enabled {
+        HookEvent::Resumed
+    } else {
+        HookEvent::Paused
+    })
+}
+
+/// Runs the hooks on its own thread, one at a time, so that they don't block the controller
+pub struct HookRunner {
+    sender: Option<mpsc::Sender<(Vec<Hook>, Event)>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl HookRunner {
+    pub fn start() -> Self {
+        let (sender, receiver) = mpsc::channel::<(Vec<Hook>, Event)>();
+        let join_handle = std::thread::spawn(move || {
+            for (hooks, event) in receiver {
+                for hook in hooks.iter().filter(|h| h.events.contains(&event.kind)) {
+                    run_hook(hook, &event);
+                }
+            }
+            log::debug!("Hook runner thread exiting");
+        });
+        Self {
+            sender: Some(sender),
+            join_handle: Some(join_handle),
+        }
+    }
+
+    /// Run each of the hooks that are subscribed to these events
+    pub fn trigger(&self, hooks: &[Hook], events: Vec<Event>) {
+        if hooks.is_empty() {
+            return;
+        }
+        for event in events {
+            self.sender
+                .as_ref()
+                .unwrap()
+                .send((hooks.to_vec(), event))
+                .unwrap();
+        }
+    }
+}
+
+impl Drop for HookRunner {
+    fn drop(&mut self) {
+        log::info!("Stopping hook runner");
+        drop(self.sender.take());
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn run_hook(hook: &Hook, event: &Event) {
+    let name = event_name(event.kind);
+    let timeout = Duration::from_secs(hook.timeout_secs as u64);
+    let mut env = event.env.clone();
+    env.push(("SSB_EVENT", name.to_owned()));
+    log::info!("Running {name} hook {:?}", hook.command);
+    match run_command(&hook.command, &env, timeout) {
+        Ok(output) => {
+            for line in output.stdout.lines() {
+                log::info!("Hook output: {line}");
+            }
+            for line in output.stderr.lines() {
+                log::warn!("Hook error output: {line}");
+            }
+            if !output.status.success() {
+                log::error!("{name} hook {:?} failed ({})", hook.command, output.status);
+            }
+        }
+        Err(e) => log::error!("Unable to run {name} hook {:?}: {:#}", hook.command, e),
+    }
+}
+
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run a command with these extra environment variables, killing it after the timeout
+pub fn run_command(
+    command: &[String],
+    env: &[(&str, String)],
+    timeout: Duration,
+) -> anyhow::Result<CommandOutput> {
+    let (program, args) = command.split_first().context("Command is empty")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Unable to run '{program}'"))?;
+    // Read the output while waiting, so the command can't block on a full pipe
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut buffer = vec![];
+            pipe.read_to_end(&mut buffer).ok();
+            String::from_utf8_lossy(&buffer).into_owned()
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().unwrap()));
+    let stderr = read(Box::new(child.stderr.take().unwrap()));
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!(
+                "'{program}' timed out after {}s",
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    Ok(CommandOutput {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::Phase;
+
+    fn results(brightness: &[(&str, u32)], phase: Phase) -> Option<ApplyResults> {
+        let mut results = ApplyResults::for_test(brightness);
+        results.phase.phase = phase;
+        results.sun.visible = phase != Phase::Night;
+        Some(results)
+    }
+
+    #[test]
+    fn test_update_events() {
+        let first = results(&[("a", 80), ("b", 60)], Phase::Day);
+        let events = update_events(&None, &first);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0],
+            Event::new(HookEvent::BrightnessChanged)
+                .with("SSB_MONITOR", "a")
+                .with("SSB_OLD_BRIGHTNESS", "")
+                .with("SSB_NEW_BRIGHTNESS", 80)
+                .with("SSB_PHASE", "day")
+                .with("SSB_SUNRISE", 1689950000)
+                .with("SSB_SUNSET", 1690000000)
+        );
+
+        assert!(update_events(&first, &first).is_empty());
+
+        let second = results(&[("a", 70), ("b", 60)], Phase::Dusk);
+        let events = update_events(&first, &second);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].env[2], ("SSB_NEW_BRIGHTNESS", "70".to_owned()));
+        assert_eq!(events[0].env[1], ("SSB_OLD_BRIGHTNESS", "80".to_owned()));
+        assert_eq!(events[1].kind, HookEvent::PhaseChanged);
+        assert_eq!(events[1].env[0], ("SSB_PHASE", "dusk".to_owned()));
+
+        assert!(update_events(&second, &None).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_command() {
+        let command =
+            ["sh", "-c", "echo $SSB_MONITOR; echo oops >&2; exit 3"].map(|s| s.to_owned());
+        let env = [("SSB_MONITOR", "eDP-1".to_owned())];
+        let output = run_command(&command, &env, Duration::from_secs(10)).unwrap();
+        assert_eq!(output.stdout, "eDP-1\n");
+        assert_eq!(output.stderr, "oops\n");
+        assert_eq!(output.status.code(), Some(3));
+
+        let command = ["sleep", "10"].map(|s| s.to_owned());
+        let start = Instant::now();
+        assert!(run_command(&command, &[], Duration::from_secs(1)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
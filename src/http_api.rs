@@ -0,0 +1,476 @@
+//! A REST API served on localhost, so that dashboards and scripts can see and control the
+//! brightness. Every request must include the token from the token file as a bearer token.
+
+use crate::apply::{preview_schedule, ApplyResults};
+use crate::config::SsbConfig;
+use crate::controller::{BrightnessController, ControllerStatus, Message};
+use crate::location::resolve_location;
+use crate::metrics::Metrics;
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
+use validator::Validate;
+
+const TOKEN_FILE_NAME: &str = "api_token";
+
+/// Replaces secrets in the config that is returned by the API
+const REDACTED: &str = "<redacted>";
+
+/// How long to wait for a request before checking whether the config has changed
+const REQUEST_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to check whether the API has been enabled in the config
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Request bodies larger than this are truncated
+const MAX_BODY_LENGTH: u64 = 64 * 1024;
+
+/// The schedule preview covers the next 24 hours in 15 minute steps
+const SCHEDULE_DURATION_SECS: i64 = 24 * 60 * 60;
+const SCHEDULE_STEP_SECS: i64 = 15 * 60;
+
+/// The path of the token file, which is kept alongside the config file
+pub fn token_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(TOKEN_FILE_NAME)
+}
+
+/// Read the token from the file, or generate a new token if the file doesn't exist
+pub fn load_or_create_token(path: &Path) -> anyhow::Result<String> {
+    if path.exists() {
+        let token = fs::read_to_string(path)
+            .context(format!("Unable to read file '{}'", path.display()))?;
+        let token = token.trim();
+        anyhow::ensure!(
+            !token.is_empty(),
+            "Token file '{}' is empty",
+            path.display()
+        );
+        return Ok(token.to_owned());
+    }
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).context("Unable to generate a token")?;
+    let token = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        // Only the user should be able to read the token
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .context(format!("Unable to write file '{}'", path.display()))?;
+    log::info!("Created API token file '{}'", path.display());
+    Ok(token)
+}
+
+/// Compares in constant time, so the token can't be guessed from the response times
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[derive(Deserialize, Validate)]
+struct SetBrightness {
+    /// Null to go back to the calculated brightness
+    #[validate(range(max = 100))]
+    brightness: Option<u32>,
+}
+
+struct Api {
+    config: Arc<RwLock<SsbConfig>>,
+    controller: mpsc::Sender<Message>,
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    enabled: Arc<AtomicBool>,
+    metrics: Arc<Mutex<Metrics>>,
+    token: String,
+}
+
+enum Body {
+    Empty,
+    Json(Value),
+    /// The Prometheus text exposition format
+    Metrics(String),
+}
+
+fn error(status: u16, message: &str) -> (u16, Body) {
+    (status, Body::Json(json!({ "error": message })))
+}
+
+impl Api {
+    /// Returns the status code and the body
+    fn handle(
+        &self,
+        method: &Method,
+        url: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> (u16, Body) {
+        let authorised = authorization
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .map(|token| tokens_match(&self.token, token.trim()))
+            .unwrap_or(false);
+        if !authorised {
+            return error(401, "A valid bearer token is required");
+        }
+        let path = url.split('?').next().unwrap();
+        match (method, path) {
+            (Method::Get, "/api/status") => {
+                let results = self.last_result.read().unwrap();
+                let status = ControllerStatus {
+                    enabled: self.enabled.load(Ordering::SeqCst),
+                    results: &results,
+                };
+                (200, Body::Json(serde_json::to_value(status).unwrap()))
+            }
+            (Method::Get, "/api/config") => {
+                let mut config = self.config.read().unwrap().clone();
+                // The token only grants access to the API, not to the other services
+                if let Some(password) = config.mqtt.as_mut().and_then(|m| m.password.as_mut()) {
+                    *password = REDACTED.to_owned();
+                }
+                (200, Body::Json(serde_json::to_value(&config).unwrap()))
+            }
+            (Method::Get, "/api/schedule") => self.schedule(),
+            (Method::Get, "/metrics") if self.metrics_enabled() => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as i64;
+                let metrics = self.metrics.lock().unwrap().render(now);
+                (200, Body::Metrics(metrics))
+            }
+            (Method::Post, "/api/refresh") => self.send(Message::Refresh("HTTP API Refresh")),
+            (Method::Post, "/api/pause") => self.send(Message::Disable("HTTP API Pause")),
+            (Method::Post, "/api/resume") => self.send(Message::Enable("HTTP API Resume")),
+            (Method::Post, "/api/brightness") => {
+                let request = match serde_json::from_str::<SetBrightness>(body) {
+                    Ok(request) => request,
+                    Err(e) => return error(400, &e.to_string()),
+                };
+                if let Err(e) = request.validate() {
+                    return error(400, &e.to_string());
+                }
+                self.send(Message::ManualBrightness(request.brightness))
+            }
+            (
+                _,
+                "/api/status" | "/api/config" | "/api/schedule" | "/api/refresh" | "/api/pause"
+                | "/api/resume" | "/api/brightness",
+            ) => error(405, "Method not allowed"),
+            _ => error(404, "Not found"),
+        }
+    }
+
+    fn metrics_enabled(&self) -> bool {
+        let config = self.config.read().unwrap();
+        config.http_api.as_ref().map(|s| s.metrics).unwrap_or(false)
+    }
+
+    fn send(&self, message: Message) -> (u16, Body) {
+        self.controller.send(message).unwrap();
+        (204, Body::Empty)
+    }
+
+    fn schedule(&self) -> (u16, Body) {
+        let config = self.config.read().unwrap();
+        let results = self.last_result.read().unwrap();
+        // Prefer the location that was last used, as it may have been found automatically
+        let location = match results.as_ref() {
+            Some(results) => results.location,
+            None => match resolve_location(&config, None) {
+                Some((location, _)) => location,
+                None => return error(409, "Location is not configured"),
+            },
+        };
+        let monitors = results
+            .iter()
+            .flat_map(|r| &r.monitors)
+            .map(|m| &m.properties)
+            .collect::<Vec<_>>();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let schedule = preview_schedule(
+            &config,
+            location,
+            &monitors,
+            now,
+            SCHEDULE_DURATION_SECS,
+            SCHEDULE_STEP_SECS,
+        );
+        (200, Body::Json(serde_json::to_value(schedule).unwrap()))
+    }
+
+    fn respond(&self, mut request: Request) {
+        let mut body = String::new();
+        let read = request
+            .as_reader()
+            .take(MAX_BODY_LENGTH)
+            .read_to_string(&mut body);
+        let (status, body) = match read {
+            Ok(_) => {
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Authorization"))
+                    .map(|h| h.value.as_str());
+                self.handle(request.method(), request.url(), authorization, &body)
+            }
+            Err(_) => error(400, "Request body must be UTF-8"),
+        };
+        log::debug!(
+            "HTTP API {} {} -> {}",
+            request.method(),
+            request.url(),
+            status
+        );
+        let (body, content_type) = match body {
+            Body::Empty => (String::new(), None),
+            Body::Json(json) => (json.to_string(), Some("application/json")),
+            Body::Metrics(text) => (text, Some("text/plain; version=0.0.4")),
+        };
+        let mut response = Response::from_string(body);
+        if let Some(content_type) = content_type {
+            let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes());
+            response.add_header(header.unwrap());
+        }
+        if let Err(e) = request.respond(response.with_status_code(status)) {
+            log::warn!("Unable to send HTTP API response: {e}");
+        }
+    }
+}
+
+/// Serves the API on its own thread while it is enabled in the config
+pub struct HttpApiServer {
+    stop: Option<mpsc::Sender<()>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl HttpApiServer {
+    pub fn start(
+        config: Arc<RwLock<SsbConfig>>,
+        config_path: PathBuf,
+        controller: &BrightnessController,
+    ) -> Self {
+        let api = Api {
+            config,
+            controller: controller.sender.clone(),
+            last_result: controller.last_result.clone(),
+            enabled: controller.enabled.clone(),
+            metrics: controller.metrics.clone(),
+            token: String::new(),
+        };
+        let (stop, stop_rx) = mpsc::channel();
+        let join_handle = std::thread::spawn(move || run(api, token_path(&config_path), stop_rx));
+        Self {
+            stop: Some(stop),
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for HttpApiServer {
+    fn drop(&mut self) {
+        log::info!("Stopping HTTP API server");
+        drop(self.stop.take());
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn bind(port: u16, token_path: &Path) -> anyhow::Result<(Server, String)> {
+    let token = load_or_create_token(token_path)?;
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|e| anyhow::anyhow!(e))
+        .context(format!("Unable to listen on port {port}"))?;
+    log::info!("Serving HTTP API on http://127.0.0.1:{port}");
+    Ok((server, token))
+}
+
+fn run(mut api: Api, token_path: PathBuf, stop: mpsc::Receiver<()>) {
+    // The port that was last configured, the server is None if it failed to start
+    let mut current: Option<(u16, Option<Server>)> = None;
+    loop {
+        let port = api.config.read().unwrap().http_api.as_ref().map(|s| s.port);
+        if current.as_ref().map(|(p, _)| *p) != port {
+            if current.take().is_some() {
+                log::info!("Stopped serving HTTP API");
+            }
+            current = port.map(|port| match bind(port, &token_path) {
+                Ok((server, token)) => {
+                    api.token = token;
+                    (port, Some(server))
+                }
+                Err(e) => {
+                    log::error!("Unable to start HTTP API: {:#}", e);
+                    (port, None)
+                }
+            });
+        }
+        let wait = match current.as_ref().and_then(|(_, s)| s.as_ref()) {
+            Some(server) => {
+                match server.recv_timeout(REQUEST_POLL_INTERVAL) {
+                    Ok(Some(request)) => api.respond(request),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Unable to receive HTTP API request: {e}"),
+                }
+                Duration::ZERO
+            }
+            None => CONFIG_POLL_INTERVAL,
+        };
+        match stop.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => break,
+        }
+    }
+    log::debug!("HTTP API thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HttpApiSettings, Location, MqttSettings};
+
+    fn api() -> (Api, mpsc::Receiver<Message>) {
+        let config = SsbConfig {
+            location: Some(Location {
+                latitude: 51.5,
+                longitude: -0.12,
+            }),
+            ..SsbConfig::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let api = Api {
+            config: Arc::new(RwLock::new(config)),
+            controller: tx,
+            last_result: Arc::new(RwLock::new(None)),
+            enabled: Arc::new(AtomicBool::new(true)),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            token: "secret".to_owned(),
+        };
+        (api, rx)
+    }
+
+    fn json(body: Body) -> Value {
+        match body {
+            Body::Json(json) => json,
+            _ => panic!("Expected a JSON body"),
+        }
+    }
+
+    #[test]
+    fn test_authorisation() {
+        let (api, _rx) = api();
+        assert_eq!(api.handle(&Method::Get, "/api/status", None, "").0, 401);
+        let wrong = Some("Bearer secreT");
+        assert_eq!(api.handle(&Method::Get, "/api/status", wrong, "").0, 401);
+        let basic = Some("Basic secret");
+        assert_eq!(api.handle(&Method::Get, "/api/status", basic, "").0, 401);
+        let (status, body) = api.handle(&Method::Get, "/api/status", Some("Bearer secret"), "");
+        assert_eq!(status, 200);
+        assert_eq!(json(body), json!({"enabled": true, "results": null}));
+    }
+
+    #[test]
+    fn test_requests() {
+        let (api, rx) = api();
+        let auth = Some("Bearer secret");
+
+        let (status, body) = api.handle(&Method::Get, "/api/config", auth, "");
+        assert_eq!(status, 200);
+        assert_eq!(json(body)["location"]["latitude"], 51.5);
+        api.config.write().unwrap().mqtt = Some(MqttSettings {
+            password: Some("hunter2".to_owned()),
+            ..MqttSettings::default()
+        });
+        let (_, body) = api.handle(&Method::Get, "/api/config", auth, "");
+        assert_eq!(json(body)["mqtt"]["password"], "<redacted>");
+        let mqtt = api.config.read().unwrap().mqtt.clone().unwrap();
+        assert_eq!(mqtt.password.as_deref(), Some("hunter2"));
+
+        let (status, body) = api.handle(&Method::Get, "/api/schedule?x=1", auth, "");
+        assert_eq!(status, 200);
+        let body = json(body);
+        assert_eq!(body["default"].as_array().unwrap().len(), 97);
+        assert_eq!(body["monitors"], json!([]));
+
+        assert_eq!(api.handle(&Method::Post, "/api/pause", auth, "").0, 204);
+        assert_eq!(rx.try_recv().unwrap(), Message::Disable("HTTP API Pause"));
+
+        let (status, _) = api.handle(
+            &Method::Post,
+            "/api/brightness",
+            auth,
+            "{\"brightness\": 50}",
+        );
+        assert_eq!(status, 204);
+        assert_eq!(rx.try_recv().unwrap(), Message::ManualBrightness(Some(50)));
+        let (status, _) = api.handle(
+            &Method::Post,
+            "/api/brightness",
+            auth,
+            "{\"brightness\": null}",
+        );
+        assert_eq!(status, 204);
+        assert_eq!(rx.try_recv().unwrap(), Message::ManualBrightness(None));
+        let (status, _) = api.handle(
+            &Method::Post,
+            "/api/brightness",
+            auth,
+            "{\"brightness\": 101}",
+        );
+        assert_eq!(status, 400);
+        assert_eq!(
+            api.handle(&Method::Post, "/api/brightness", auth, "").0,
+            400
+        );
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(api.handle(&Method::Get, "/metrics", auth, "").0, 404);
+        api.config.write().unwrap().http_api = Some(HttpApiSettings {
+            port: 8185,
+            metrics: true,
+        });
+        match api.handle(&Method::Get, "/metrics", auth, "") {
+            (200, Body::Metrics(text)) => assert!(text.contains("\nssb_enabled 0\n")),
+            _ => panic!("Expected metrics"),
+        }
+
+        assert_eq!(api.handle(&Method::Get, "/api/pause", auth, "").0, 405);
+        assert_eq!(api.handle(&Method::Get, "/api/missing", auth, "").0, 404);
+    }
+
+    #[test]
+    fn test_load_or_create_token() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = token_path(&directory.path().join("config.json"));
+        let token = load_or_create_token(&path).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(load_or_create_token(&path).unwrap(), token);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! LED devices such as keyboard backlights, set through the Linux LED class in sysfs
+
+use crate::apply::{BrightnessDetails, LedResult};
+use crate::calculator::{calculate_brightness, percent_to_level};
+use crate::config::{BrightnessValues, LedSettings, TransitionCurve};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use sunrise_sunset_calculator::SunriseSunsetResult;
+use wildmatch::WildMatch;
+use zbus::dbus_proxy;
+
+pub const LEDS_PATH: &str = "/sys/class/leds";
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1/session/auto"
+)]
+trait Session {
+    fn set_brightness(&self, subsystem: &str, name: &str, brightness: u32) -> zbus::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct LedDevice {
+    pub name: String,
+    dir: PathBuf,
+    pub max_brightness: u32,
+}
+
+impl LedDevice {
+    /// Find the devices in the LED class directory with a name matching the pattern
+    pub fn find(leds_dir: &Path, pattern: &str) -> Vec<Self> {
+        let pattern = WildMatch::new(pattern);
+        let Ok(entries) = fs::read_dir(leds_dir) else {
+            return vec![];
+        };
+        let mut devices = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| pattern.matches(&e.file_name().to_string_lossy()))
+            .filter_map(|e| {
+                let dir = e.path();
+                match read_value(&dir.join("max_brightness")) {
+                    Ok(max_brightness) => Some(LedDevice {
+                        name: e.file_name().to_string_lossy().into_owned(),
+                        dir,
+                        max_brightness,
+                    }),
+                    Err(e) => {
+                        log::warn!("Skipping LED device: {:#}", e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+
+    pub fn read_brightness(&self) -> anyhow::Result<u32> {
+        read_value(&self.dir.join("brightness"))
+    }
+
+    /// Write the raw brightness, using logind if the sysfs file isn't writable by this user
+    pub fn write_brightness(&self, value: u32) -> anyhow::Result<()> {
+        let path = self.dir.join("brightness");
+        match fs::write(&path, value.to_string()) {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                log::debug!("Setting '{}' brightness using logind", self.name);
+                let connection = zbus::blocking::Connection::system()?;
+                SessionProxyBlocking::new(&connection)?
+                    .set_brightness("leds", &self.name, value)
+                    .context(format!(
+                        "Unable to set '{}' brightness using logind",
+                        self.name
+                    ))
+            }
+            result => result.context(format!("Unable to write file '{}'", path.display())),
+        }
+    }
+}
+
+fn read_value(path: &Path) -> anyhow::Result<u32> {
+    let contents =
+        fs::read_to_string(path).context(format!("Unable to read file '{}'", path.display()))?;
+    contents
+        .trim()
+        .parse()
+        .context(format!("Invalid value in '{}'", path.display()))
+}
+
+/// Calculate and apply the brightness for every matching LED device
+#[allow(clippy::too_many_arguments)]
+pub fn apply_leds(
+    leds_dir: &Path,
+    settings: &LedSettings,
+    transition_mins: u32,
+    transition_curve: TransitionCurve,
+    sun: &SunriseSunsetResult,
+    time_now: i64,
+    last_applied: &mut HashMap<String, u32>,
+    verify: bool,
+) -> Vec<LedResult> {
+    let overrides = settings
+        .overrides
+        .iter()
+        .map(|o| (WildMatch::new(&o.pattern), o.brightness))
+        .collect::<Vec<_>>();
+    let devices = LedDevice::find(leds_dir, &settings.pattern);
+    last_applied.retain(|name, _| devices.iter().any(|d| &d.name == name));
+    devices
+        .into_iter()
+        .map(|device| {
+            let values = match overrides.iter().find(|(p, _)| p.matches(&device.name)) {
+                Some((_, values)) => *values,
+                None => Some(BrightnessValues {
+                    brightness_day: settings.brightness_day,
+                    brightness_night: settings.brightness_night,
+                }),
+            };
+            let Some(values) = values else {
+                log::info!("Skipping LED '{}' due to override", device.name);
+                return LedResult {
+                    name: device.name,
+                    brightness: None,
+                    max_brightness: device.max_brightness,
+                    error: None,
+                    unchanged: false,
+                };
+            };
+            let brightness = calculate_brightness(
+                values.brightness_day,
+                values.brightness_night,
+                transition_mins,
+                transition_curve,
+                sun,
+                time_now,
+            );
+            let raw = percent_to_level(brightness.brightness, device.max_brightness);
+            let mut unchanged = last_applied.get(&device.name) == Some(&raw);
+            if unchanged && verify {
+                unchanged = matches!(device.read_brightness(), Ok(current) if current == raw);
+            }
+            let error = if unchanged {
+                log::info!("Brightness for LED '{}' is unchanged", device.name);
+                None
+            } else {
+                match device.write_brightness(raw) {
+                    Ok(_) => {
+                        log::info!(
+                            "Set brightness for LED '{}' to {}/{} ({}%)",
+                            device.name,
+                            raw,
+                            device.max_brightness,
+                            brightness.brightness
+                        );
+                        last_applied.insert(device.name.clone(), raw);
+                        None
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "Failed to set brightness for LED '{}': {:#}",
+                            device.name,
+                            e
+                        );
+                        last_applied.remove(&device.name);
+                        Some(format!("{:#}", e))
+                    }
+                }
+            };
+            LedResult {
+                name: device.name,
+                brightness: Some(BrightnessDetails {
+                    expiry_time: brightness.expiry_time,
+                    brightness: brightness.brightness,
+                    brightness_day: values.brightness_day,
+                    brightness_night: values.brightness_night,
+                }),
+                max_brightness: device.max_brightness,
+                error,
+                unchanged,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LedOverride;
+
+    fn add_device(dir: &Path, name: &str, max_brightness: u32) {
+        let device = dir.join(name);
+        fs::create_dir(&device).unwrap();
+        fs::write(device.join("max_brightness"), format!("{max_brightness}\n")).unwrap();
+        fs::write(device.join("brightness"), "0\n").unwrap();
+    }
+
+    #[test]
+    fn test_apply_leds() {
+        let dir = tempfile::tempdir().unwrap();
+        add_device(dir.path(), "tpacpi::kbd_backlight", 2);
+        add_device(dir.path(), "asus::kbd_backlight", 3);
+        add_device(dir.path(), "input3::capslock", 1);
+        let settings = LedSettings {
+            overrides: vec![LedOverride {
+                pattern: "asus::*".to_owned(),
+                brightness: None,
+            }],
+            ..LedSettings::default()
+        };
+        // Night time
+        let sun = SunriseSunsetResult {
+            set: 1000,
+            rise: 100000,
+            visible: false,
+        };
+        let mut last_applied = HashMap::new();
+        let apply = |last_applied: &mut HashMap<_, _>, verify| {
+            apply_leds(
+                dir.path(),
+                &settings,
+                40,
+                TransitionCurve::Sine,
+                &sun,
+                50000,
+                last_applied,
+                verify,
+            )
+        };
+
+        let results = apply(&mut last_applied, false);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "asus::kbd_backlight");
+        assert!(results[0].brightness.is_none());
+        assert_eq!(results[1].name, "tpacpi::kbd_backlight");
+        assert_eq!(results[1].brightness.as_ref().unwrap().brightness, 100);
+        assert!(results[1].error.is_none());
+        assert!(!results[1].unchanged);
+        let written = dir.path().join("tpacpi::kbd_backlight/brightness");
+        assert_eq!(fs::read_to_string(&written).unwrap(), "2");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("asus::kbd_backlight/brightness")).unwrap(),
+            "0\n"
+        );
+
+        // Not written again unless it has drifted
+        let results = apply(&mut last_applied, false);
+        assert!(results[1].unchanged);
+        fs::write(&written, "0").unwrap();
+        let results = apply(&mut last_applied, true);
+        assert!(!results[1].unchanged);
+        assert_eq!(fs::read_to_string(&written).unwrap(), "2");
+    }
+}
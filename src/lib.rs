@@ -1,9 +1,32 @@
+#[cfg(target_os = "linux")]
+pub mod ambient;
 pub mod apply;
 pub mod calculator;
 pub mod common;
 pub mod config;
 pub mod controller;
+#[cfg(target_os = "linux")]
+pub mod dbus_service;
 pub mod event_watcher;
+pub mod fade;
+#[cfg(target_os = "linux")]
+pub mod gamma;
+#[cfg(target_os = "linux")]
+pub mod geoclue;
+pub mod geocoder;
 pub mod gui;
+pub mod hooks;
+pub mod http_api;
+#[cfg(target_os = "linux")]
+pub mod leds;
+pub mod location;
+pub mod metrics;
+pub mod mqtt;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+#[cfg(test)]
+mod test_utils;
+pub mod theme;
 pub mod tray;
 pub mod unique;
+pub mod weather;
@@ -0,0 +1,170 @@
+//! Determines the location used to calculate the sunrise and sunset times
+
+use crate::config::{Location, SsbConfig};
+use serde::Serialize;
+
+/// Coordinates of the principal location of each IANA time zone
+const TIMEZONE_COORDINATES: &str = include_str!("../assets/timezone_coordinates.tsv");
+
+/// A location found automatically, e.g. by GeoClue on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AutomaticLocation {
+    pub location: Location,
+    /// Radius of the accuracy circle, in metres
+    pub accuracy: f64,
+}
+
+/// Where the location used for the calculation came from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LocationSource {
+    /// Set by the user in the config
+    Configured,
+    /// Found automatically because automatic location is enabled
+    Automatic { accuracy: f64 },
+    /// Estimated from the system time zone because no location is configured
+    Timezone { timezone: String },
+}
+
+impl LocationSource {
+    /// Whether the location is only a rough estimate of where the user is
+    pub fn is_approximate(&self) -> bool {
+        matches!(self, LocationSource::Timezone { .. })
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            LocationSource::Configured => "Configured".to_owned(),
+            LocationSource::Automatic { accuracy } => {
+                format!(
+                    "Automatic, accurate to within {}",
+                    format_distance(*accuracy)
+                )
+            }
+            LocationSource::Timezone { timezone } => {
+                format!("Approximate, from the time zone {timezone}")
+            }
+        }
+    }
+}
+
+/// The automatic location if it is enabled and available, otherwise the configured location, or
+/// otherwise an approximate location from the system time zone
+pub fn resolve_location(
+    config: &SsbConfig,
+    automatic: Option<AutomaticLocation>,
+) -> Option<(Location, LocationSource)> {
+    if let (true, Some(automatic)) = (config.automatic_location, automatic) {
+        return Some((
+            automatic.location,
+            LocationSource::Automatic {
+                accuracy: automatic.accuracy,
+            },
+        ));
+    }
+    if let Some(location) = config.location {
+        return Some((location, LocationSource::Configured));
+    }
+    let timezone = match iana_time_zone::get_timezone() {
+        Ok(timezone) => timezone,
+        Err(e) => {
+            log::warn!("Unable to get the system time zone: {e}");
+            return None;
+        }
+    };
+    match timezone_location(&timezone) {
+        Some(location) => {
+            log::info!("No location is configured, using time zone {timezone} at {location:?}");
+            Some((location, LocationSource::Timezone { timezone }))
+        }
+        None => {
+            log::warn!("No location is known for the time zone {timezone}");
+            None
+        }
+    }
+}
+
+fn format_distance(metres: f64) -> String {
+    if metres >= 1000.0 {
+        format!("{:.1} km", metres / 1000.0)
+    } else {
+        format!("{:.0} m", metres)
+    }
+}
+
+/// The approximate location of an IANA time zone, e.g. Europe/London
+pub fn timezone_location(timezone: &str) -> Option<Location> {
+    TIMEZONE_COORDINATES
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            let mut fields = line.split('\t');
+            if fields.next()? != timezone {
+                return None;
+            }
+            Some(Location {
+                latitude: fields.next()?.parse().ok()?,
+                longitude: fields.next()?.parse().ok()?,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_location() {
+        let configured = Location {
+            latitude: 51.5,
+            longitude: -0.12,
+        };
+        let automatic = AutomaticLocation {
+            location: Location {
+                latitude: 48.86,
+                longitude: 2.35,
+            },
+            accuracy: 1500.0,
+        };
+        let mut config = SsbConfig {
+            location: Some(configured),
+            ..SsbConfig::default()
+        };
+        // The automatic location is ignored unless it is enabled
+        assert_eq!(
+            resolve_location(&config, Some(automatic)),
+            Some((configured, LocationSource::Configured))
+        );
+        config.automatic_location = true;
+        let (location, source) = resolve_location(&config, Some(automatic)).unwrap();
+        assert_eq!(location, automatic.location);
+        assert_eq!(source.describe(), "Automatic, accurate to within 1.5 km");
+        assert!(!source.is_approximate());
+        // Falls back to the configured location when it is unavailable
+        assert_eq!(
+            resolve_location(&config, None),
+            Some((configured, LocationSource::Configured))
+        );
+    }
+
+    #[test]
+    fn test_timezone_location() {
+        let london = timezone_location("Europe/London").unwrap();
+        assert_eq!(london.latitude, 51.5083);
+        assert_eq!(london.longitude, -0.1253);
+        // Aliases are resolved to their canonical zone
+        assert_eq!(timezone_location("GB"), Some(london));
+        assert!(timezone_location("America/New_York").unwrap().longitude < -70.0);
+        // Zones without a location
+        assert_eq!(timezone_location("UTC"), None);
+        assert_eq!(timezone_location("Not/A_Zone"), None);
+
+        // Every entry in the table is a valid location
+        use validator::Validate;
+        for line in TIMEZONE_COORDINATES.lines().filter(|l| !l.starts_with('#')) {
+            let timezone = line.split('\t').next().unwrap();
+            let location = timezone_location(timezone).unwrap();
+            assert!(location.validate().is_ok(), "{timezone}");
+        }
+    }
+}
@@ -1,13 +1,23 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 use clap::Parser;
 use egui_winit::winit;
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::ambient::AmbientLightWatcher;
 use solar_screen_brightness::common::{install_logger, APP_NAME};
-use solar_screen_brightness::config::SsbConfig;
+use solar_screen_brightness::config::{get_default_config_path, SsbConfig};
 use solar_screen_brightness::controller::BrightnessController;
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::dbus_service::DbusService;
 use solar_screen_brightness::event_watcher::EventWatcher;
+#[cfg(target_os = "linux")]
+use solar_screen_brightness::geoclue::GeoclueWatcher;
 use solar_screen_brightness::gui::app::SsbEguiApp;
 use solar_screen_brightness::gui::{NextPaint, UserEvent, WgpuWinitApp};
+use solar_screen_brightness::http_api::HttpApiServer;
+use solar_screen_brightness::mqtt::MqttClient;
+use solar_screen_brightness::theme::ThemeSwitcher;
 use solar_screen_brightness::unique::SsbUniqueInstance;
+use solar_screen_brightness::weather::WeatherWatcher;
 use solar_screen_brightness::{tray, unique};
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
@@ -77,7 +87,23 @@ fn main() {
     ctrlc::set_handler(move || ctrlc_proxy.send_event(UserEvent::Exit("ctrl-c")).unwrap()).unwrap();
 
     let _event_watcher = EventWatcher::start(&controller, Some(&event_loop));
-    let _tray = tray::create(&event_loop);
+    #[cfg(target_os = "linux")]
+    let _ambient_light_watcher = AmbientLightWatcher::start(config.clone(), &controller);
+    #[cfg(target_os = "linux")]
+    let _geoclue_watcher = GeoclueWatcher::start(config.clone(), &controller);
+    let _weather_watcher = WeatherWatcher::start(config.clone(), &controller);
+    #[cfg(target_os = "linux")]
+    let _dbus_service = DbusService::start(config.clone(), get_default_config_path(), &controller);
+    let _http_api_server =
+        HttpApiServer::start(config.clone(), get_default_config_path(), &controller);
+    let _mqtt_client = MqttClient::start(config.clone(), &controller);
+    let _theme_switcher = ThemeSwitcher::start(config.clone(), &controller);
+    #[cfg(target_os = "linux")]
+    let _tray = tray::create(&event_loop, config.clone(), &controller);
+    #[cfg(not(target_os = "linux"))]
+    let mut tray = tray::create(&event_loop, config.clone(), &controller);
+    #[cfg(not(target_os = "linux"))]
+    let tray_config = config.clone();
 
     let app_proxy = event_loop.create_proxy();
     let mut framework = WgpuWinitApp::new(event_loop.create_proxy(), args.minimised, move || {
@@ -92,6 +118,11 @@ fn main() {
     let mut next_repaint_time = Some(Instant::now());
 
     event_loop.run_return(|event, event_loop, control_flow| {
+        #[cfg(not(target_os = "linux"))]
+        if let Event::UserEvent(UserEvent::UpdateTray(_)) = &event {
+            tray.update_locations(&tray_config.read().unwrap());
+        }
+
         let event_result = match &event {
             // Platform-dependent event handlers to workaround a winit bug
             // See: https://github.com/rust-windowing/winit/issues/987
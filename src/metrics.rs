@@ -0,0 +1,312 @@
+//! Metrics recorded by the BrightnessController, in the Prometheus text exposition format
+
+use crate::apply::ApplyResults;
+use crate::calculator::sun_elevation;
+use crate::config::Location;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Upper bounds of the write duration histogram buckets, in seconds
+const WRITE_DURATION_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative count of the observations less than or equal to each bucket
+    buckets: [u64; WRITE_DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(WRITE_DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MonitorMetrics {
+    writes_succeeded: u64,
+    writes_failed: u64,
+    write_duration: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    enabled: bool,
+    updates: u64,
+    location: Option<Location>,
+    next_update: Option<i64>,
+    /// The target brightness of each monitor in the latest results
+    brightness: BTreeMap<String, u32>,
+    /// Counters for every monitor seen since starting, so these are never removed
+    monitors: BTreeMap<String, MonitorMetrics>,
+}
+
+impl Metrics {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Record the results of an update, and when the next update is due
+    pub fn record(&mut self, results: Option<&ApplyResults>, next_update: Option<SystemTime>) {
+        self.updates += 1;
+        self.next_update = next_update
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+        self.location = results.map(|r| r.location);
+        self.brightness.clear();
+        for monitor in results.iter().flat_map(|r| &r.monitors) {
+            let name = &monitor.properties.device_name;
+            if let Some(brightness) = &monitor.brightness {
+                self.brightness.insert(name.clone(), brightness.brightness);
+            }
+            if let Some(write) = &monitor.write {
+                self.record_write(name, monitor.error.is_none(), write.duration_ms);
+            }
+        }
+        for fade in results.iter().flat_map(|r| &r.finished_fades) {
+            self.record_write(&fade.device_name, fade.result.is_ok(), fade.duration_ms);
+        }
+    }
+
+    fn record_write(&mut self, device_name: &str, succeeded: bool, duration_ms: u64) {
+        let metrics = self.monitors.entry(device_name.to_owned()).or_default();
+        if succeeded {
+            metrics.writes_succeeded += 1;
+        } else {
+            metrics.writes_failed += 1;
+        }
+        metrics.write_duration.observe(duration_ms as f64 / 1000.0);
+    }
+
+    /// Render the metrics in the text exposition format, the sun elevation is calculated for `now`
+    pub fn render(&self, now: i64) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "ssb_enabled",
+            "gauge",
+            "1 if the brightness is being updated, 0 while paused",
+        );
+        writeln!(out, "ssb_enabled {}", self.enabled as u8).unwrap();
+
+        header(
+            &mut out,
+            "ssb_updates_total",
+            "counter",
+            "Number of times the brightness has been calculated",
+        );
+        writeln!(out, "ssb_updates_total {}", self.updates).unwrap();
+
+        if let Some(next_update) = self.next_update {
+            header(
+                &mut out,
+                "ssb_next_update_timestamp_seconds",
+                "gauge",
+                "Unix time when the brightness is next due to change",
+            );
+            writeln!(out, "ssb_next_update_timestamp_seconds {next_update}").unwrap();
+        }
+
+        if let Some(location) = self.location {
+            header(
+                &mut out,
+                "ssb_sun_elevation_degrees",
+                "gauge",
+                "Elevation of the sun above the horizon",
+            );
+            let elevation = sun_elevation(now, location.latitude, location.longitude);
+            writeln!(out, "ssb_sun_elevation_degrees {elevation:.2}").unwrap();
+        }
+
+        header(
+            &mut out,
+            "ssb_monitor_brightness_percent",
+            "gauge",
+            "Target brightness of the monitor",
+        );
+        for (name, brightness) in &self.brightness {
+            writeln!(
+                out,
+                "ssb_monitor_brightness_percent{{device=\"{}\"}} {brightness}",
+                escape(name)
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "ssb_monitor_writes_total",
+            "counter",
+            "Number of attempts to set or fade the brightness of the monitor",
+        );
+        for (name, metrics) in &self.monitors {
+            let name = escape(name);
+            writeln!(
+                out,
+                "ssb_monitor_writes_total{{device=\"{name}\",result=\"success\"}} {}",
+                metrics.writes_succeeded
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "ssb_monitor_writes_total{{device=\"{name}\",result=\"failure\"}} {}",
+                metrics.writes_failed
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "ssb_monitor_write_duration_seconds",
+            "histogram",
+            "Time taken to set the brightness of the monitor, including retries and fading",
+        );
+        for (name, metrics) in &self.monitors {
+            let name = escape(name);
+            let histogram = &metrics.write_duration;
+            for (bound, count) in WRITE_DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                writeln!(
+                    out,
+                    "ssb_monitor_write_duration_seconds_bucket{{device=\"{name}\",le=\"{bound}\"}} {count}"
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "ssb_monitor_write_duration_seconds_bucket{{device=\"{name}\",le=\"+Inf\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "ssb_monitor_write_duration_seconds_sum{{device=\"{name}\"}} {}",
+                histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "ssb_monitor_write_duration_seconds_count{{device=\"{name}\"}} {}",
+                histogram.count
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::{MonitorResult, WriteDetails};
+    use crate::fade::FadeResult;
+    use std::time::Duration;
+
+    fn monitor(name: &str, duration_ms: u64, error: Option<&str>) -> MonitorResult {
+        MonitorResult {
+            error: error.map(|e| e.to_owned()),
+            write: Some(WriteDetails {
+                duration_ms,
+                attempts: 1,
+            }),
+            ..MonitorResult::for_test(name, 70)
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let results = ApplyResults {
+            monitors: vec![
+                monitor("ddcci\"1", 300, None),
+                monitor("eDP-1", 20, Some("Failed")),
+            ],
+            ..ApplyResults::for_test(&[])
+        };
+        let mut metrics = Metrics::default();
+        metrics.set_enabled(true);
+        metrics.record(
+            Some(&results),
+            Some(UNIX_EPOCH + Duration::from_secs(1690000000)),
+        );
+        metrics.record(Some(&results), None);
+        let rendered = metrics.render(1687348800);
+        let lines = rendered.lines().collect::<Vec<_>>();
+        for expected in [
+            "ssb_enabled 1",
+            "ssb_updates_total 2",
+            "ssb_monitor_brightness_percent{device=\"ddcci\\\"1\"} 70",
+            "ssb_monitor_writes_total{device=\"eDP-1\",result=\"success\"} 0",
+            "ssb_monitor_writes_total{device=\"eDP-1\",result=\"failure\"} 2",
+            "ssb_monitor_write_duration_seconds_bucket{device=\"ddcci\\\"1\",le=\"0.25\"} 0",
+            "ssb_monitor_write_duration_seconds_bucket{device=\"ddcci\\\"1\",le=\"0.5\"} 2",
+            "ssb_monitor_write_duration_seconds_bucket{device=\"ddcci\\\"1\",le=\"+Inf\"} 2",
+            "ssb_monitor_write_duration_seconds_sum{device=\"ddcci\\\"1\"} 0.6",
+            "ssb_monitor_write_duration_seconds_count{device=\"eDP-1\"} 2",
+            "# TYPE ssb_monitor_write_duration_seconds histogram",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "Missing '{expected}' in:\n{rendered}"
+            );
+        }
+        // The next update was cleared by the second update
+        assert!(!rendered.contains("ssb_next_update_timestamp_seconds"));
+        let elevation = lines
+            .iter()
+            .find_map(|l| l.strip_prefix("ssb_sun_elevation_degrees "))
+            .unwrap();
+        assert!((elevation.parse::<f64>().unwrap() - 62.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_record_fades() {
+        let fade = |result, duration_ms| FadeResult {
+            device_name: "ddcci/5".to_owned(),
+            target: 70,
+            result,
+            duration_ms,
+        };
+        let results = ApplyResults {
+            finished_fades: vec![
+                fade(Ok(()), 2000),
+                fade(Err("Timed out fading brightness".to_owned()), 7000),
+            ],
+            ..ApplyResults::for_test(&[("ddcci/5", 70)])
+        };
+        let mut metrics = Metrics::default();
+        metrics.record(Some(&results), None);
+        let rendered = metrics.render(1687348800);
+        let lines = rendered.lines().collect::<Vec<_>>();
+        for expected in [
+            "ssb_monitor_writes_total{device=\"ddcci/5\",result=\"success\"} 1",
+            "ssb_monitor_writes_total{device=\"ddcci/5\",result=\"failure\"} 1",
+            "ssb_monitor_write_duration_seconds_bucket{device=\"ddcci/5\",le=\"2.5\"} 1",
+            "ssb_monitor_write_duration_seconds_sum{device=\"ddcci/5\"} 9",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "Missing '{expected}' in:\n{rendered}"
+            );
+        }
+    }
+}
@@ -0,0 +1,738 @@
+//! Publishes the state to an MQTT broker and subscribes to command topics, with optional
+//! Home Assistant discovery so that the entities are created automatically
+
+use crate::apply::ApplyResults;
+use crate::common::{APP_DIRECTORY_NAME, APP_NAME};
+use crate::config::{MqttSettings, SsbConfig};
+use crate::controller::{BrightnessController, ControllerStatus, Message};
+use chrono::{TimeZone, Utc};
+use rumqttc::{
+    valid_topic, Client, Connection, ConnectionError, Event, LastWill, MqttOptions, Packet, QoS,
+    RecvTimeoutError,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long to wait for the broker before checking for updates
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often to check whether MQTT has been enabled in the config
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before the first reconnection attempt, this is doubled after each failed attempt
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// How long to wait for the offline message to be sent when stopping
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Capacity of the client's request channel, messages that don't fit are queued by the Session
+const REQUEST_CAPACITY: usize = 64;
+
+/// The state includes every monitor, so may be larger than the default limit of 10KB
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Published as the manual brightness when it isn't set, Home Assistant resets the number to
+/// unknown when it receives this
+const NO_MANUAL_BRIGHTNESS: &str = "unknown";
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Monitor names may contain characters that aren't allowed in a topic level
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+/// Home Assistant node and object ids must only contain `[a-zA-Z0-9_-]`
+fn discovery_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn availability_topic(settings: &MqttSettings) -> String {
+    format!("{}/availability", settings.base_topic)
+}
+
+fn monitor_topic(settings: &MqttSettings, name: &str) -> String {
+    format!(
+        "{}/monitors/{}/brightness",
+        settings.base_topic,
+        topic_level(name)
+    )
+}
+
+/// The retained messages describing the current state, as (topic, payload)
+fn state_messages(
+    settings: &MqttSettings,
+    enabled: bool,
+    results: &Option<ApplyResults>,
+) -> Vec<(String, String)> {
+    let base = &settings.base_topic;
+    let status = ControllerStatus { enabled, results };
+    let mut messages = vec![
+        (
+            format!("{base}/state"),
+            serde_json::to_string(&status).unwrap(),
+        ),
+        (format!("{base}/enabled"), on_off(enabled).to_owned()),
+    ];
+    if let Some(results) = results {
+        let manual = results
+            .manual_brightness
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| NO_MANUAL_BRIGHTNESS.to_owned());
+        let time = |t: i64| Utc.timestamp_opt(t, 0).unwrap().to_rfc3339();
+        messages.extend([
+            (format!("{base}/manual_brightness"), manual),
+            (format!("{base}/sun/rise"), time(results.sun.rise)),
+            (format!("{base}/sun/set"), time(results.sun.set)),
+            (
+                format!("{base}/sun/visible"),
+                on_off(results.sun.visible).to_owned(),
+            ),
+            (
+                format!("{base}/phase"),
+                results.phase.phase.name().to_owned(),
+            ),
+        ]);
+        for monitor in &results.monitors {
+            if let Some(brightness) = &monitor.brightness {
+                messages.push((
+                    monitor_topic(settings, &monitor.properties.device_name),
+                    brightness.brightness.to_string(),
+                ));
+            }
+        }
+    }
+    messages
+}
+
+/// The Home Assistant discovery messages for the entities, and for each of these monitors
+fn discovery_messages(settings: &MqttSettings, monitors: &[&str]) -> Vec<(String, String)> {
+    let Some(prefix) = &settings.discovery_prefix else {
+        return vec![];
+    };
+    let base = &settings.base_topic;
+    // Identifies this instance
+    let node = discovery_id(&settings.base_topic);
+    let entity = |component: &str, object: &str, mut config: Value| {
+        let fields = config.as_object_mut().unwrap();
+        fields.insert("unique_id".into(), json!(format!("{node}_{object}")));
+        fields.insert("object_id".into(), json!(format!("{node}_{object}")));
+        fields.insert(
+            "availability_topic".into(),
+            json!(availability_topic(settings)),
+        );
+        fields.insert(
+            "device".into(),
+            json!({
+                "identifiers": [node],
+                "name": APP_NAME,
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            }),
+        );
+        (
+            format!("{prefix}/{component}/{node}/{object}/config"),
+            config.to_string(),
+        )
+    };
+    let mut messages = vec![
+        entity(
+            "switch",
+            "enabled",
+            json!({
+                "name": "Enabled",
+                "icon": "mdi:brightness-auto",
+                "state_topic": format!("{base}/enabled"),
+                "command_topic": format!("{base}/set/enabled"),
+            }),
+        ),
+        entity(
+            "number",
+            "manual_brightness",
+            json!({
+                "name": "Manual Brightness",
+                "icon": "mdi:brightness-6",
+                "state_topic": format!("{base}/manual_brightness"),
+                "command_topic": format!("{base}/set/brightness"),
+                "payload_reset": NO_MANUAL_BRIGHTNESS,
+                "min": 0,
+                "max": 100,
+                "unit_of_measurement": "%",
+            }),
+        ),
+        entity(
+            "button",
+            "automatic_brightness",
+            json!({
+                "name": "Automatic Brightness",
+                "icon": "mdi:brightness-auto",
+                "command_topic": format!("{base}/set/brightness"),
+                "payload_press": "None",
+            }),
+        ),
+        entity(
+            "button",
+            "refresh",
+            json!({
+                "name": "Refresh",
+                "icon": "mdi:refresh",
+                "command_topic": format!("{base}/set/refresh"),
+            }),
+        ),
+        entity(
+            "sensor",
+            "sunrise",
+            json!({
+                "name": "Sunrise",
+                "device_class": "timestamp",
+                "state_topic": format!("{base}/sun/rise"),
+            }),
+        ),
+        entity(
+            "sensor",
+            "sunset",
+            json!({
+                "name": "Sunset",
+                "device_class": "timestamp",
+                "state_topic": format!("{base}/sun/set"),
+            }),
+        ),
+        entity(
+            "binary_sensor",
+            "daytime",
+            json!({
+                "name": "Daytime",
+                "icon": "mdi:weather-sunny",
+                "state_topic": format!("{base}/sun/visible"),
+            }),
+        ),
+        entity(
+            "sensor",
+            "phase",
+            json!({
+                "name": "Phase",
+                "icon": "mdi:theme-light-dark",
+                "device_class": "enum",
+                "options": ["dawn", "day", "dusk", "night"],
+                "state_topic": format!("{base}/phase"),
+            }),
+        ),
+    ];
+    for monitor in monitors {
+        messages.push(entity(
+            "sensor",
+            &format!("monitor_{}", discovery_id(monitor)),
+            json!({
+                "name": format!("{monitor} Brightness"),
+                "icon": "mdi:monitor",
+                "state_topic": monitor_topic(settings, monitor),
+                "unit_of_measurement": "%",
+                "state_class": "measurement",
+            }),
+        ));
+    }
+    messages
+}
+
+/// Convert a message received on one of the command topics into a controller message
+fn parse_command(settings: &MqttSettings, topic: &str, payload: &str) -> Option<Message> {
+    let command = topic
+        .strip_prefix(&settings.base_topic)?
+        .strip_prefix("/set/")?;
+    let payload = payload.trim();
+    match command {
+        "enabled" if payload.eq_ignore_ascii_case("ON") => Some(Message::Enable("MQTT Resume")),
+        "enabled" if payload.eq_ignore_ascii_case("OFF") => Some(Message::Disable("MQTT Pause")),
+        "brightness" if payload.is_empty() || payload.eq_ignore_ascii_case("None") => {
+            Some(Message::ManualBrightness(None))
+        }
+        "brightness" => {
+            // Home Assistant may send a float, e.g. "50.0"
+            let brightness = payload.parse::<f64>().ok()?.round();
+            (0.0..=100.0)
+                .contains(&brightness)
+                .then_some(Message::ManualBrightness(Some(brightness as u32)))
+        }
+        "refresh" => Some(Message::Refresh("MQTT Refresh")),
+        _ => None,
+    }
+}
+
+enum ClientEvent {
+    Updated,
+    Stop,
+}
+
+/// Publishes to and receives commands from the broker on its own thread, while it is enabled in
+/// the config
+pub struct MqttClient {
+    events: mpsc::Sender<ClientEvent>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl MqttClient {
+    pub fn start(config: Arc<RwLock<SsbConfig>>, controller: &BrightnessController) -> Self {
+        let shared = Shared {
+            config,
+            controller: controller.sender.clone(),
+            last_result: controller.last_result.clone(),
+            enabled: controller.enabled.clone(),
+        };
+        let (events, events_rx) = mpsc::channel();
+        let listener = Mutex::new(events.clone());
+        controller.add_listener(move || {
+            listener.lock().unwrap().send(ClientEvent::Updated).ok();
+        });
+        let join_handle = std::thread::spawn(move || run(shared, events_rx));
+        Self {
+            events,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for MqttClient {
+    fn drop(&mut self) {
+        log::info!("Stopping MQTT client");
+        self.events.send(ClientEvent::Stop).ok();
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+struct Shared {
+    config: Arc<RwLock<SsbConfig>>,
+    controller: mpsc::Sender<Message>,
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    enabled: Arc<AtomicBool>,
+}
+
+/// A connection to the broker using particular settings
+struct Session {
+    settings: MqttSettings,
+    client: Client,
+    connection: Connection,
+    connected: bool,
+    backoff: Duration,
+    /// The monitors that discovery messages have been published for
+    discovered: BTreeSet<String>,
+    /// Messages waiting for space in the client's request channel, as (topic, payload)
+    queued: VecDeque<(String, String)>,
+}
+
+impl Session {
+    fn new(settings: MqttSettings) -> Self {
+        let client_id = format!("{}-{}", APP_DIRECTORY_NAME, std::process::id());
+        let mut options = MqttOptions::new(client_id, &settings.host, settings.port);
+        options
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE)
+            .set_keep_alive(KEEP_ALIVE)
+            .set_last_will(LastWill::new(
+                availability_topic(&settings),
+                OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &settings.username {
+            let password = settings.password.clone().unwrap_or_default();
+            options.set_credentials(username, password);
+        }
+        log::info!(
+            "Connecting to MQTT broker {}:{}",
+            settings.host,
+            settings.port
+        );
+        let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+        Self {
+            settings,
+            client,
+            connection,
+            connected: false,
+            backoff: RECONNECT_BACKOFF,
+            discovered: BTreeSet::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    fn publish(&mut self, topic: String, payload: impl Into<String>) {
+        if !valid_topic(&topic) {
+            log::warn!("Unable to publish MQTT message to invalid topic '{topic}'");
+            return;
+        }
+        // The messages are retained, so only the latest payload for each topic needs to be sent
+        let payload = payload.into();
+        match self.queued.iter_mut().find(|(t, _)| *t == topic) {
+            Some(queued) => queued.1 = payload,
+            None => self.queued.push_back((topic, payload)),
+        }
+        self.flush();
+    }
+
+    /// Move the queued messages into the client's request channel until it is full. This must not
+    /// block, as this thread also drives the connection, so the rest are sent on a later poll.
+    fn flush(&mut self) {
+        while let Some((topic, payload)) = self.queued.front() {
+            let publish =
+                self.client
+                    .try_publish(topic.as_str(), QoS::AtLeastOnce, true, payload.as_str());
+            if publish.is_err() {
+                log::debug!("MQTT request channel is full, {} queued", self.queued.len());
+                break;
+            }
+            self.queued.pop_front();
+        }
+    }
+
+    fn on_connected(&mut self, shared: &Shared) {
+        log::info!("Connected to MQTT broker");
+        self.connected = true;
+        self.backoff = RECONNECT_BACKOFF;
+        // The broker may have lost the retained messages, so publish everything again
+        self.discovered.clear();
+        let commands = format!("{}/set/+", self.settings.base_topic);
+        if let Err(e) = self.client.try_subscribe(commands, QoS::AtLeastOnce) {
+            log::warn!("Unable to subscribe to MQTT commands: {e}");
+        }
+        self.publish(availability_topic(&self.settings), ONLINE);
+        self.publish_state(shared);
+    }
+
+    fn publish_state(&mut self, shared: &Shared) {
+        let results = shared.last_result.read().unwrap();
+        let monitors = results
+            .iter()
+            .flat_map(|r| &r.monitors)
+            .map(|m| m.properties.device_name.as_str())
+            .collect::<BTreeSet<_>>();
+        // The entities are only published once, unless a new monitor is connected
+        let new_monitors = monitors.iter().any(|m| !self.discovered.contains(*m));
+        if new_monitors || self.discovered.is_empty() {
+            let monitors = monitors.into_iter().collect::<Vec<_>>();
+            for (topic, payload) in discovery_messages(&self.settings, &monitors) {
+                self.publish(topic, payload);
+            }
+            self.discovered
+                .extend(monitors.into_iter().map(|m| m.to_owned()));
+        }
+        let enabled = shared.enabled.load(Ordering::SeqCst);
+        for (topic, payload) in state_messages(&self.settings, enabled, &results) {
+            self.publish(topic, payload);
+        }
+    }
+
+    /// Wait for the next event from the broker, returning how long to wait before polling again
+    fn poll(&mut self, shared: &Shared) -> Duration {
+        self.flush();
+        match self.connection.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => self.on_connected(shared),
+            Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                match parse_command(&self.settings, &publish.topic, &payload) {
+                    Some(message) => {
+                        log::info!("Received MQTT command {message:?}");
+                        shared.controller.send(message).unwrap();
+                    }
+                    None => log::warn!(
+                        "Ignoring invalid MQTT command '{}' on {}",
+                        payload,
+                        publish.topic
+                    ),
+                }
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(e)) => return self.on_error(e),
+            Err(RecvTimeoutError::Disconnected) => unreachable!("The client is still alive"),
+        }
+        Duration::ZERO
+    }
+
+    /// The connection is retried on the next poll, after backing off
+    fn on_error(&mut self, e: ConnectionError) -> Duration {
+        self.connected = false;
+        let backoff = self.backoff;
+        log::warn!(
+            "MQTT connection error, reconnecting in {}s: {e}",
+            backoff.as_secs()
+        );
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        backoff
+    }
+
+    /// Mark this client as offline and disconnect cleanly
+    fn disconnect(mut self) {
+        if !self.connected {
+            return;
+        }
+        // The state is out of date, but the offline message must be sent before disconnecting
+        self.queued.clear();
+        self.publish(availability_topic(&self.settings), OFFLINE);
+        let mut disconnecting = false;
+        // Keep polling until the queued messages have been sent and the connection is closed
+        let deadline = std::time::Instant::now() + DISCONNECT_TIMEOUT;
+        while std::time::Instant::now() < deadline {
+            self.flush();
+            if !disconnecting && self.queued.is_empty() {
+                self.client.try_disconnect().ok();
+                disconnecting = true;
+            }
+            match self.connection.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect))) | Ok(Err(_)) => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+                _ => {}
+            }
+        }
+        log::info!("Disconnected from MQTT broker");
+    }
+}
+
+fn run(shared: Shared, events: mpsc::Receiver<ClientEvent>) {
+    let mut session: Option<Session> = None;
+    'outer: loop {
+        let settings = shared.config.read().unwrap().mqtt.clone();
+        if session.as_ref().map(|s| &s.settings) != settings.as_ref() {
+            if let Some(session) = session.take() {
+                session.disconnect();
+            }
+            session = settings.map(Session::new);
+        }
+        let wait = match session.as_mut() {
+            Some(session) => session.poll(&shared),
+            None => CONFIG_POLL_INTERVAL,
+        };
+        // Several updates may have queued up, but only the latest state needs to be published
+        let mut updated = false;
+        let mut received = events.recv_timeout(wait);
+        loop {
+            match received {
+                Ok(ClientEvent::Updated) => updated = true,
+                Ok(ClientEvent::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break 'outer,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+            }
+            received = events.recv_timeout(Duration::ZERO);
+        }
+        if let Some(session) = session.as_mut().filter(|s| updated && s.connected) {
+            session.publish_state(&shared);
+        }
+    }
+    if let Some(session) = session {
+        session.disconnect();
+    }
+    log::debug!("MQTT client thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> MqttSettings {
+        MqttSettings {
+            base_topic: "ssb/desk".to_owned(),
+            ..MqttSettings::default()
+        }
+    }
+
+    fn results() -> ApplyResults {
+        ApplyResults {
+            manual_brightness: Some(40),
+            ..ApplyResults::for_test(&[("ddcci/5", 40)])
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let settings = settings();
+        let parse = |topic, payload| parse_command(&settings, topic, payload);
+        assert_eq!(
+            parse("ssb/desk/set/enabled", "OFF"),
+            Some(Message::Disable("MQTT Pause"))
+        );
+        assert_eq!(
+            parse("ssb/desk/set/enabled", "on"),
+            Some(Message::Enable("MQTT Resume"))
+        );
+        assert_eq!(
+            parse("ssb/desk/set/brightness", "55.0"),
+            Some(Message::ManualBrightness(Some(55)))
+        );
+        assert_eq!(
+            parse("ssb/desk/set/brightness", "None"),
+            Some(Message::ManualBrightness(None))
+        );
+        assert_eq!(
+            parse("ssb/desk/set/refresh", ""),
+            Some(Message::Refresh("MQTT Refresh"))
+        );
+        assert_eq!(parse("ssb/desk/set/brightness", "101"), None);
+        assert_eq!(parse("ssb/desk/set/enabled", "maybe"), None);
+        assert_eq!(parse("ssb/other/set/refresh", ""), None);
+        assert_eq!(parse("ssb/desk/state", ""), None);
+    }
+
+    #[test]
+    fn test_state_messages() {
+        let messages = state_messages(&settings(), false, &Some(results()));
+        let get = |topic: &str| {
+            messages
+                .iter()
+                .find(|(t, _)| t == topic)
+                .map(|(_, p)| p.as_str())
+        };
+        assert_eq!(get("ssb/desk/enabled"), Some("OFF"));
+        assert_eq!(get("ssb/desk/manual_brightness"), Some("40"));
+        assert_eq!(get("ssb/desk/sun/rise"), Some("2023-07-21T14:33:20+00:00"));
+        assert_eq!(get("ssb/desk/sun/visible"), Some("ON"));
+        assert_eq!(get("ssb/desk/phase"), Some("day"));
+        assert_eq!(get("ssb/desk/monitors/ddcci_5/brightness"), Some("40"));
+        let state: Value = serde_json::from_str(get("ssb/desk/state").unwrap()).unwrap();
+        assert_eq!(state["enabled"], false);
+        assert_eq!(state["results"]["sun"]["set"], 1690000000);
+
+        let mut results = results();
+        results.manual_brightness = None;
+        let messages = state_messages(&settings(), true, &Some(results));
+        let manual = messages
+            .iter()
+            .find(|(t, _)| t == "ssb/desk/manual_brightness")
+            .unwrap();
+        assert_eq!(manual.1, NO_MANUAL_BRIGHTNESS);
+
+        let messages = state_messages(&settings(), true, &None);
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn test_queued_messages() {
+        // The connection is never polled, so nothing is sent to the broker
+        let mut session = Session::new(settings());
+        for i in 0..REQUEST_CAPACITY + 10 {
+            session.publish(format!("ssb/desk/{i}"), "1");
+        }
+        assert_eq!(session.queued.len(), 10);
+        // Publishing to a queued topic replaces its payload
+        session.publish(format!("ssb/desk/{}", REQUEST_CAPACITY), "2");
+        assert_eq!(session.queued.len(), 10);
+        assert_eq!(session.queued[0].1, "2");
+        session.publish("ssb/desk/+".to_owned(), "1");
+        assert_eq!(session.queued.len(), 10);
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let messages = discovery_messages(&settings(), &["ddcci/5"]);
+        let (topic, payload) = messages.last().unwrap();
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/ssb_desk/monitor_ddcci_5/config"
+        );
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["unique_id"], "ssb_desk_monitor_ddcci_5");
+        assert_eq!(
+            payload["state_topic"],
+            "ssb/desk/monitors/ddcci_5/brightness"
+        );
+        assert_eq!(payload["availability_topic"], "ssb/desk/availability");
+        assert_eq!(payload["device"]["identifiers"], json!(["ssb_desk"]));
+        let (topic, payload) = &messages[0];
+        assert_eq!(topic, "homeassistant/switch/ssb_desk/enabled/config");
+        let payload: Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["command_topic"], "ssb/desk/set/enabled");
+
+        let disabled = MqttSettings {
+            discovery_prefix: None,
+            ..settings()
+        };
+        assert!(discovery_messages(&disabled, &["ddcci/5"]).is_empty());
+    }
+
+    /// Requires a broker, e.g. `SSB_TEST_MQTT_BROKER=localhost:1883 cargo test mqtt`
+    #[test]
+    fn test_broker() {
+        let Ok(broker) = std::env::var("SSB_TEST_MQTT_BROKER") else {
+            eprintln!("Skipping test because SSB_TEST_MQTT_BROKER is not set");
+            return;
+        };
+        let (host, port) = broker.rsplit_once(':').unwrap();
+        let settings = MqttSettings {
+            host: host.to_owned(),
+            port: port.parse().unwrap(),
+            base_topic: format!("ssb-test-{}", std::process::id()),
+            discovery_prefix: Some(format!("ssb-test-{}-discovery", std::process::id())),
+            ..MqttSettings::default()
+        };
+        // Enough monitors that the messages don't all fit in the request channel
+        let names = (0..REQUEST_CAPACITY)
+            .map(|i| format!("ddcci/{i}"))
+            .collect::<Vec<_>>();
+        let monitors = names.iter().map(|n| (n.as_str(), 40)).collect::<Vec<_>>();
+        let config = SsbConfig {
+            mqtt: Some(settings.clone()),
+            ..SsbConfig::default()
+        };
+        let (controller, controller_rx) = mpsc::channel();
+        let shared = Shared {
+            config: Arc::new(RwLock::new(config)),
+            controller,
+            last_result: Arc::new(RwLock::new(Some(ApplyResults::for_test(&monitors)))),
+            enabled: Arc::new(AtomicBool::new(true)),
+        };
+
+        // Subscribe before starting, to receive everything that is published
+        let mut options = MqttOptions::new("ssb-test-observer", host, settings.port);
+        options.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        let (observer, mut connection) = Client::new(options, 64);
+        let state_topics = format!("{}/#", settings.base_topic);
+        observer.subscribe(&state_topics, QoS::AtLeastOnce).unwrap();
+        let (received_tx, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for event in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(p))) = event {
+                    let payload = String::from_utf8_lossy(&p.payload).to_string();
+                    if received_tx.send((p.topic, payload)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        let wait_for = |topic: String, payload: &str| loop {
+            let (t, p) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+            if t == topic && p == payload {
+                break;
+            }
+        };
+        // Wait for the subscription to be active
+        std::thread::sleep(Duration::from_millis(500));
+
+        let (events, events_rx) = mpsc::channel();
+        let client = std::thread::spawn(move || run(shared, events_rx));
+        wait_for(availability_topic(&settings), ONLINE);
+        let last = names.last().unwrap();
+        wait_for(monitor_topic(&settings, last), "40");
+
+        let command = format!("{}/set/enabled", settings.base_topic);
+        observer
+            .publish(command, QoS::AtLeastOnce, false, "OFF")
+            .unwrap();
+        let message = controller_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(message, Message::Disable("MQTT Pause"));
+
+        events.send(ClientEvent::Stop).unwrap();
+        client.join().unwrap();
+        wait_for(availability_topic(&settings), OFFLINE);
+    }
+}
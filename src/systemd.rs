@@ -0,0 +1,190 @@
+//! Integration with systemd when running as a user service: journald logging and unit files
+
+use crate::common::APP_DIRECTORY_NAME;
+use anyhow::Context;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+const SYSLOG_IDENTIFIER: &str = "ssb-cli";
+
+/// How long systemd waits for a watchdog ping before restarting the service
+const WATCHDOG_SEC: u32 = 60;
+
+/// Whether stderr is connected to the journal, i.e. running as a systemd service
+pub fn connected_to_journal() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Logs to journald using its native protocol, so that each entry has structured fields
+pub struct JournalLogger {
+    socket: UnixDatagram,
+    filter: LevelFilter,
+}
+
+impl JournalLogger {
+    pub fn install(debug: bool) -> anyhow::Result<()> {
+        let filter = if debug {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(JOURNAL_SOCKET)
+            .context("Unable to connect to the journal")?;
+        log::set_boxed_logger(Box::new(JournalLogger { socket, filter }))?;
+        log::set_max_level(filter);
+        if debug {
+            log::warn!("Debug logging enabled");
+        }
+        Ok(())
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let ignored = ["wgpu", "naga"]
+            .iter()
+            .any(|prefix| metadata.target().starts_with(prefix));
+        metadata.level() <= self.filter && !ignored
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // Nowhere to report the error if the journal is unavailable
+            self.socket.send(&encode_record(record)).ok();
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Syslog priority of the log level
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn encode_record(record: &Record) -> Vec<u8> {
+    let mut buffer = vec![];
+    encode_field(&mut buffer, "MESSAGE", &record.args().to_string());
+    encode_field(
+        &mut buffer,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    encode_field(&mut buffer, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    encode_field(&mut buffer, "TARGET", record.target());
+    if let Some(module) = record.module_path() {
+        encode_field(&mut buffer, "CODE_MODULE", module);
+    }
+    if let Some(file) = record.file() {
+        encode_field(&mut buffer, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        encode_field(&mut buffer, "CODE_LINE", &line.to_string());
+    }
+    buffer
+}
+
+/// Values containing a newline must be prefixed with their length instead of ending with one
+/// See: https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+fn encode_field(buffer: &mut Vec<u8>, key: &str, value: &str) {
+    buffer.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buffer.push(b'=');
+    }
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(b'\n');
+}
+
+pub fn service_name() -> String {
+    format!("{}.service", APP_DIRECTORY_NAME)
+}
+
+/// The unit file for running the daemon as a systemd user service
+pub fn service_unit(executable: &Path, config_path: Option<&Path>) -> String {
+    let mut exec_start = format!("\"{}\"", executable.display());
+    if let Some(config_path) = config_path {
+        exec_start.push_str(&format!(" --config \"{}\"", config_path.display()));
+    }
+    exec_start.push_str(" daemon");
+    format!(
+        "[Unit]
+Description=Solar Screen Brightness
+Documentation=https://github.com/jacob-pro/solar-screen-brightness
+After=graphical-session.target
+
+[Service]
+Type=notify
+ExecStart={exec_start}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+WatchdogSec={WATCHDOG_SEC}
+
+[Install]
+WantedBy=default.target
+"
+    )
+}
+
+/// Write the unit file into the user's systemd directory, returning its path
+pub fn install_user_service(config_path: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let executable = std::env::current_exe().context("Unable to find the executable path")?;
+    let directory = dirs::config_dir()
+        .context("Unable to find the config directory")?
+        .join("systemd")
+        .join("user");
+    fs::create_dir_all(&directory)
+        .context(format!("Unable to create '{}'", directory.display()))?;
+    let path = directory.join(service_name());
+    fs::write(&path, service_unit(&executable, config_path))
+        .context(format!("Unable to write file '{}'", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_field() {
+        let mut buffer = vec![];
+        encode_field(&mut buffer, "MESSAGE", "Refreshing");
+        encode_field(&mut buffer, "PRIORITY", "6");
+        assert_eq!(buffer, b"MESSAGE=Refreshing\nPRIORITY=6\n");
+
+        let mut buffer = vec![];
+        encode_field(&mut buffer, "MESSAGE", "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&[9, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn test_service_unit() {
+        let unit = service_unit(
+            Path::new("/usr/bin/ssb-cli"),
+            Some(Path::new("/home/user/ssb.json")),
+        );
+        assert!(unit.contains(
+            "\nExecStart=\"/usr/bin/ssb-cli\" --config \"/home/user/ssb.json\" daemon\n"
+        ));
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nWatchdogSec=60\n"));
+        let unit = service_unit(Path::new("/usr/bin/ssb-cli"), None);
+        assert!(unit.contains("\nExecStart=\"/usr/bin/ssb-cli\" daemon\n"));
+    }
+}
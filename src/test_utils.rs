@@ -0,0 +1,68 @@
+//! Fixtures shared by the unit tests of several modules
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+#[cfg(target_os = "linux")]
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+
+/// A private session bus that is stopped when dropped
+#[cfg(target_os = "linux")]
+pub struct PrivateBus {
+    daemon: Child,
+    pub address: String,
+}
+
+#[cfg(target_os = "linux")]
+impl PrivateBus {
+    /// Returns None if dbus-daemon is not installed
+    pub fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(Self {
+            daemon,
+            address: address.trim().to_owned(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        self.daemon.kill().ok();
+        self.daemon.wait().ok();
+    }
+}
+
+/// Starts a server that responds to a single HTTP request with the JSON body. Returns the base URL
+/// of the server, and a handle that returns the request line once it has responded.
+pub fn serve_json_once(body: &'static str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request_line = String::new();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        reader.read_line(&mut request_line).unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        request_line
+    });
+    (base_url, server)
+}
@@ -0,0 +1,206 @@
+//! Switches the desktop between the light and dark theme as the phase of the day changes
+
+use crate::apply::ApplyResults;
+use crate::calculator::Phase;
+use crate::config::{SsbConfig, ThemeSettings};
+use crate::controller::BrightnessController;
+use crate::hooks::run_command;
+use anyhow::anyhow;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The theme command is killed if it takes longer than this
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+impl Theme {
+    fn name(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+/// The theme to use during a phase
+pub fn theme_for_phase(settings: &ThemeSettings, phase: Phase) -> Theme {
+    let light = if settings.at_transition_start {
+        matches!(phase, Phase::Dawn | Phase::Day)
+    } else {
+        matches!(phase, Phase::Day | Phase::Dusk)
+    };
+    if light {
+        Theme::Light
+    } else {
+        Theme::Dark
+    }
+}
+
+enum Event {
+    Updated,
+    Stop,
+}
+
+pub struct ThemeSwitcher {
+    events: mpsc::Sender<Event>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl ThemeSwitcher {
+    pub fn start(config: Arc<RwLock<SsbConfig>>, controller: &BrightnessController) -> Self {
+        let last_result = controller.last_result.clone();
+        let (events, events_rx) = mpsc::channel();
+        let listener = Mutex::new(events.clone());
+        controller.add_listener(move || {
+            listener.lock().unwrap().send(Event::Updated).ok();
+        });
+        let join_handle = std::thread::spawn(move || run(config, last_result, events_rx));
+        Self {
+            events,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for ThemeSwitcher {
+    fn drop(&mut self) {
+        log::info!("Stopping theme switcher");
+        self.events.send(Event::Stop).ok();
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn run(
+    config: Arc<RwLock<SsbConfig>>,
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    events: mpsc::Receiver<Event>,
+) {
+    // The theme that was last set, and the settings used to set it
+    let mut applied: Option<(Theme, ThemeSettings)> = None;
+    for event in events {
+        if let Event::Stop = event {
+            break;
+        }
+        let Some(settings) = config.read().unwrap().theme.clone() else {
+            applied = None;
+            continue;
+        };
+        let Some(phase) = last_result.read().unwrap().as_ref().map(|r| r.phase.phase) else {
+            continue;
+        };
+        let theme = theme_for_phase(&settings, phase);
+        // Only switch when it changes, so a theme chosen manually is kept until the next switch
+        if matches!(&applied, Some((t, s)) if *t == theme && *s == settings) {
+            continue;
+        }
+        log::info!(
+            "Switching to the {} theme for {}",
+            theme.name(),
+            phase.name()
+        );
+        match set_theme(&settings, theme, phase) {
+            Ok(_) => applied = Some((theme, settings)),
+            Err(e) => log::error!("Unable to switch to the {} theme: {:#}", theme.name(), e),
+        }
+    }
+    log::debug!("Theme switcher thread exiting");
+}
+
+fn set_theme(settings: &ThemeSettings, theme: Theme, phase: Phase) -> anyhow::Result<()> {
+    let command = if settings.command.is_empty() {
+        gnome_command(theme)?
+    } else {
+        settings.command.clone()
+    };
+    let env = [
+        ("SSB_THEME", theme.name().to_owned()),
+        ("SSB_PHASE", phase.name().to_owned()),
+    ];
+    let output = run_command(&command, &env, COMMAND_TIMEOUT)?;
+    for line in output.stdout.lines().chain(output.stderr.lines()) {
+        log::info!("Theme command output: {line}");
+    }
+    if !output.status.success() {
+        return Err(anyhow!("{:?} failed ({})", command, output.status));
+    }
+    Ok(())
+}
+
+/// Sets the GNOME color scheme, which is also exposed to applications by the settings portal
+#[cfg(target_os = "linux")]
+fn gnome_command(theme: Theme) -> anyhow::Result<Vec<String>> {
+    let scheme = match theme {
+        Theme::Light => "default",
+        Theme::Dark => "prefer-dark",
+    };
+    Ok([
+        "gsettings",
+        "set",
+        "org.gnome.desktop.interface",
+        "color-scheme",
+        scheme,
+    ]
+    .map(|s| s.to_owned())
+    .to_vec())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gnome_command(_theme: Theme) -> anyhow::Result<Vec<String>> {
+    Err(anyhow!(
+        "A theme command must be configured on this platform"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_theme_for_phase() {
+        let mut settings = ThemeSettings::default();
+        let themes = |settings: &ThemeSettings| {
+            [Phase::Dawn, Phase::Day, Phase::Dusk, Phase::Night]
+                .map(|p| theme_for_phase(settings, p))
+        };
+        assert_eq!(
+            themes(&settings),
+            [Theme::Dark, Theme::Light, Theme::Light, Theme::Dark]
+        );
+        settings.at_transition_start = true;
+        assert_eq!(
+            themes(&settings),
+            [Theme::Light, Theme::Light, Theme::Dark, Theme::Dark]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_set_theme_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("theme");
+        let settings = ThemeSettings {
+            at_transition_start: false,
+            command: [
+                "sh",
+                "-c",
+                &format!("echo $SSB_THEME $SSB_PHASE > {}", path.display()),
+            ]
+            .map(|s| s.to_owned())
+            .to_vec(),
+        };
+        set_theme(&settings, Theme::Dark, Phase::Night).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "dark night\n");
+
+        let settings = ThemeSettings {
+            at_transition_start: false,
+            command: ["false".to_owned()].to_vec(),
+        };
+        assert!(set_theme(&settings, Theme::Light, Phase::Day).is_err());
+    }
+}
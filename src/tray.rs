@@ -1,12 +1,64 @@
 use crate::common::APP_NAME;
+use crate::config::SsbConfig;
+use crate::controller::{BrightnessController, Message};
 use crate::gui::UserEvent;
 use egui_winit::winit::event_loop::{EventLoop, EventLoopProxy};
-use std::sync::{Arc, Mutex};
-use tray_icon::menu::{Menu, MenuEvent, MenuId, MenuItemBuilder};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuId, MenuItemBuilder, Submenu};
 use tray_icon::{ClickType, Icon, TrayIcon, TrayIconBuilder, TrayIconEvent};
 
 const MENU_ID_OPEN: &str = "OPEN";
 const MENU_ID_EXIT: &str = "EXIT";
+/// Followed by the name of the saved location
+const MENU_ID_LOCATION_PREFIX: &str = "LOCATION:";
+
+pub struct Tray {
+    _tray_icon: TrayIcon,
+    locations_menu: Submenu,
+    location_items: Vec<(String, CheckMenuItem)>,
+}
+
+impl Tray {
+    /// Update the locations menu to match the saved locations in the config.
+    /// This should be called after the config has changed, e.g. from a controller listener.
+    pub fn update_locations(&mut self, config: &SsbConfig) {
+        let names_changed = self
+            .location_items
+            .iter()
+            .map(|(name, _)| name)
+            .ne(config.saved_locations.iter().map(|l| &l.name));
+        if names_changed {
+            for (_, item) in self.location_items.drain(..) {
+                self.locations_menu.remove(&item).unwrap();
+            }
+            for saved in &config.saved_locations {
+                let item = CheckMenuItem::with_id(
+                    MenuId::new(format!("{}{}", MENU_ID_LOCATION_PREFIX, saved.name)),
+                    // Otherwise & would be treated as a mnemonic
+                    saved.name.replace('&', "&&"),
+                    true,
+                    false,
+                    None,
+                );
+                self.locations_menu.append(&item).unwrap();
+                self.location_items.push((saved.name.clone(), item));
+            }
+        }
+        // The saved locations are ignored while the location is found automatically
+        let automatic = cfg!(target_os = "linux") && config.automatic_location;
+        self.locations_menu
+            .set_enabled(!config.saved_locations.is_empty() && !automatic);
+        self.locations_menu.set_text(if automatic {
+            "Location (Automatic)"
+        } else {
+            "Location"
+        });
+        // Clicking an item toggles it, so the active location is always re-checked
+        for (name, item) in &self.location_items {
+            item.set_checked(config.active_location.as_ref() == Some(name));
+        }
+    }
+}
 
 pub fn read_icon() -> (Vec<u8>, png::OutputInfo) {
     let mut decoder = png::Decoder::new(include_bytes!("../assets/icon-256.png").as_slice());
@@ -18,32 +70,71 @@ pub fn read_icon() -> (Vec<u8>, png::OutputInfo) {
 }
 
 #[cfg(target_os = "linux")]
-pub fn create(event_loop: &EventLoop<UserEvent>) -> std::thread::JoinHandle<()> {
+pub fn create(
+    event_loop: &EventLoop<UserEvent>,
+    config: Arc<RwLock<SsbConfig>>,
+    controller: &BrightnessController,
+) -> std::thread::JoinHandle<()> {
+    use futures::StreamExt;
+
     let proxy = event_loop.create_proxy();
+    let sender = controller.sender.clone();
+    // Every change to the config refreshes the controller, so update the menu after each update
+    let (updated, mut updated_rx) = futures::channel::mpsc::unbounded();
+    let listener = Mutex::new(updated);
+    controller.add_listener(move || {
+        listener.lock().unwrap().unbounded_send(()).ok();
+    });
     // https://github.com/tauri-apps/tray-icon/blob/817d85579b406ddf83891017edb8c7e290bfaa8e/examples/egui.rs#L13-L15
     std::thread::spawn(move || {
         gtk::init().unwrap();
-        // must not drop tray
-        let _tray = create_internal(proxy);
+        // must not drop tray, it is owned by the update task
+        let mut tray = create_internal(proxy, config.clone(), sender);
+        gtk::glib::MainContext::default().spawn_local(async move {
+            while updated_rx.next().await.is_some() {
+                tray.update_locations(&config.read().unwrap());
+            }
+        });
         gtk::main();
     })
 }
 
+/// The locations menu must be updated from the main thread, by calling Tray::update_locations()
+/// when UserEvent::UpdateTray is received
 #[cfg(not(target_os = "linux"))]
-pub fn create(event_loop: &EventLoop<UserEvent>) -> TrayIcon {
-    create_internal(event_loop.create_proxy())
+pub fn create(
+    event_loop: &EventLoop<UserEvent>,
+    config: Arc<RwLock<SsbConfig>>,
+    controller: &BrightnessController,
+) -> Tray {
+    // Every change to the config refreshes the controller, so update the menu after each update
+    let listener = Mutex::new(event_loop.create_proxy());
+    controller.add_listener(move || {
+        listener
+            .lock()
+            .unwrap()
+            .send_event(UserEvent::UpdateTray("Brightness Controller Update"))
+            .ok();
+    });
+    create_internal(event_loop.create_proxy(), config, controller.sender.clone())
 }
 
-fn create_internal(event_loop: EventLoopProxy<UserEvent>) -> TrayIcon {
+fn create_internal(
+    event_loop: EventLoopProxy<UserEvent>,
+    config: Arc<RwLock<SsbConfig>>,
+    controller: mpsc::Sender<Message>,
+) -> Tray {
     let (buf, info) = read_icon();
     let icon = Icon::from_rgba(buf, info.width, info.height).unwrap();
 
+    let locations_menu = Submenu::new("Location", false);
     let menu = Menu::with_items(&[
         &MenuItemBuilder::new()
             .text("Open")
             .id(MenuId::new(MENU_ID_OPEN))
             .enabled(true)
             .build(),
+        &locations_menu,
         &MenuItemBuilder::new()
             .text("Exit")
             .id(MenuId::new(MENU_ID_EXIT))
@@ -61,6 +152,8 @@ fn create_internal(event_loop: EventLoopProxy<UserEvent>) -> TrayIcon {
 
     let tray_loop = Arc::new(Mutex::new(event_loop.clone()));
     let menu_loop = Arc::new(Mutex::new(event_loop));
+    let menu_config = config.clone();
+    let menu_controller = Arc::new(Mutex::new(controller));
 
     TrayIconEvent::set_event_handler(Some(move |event: TrayIconEvent| {
         if event.click_type == ClickType::Left {
@@ -76,10 +169,36 @@ fn create_internal(event_loop: EventLoopProxy<UserEvent>) -> TrayIcon {
         let action = match event.id.0.as_str() {
             MENU_ID_OPEN => UserEvent::OpenWindow("Tray Button"),
             MENU_ID_EXIT => UserEvent::Exit("Tray Button"),
-            _ => return,
+            id => {
+                if let Some(name) = id.strip_prefix(MENU_ID_LOCATION_PREFIX) {
+                    use_location(&menu_config, &menu_controller.lock().unwrap(), name);
+                }
+                return;
+            }
         };
         menu_loop.lock().unwrap().send_event(action).unwrap();
     }));
 
-    tray_icon
+    let mut tray = Tray {
+        _tray_icon: tray_icon,
+        locations_menu,
+        location_items: vec![],
+    };
+    tray.update_locations(&config.read().unwrap());
+    tray
+}
+
+fn use_location(config: &RwLock<SsbConfig>, controller: &mpsc::Sender<Message>, name: &str) {
+    let mut config = config.write().unwrap();
+    if let Err(e) = config.use_saved_location(name) {
+        log::error!("Unable to switch location: {:#}", e);
+        return;
+    }
+    log::info!("Switched to location '{name}' from the tray");
+    controller
+        .send(Message::Refresh("Tray location change"))
+        .unwrap();
+    if let Err(e) = config.save() {
+        log::error!("Unable to save config: {:#}", e);
+    }
 }
@@ -0,0 +1,274 @@
+//! Fetches the cloud cover, so that the day brightness can be reduced when it is overcast
+
+use crate::apply::ApplyResults;
+use crate::config::{Location, SsbConfig, WeatherProvider, WeatherSettings};
+use crate::controller::{BrightnessController, Message};
+use crate::hooks::run_command;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long to allow a provider to fetch the cloud cover
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether the settings have changed, the location is checked after every
+/// update of the BrightnessController
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A source of the current cloud cover
+pub trait CloudCoverProvider {
+    /// The cloud cover percentage (0-100) at the location
+    fn cloud_cover(&self, location: Location) -> anyhow::Result<f64>;
+}
+
+pub fn create_provider(provider: &WeatherProvider) -> Box<dyn CloudCoverProvider> {
+    match provider {
+        WeatherProvider::File { path } => Box::new(FileProvider { path: path.clone() }),
+        WeatherProvider::Command { command } => Box::new(CommandProvider {
+            command: command.clone(),
+        }),
+        WeatherProvider::OpenMeteo { url } => Box::new(OpenMeteoProvider { url: url.clone() }),
+    }
+}
+
+fn parse_cloud_cover(value: &str) -> anyhow::Result<f64> {
+    let cloud_cover = value
+        .trim()
+        .parse::<f64>()
+        .context(format!("Invalid cloud cover '{}'", value.trim()))?;
+    if !(0.0..=100.0).contains(&cloud_cover) {
+        return Err(anyhow!("Cloud cover {cloud_cover} is not a percentage"));
+    }
+    Ok(cloud_cover)
+}
+
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+impl CloudCoverProvider for FileProvider {
+    fn cloud_cover(&self, _: Location) -> anyhow::Result<f64> {
+        let contents = fs::read_to_string(&self.path)
+            .context(format!("Unable to read file '{}'", self.path.display()))?;
+        parse_cloud_cover(&contents)
+    }
+}
+
+pub struct CommandProvider {
+    command: Vec<String>,
+}
+
+impl CloudCoverProvider for CommandProvider {
+    fn cloud_cover(&self, location: Location) -> anyhow::Result<f64> {
+        let env = [
+            ("SSB_LATITUDE", location.latitude.to_string()),
+            ("SSB_LONGITUDE", location.longitude.to_string()),
+        ];
+        let output = run_command(&self.command, &env, PROVIDER_TIMEOUT)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "'{}' failed ({}): {}",
+                self.command[0],
+                output.status,
+                output.stderr.trim()
+            ));
+        }
+        parse_cloud_cover(&output.stdout)
+    }
+}
+
+pub struct OpenMeteoProvider {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoResponse {
+    current: OpenMeteoCurrent,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoCurrent {
+    cloud_cover: f64,
+}
+
+impl CloudCoverProvider for OpenMeteoProvider {
+    fn cloud_cover(&self, location: Location) -> anyhow::Result<f64> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(PROVIDER_TIMEOUT)
+            .build()?;
+        let response = client
+            .get(&self.url)
+            .query(&[
+                ("latitude", location.latitude.to_string()),
+                ("longitude", location.longitude.to_string()),
+                ("current", "cloud_cover".to_owned()),
+            ])
+            .send()?
+            .error_for_status()?
+            .json::<OpenMeteoResponse>()
+            .context("Unable to parse weather response")?;
+        parse_cloud_cover(&response.current.cloud_cover.to_string())
+    }
+}
+
+enum Event {
+    Updated,
+    Stop,
+}
+
+/// Periodically fetches the cloud cover on its own thread, and sends it to the
+/// BrightnessController. If it can't be fetched then the plain solar schedule is used.
+pub struct WeatherWatcher {
+    events: mpsc::Sender<Event>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl WeatherWatcher {
+    pub fn start(config: Arc<RwLock<SsbConfig>>, controller: &BrightnessController) -> Self {
+        let sender = controller.sender.clone();
+        let last_result = controller.last_result.clone();
+        let (events, events_rx) = mpsc::channel();
+        let listener = Mutex::new(events.clone());
+        controller.add_listener(move || {
+            listener.lock().unwrap().send(Event::Updated).ok();
+        });
+        let join_handle = std::thread::spawn(move || run(config, last_result, sender, events_rx));
+        Self {
+            events,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for WeatherWatcher {
+    fn drop(&mut self) {
+        log::info!("Stopping weather watcher");
+        self.events.send(Event::Stop).ok();
+        self.join_handle.take().unwrap().join().unwrap();
+    }
+}
+
+fn run(
+    config: Arc<RwLock<SsbConfig>>,
+    last_result: Arc<RwLock<Option<ApplyResults>>>,
+    controller: mpsc::Sender<Message>,
+    events: mpsc::Receiver<Event>,
+) {
+    let mut last_fetch: Option<(Instant, WeatherSettings, Location)> = None;
+    let mut reported = None;
+    loop {
+        let settings = config.read().unwrap().weather.clone();
+        // The location the brightness was last calculated for, which may have been found
+        // automatically or chosen from the saved locations rather than configured
+        let location = last_result.read().unwrap().as_ref().map(|r| r.location);
+        let cloud_cover = match (settings, location) {
+            (Some(settings), Some(location)) => {
+                let due = match &last_fetch {
+                    None => true,
+                    Some((time, s, l)) => {
+                        time.elapsed() >= Duration::from_secs(settings.refresh_mins as u64 * 60)
+                            || *s != settings
+                            || *l != location
+                    }
+                };
+                if due {
+                    let result = create_provider(&settings.provider).cloud_cover(location);
+                    last_fetch = Some((Instant::now(), settings, location));
+                    match result {
+                        Ok(cloud_cover) => {
+                            log::info!("Cloud cover is {cloud_cover}%");
+                            Some(cloud_cover)
+                        }
+                        Err(e) => {
+                            log::error!("Unable to fetch cloud cover: {:#}", e);
+                            None
+                        }
+                    }
+                } else {
+                    reported
+                }
+            }
+            _ => {
+                last_fetch = None;
+                None
+            }
+        };
+        if cloud_cover != reported {
+            reported = cloud_cover;
+            controller.send(Message::CloudCover(cloud_cover)).unwrap();
+        }
+        match events.recv_timeout(CONFIG_POLL_INTERVAL) {
+            Ok(Event::Updated) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    log::debug!("Weather watcher thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::serve_json_once;
+
+    const LOCATION: Location = Location {
+        latitude: 51.5,
+        longitude: -0.12,
+    };
+
+    #[test]
+    fn test_file_provider() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let provider = create_provider(&WeatherProvider::File {
+            path: file.path().to_path_buf(),
+        });
+        fs::write(file.path(), "75\n").unwrap();
+        assert_eq!(provider.cloud_cover(LOCATION).unwrap(), 75.0);
+        fs::write(file.path(), "150").unwrap();
+        assert!(provider.cloud_cover(LOCATION).is_err());
+        fs::write(file.path(), "cloudy").unwrap();
+        assert!(provider.cloud_cover(LOCATION).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_provider() {
+        let provider = create_provider(&WeatherProvider::Command {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "echo $SSB_LATITUDE".to_owned(),
+            ],
+        });
+        assert_eq!(provider.cloud_cover(LOCATION).unwrap(), 51.5);
+        // Output larger than the pipe buffer
+        let provider = create_provider(&WeatherProvider::Command {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "head -c 200000 /dev/zero | tr '\\0' ' '; echo 40".to_owned(),
+            ],
+        });
+        assert_eq!(provider.cloud_cover(LOCATION).unwrap(), 40.0);
+        let provider = create_provider(&WeatherProvider::Command {
+            command: vec!["false".to_owned()],
+        });
+        assert!(provider.cloud_cover(LOCATION).is_err());
+    }
+
+    #[test]
+    fn test_open_meteo_provider() {
+        let body = r#"{"latitude":51.5,"longitude":-0.12,"current":{"time":"2023-08-01T12:00","cloud_cover":62}}"#;
+        let (base_url, server) = serve_json_once(body);
+        let url = format!("{base_url}/v1/forecast");
+        let provider = create_provider(&WeatherProvider::OpenMeteo { url });
+        assert_eq!(provider.cloud_cover(LOCATION).unwrap(), 62.0);
+        let request_line = server.join().unwrap();
+        assert!(request_line
+            .starts_with("GET /v1/forecast?latitude=51.5&longitude=-0.12&current=cloud_cover "));
+    }
+}